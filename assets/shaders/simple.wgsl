@@ -13,8 +13,37 @@ struct Params {
     samples: i32,
     depth: i32,
     render_mode: i32,
+    frame: i32,
+    sky: i32,
+    instance_count: i32,
+    unbounded_count: i32,
+    instance_root: i32,
+    sample_pattern: i32,
+    light_count: i32,
+    mis: i32,
+    emitter_count: i32,
+    sky_rotation: f32,
+    sky_intensity: f32,
+    turbidity: f32,
+    sun_elevation: f32,
+    sun_azimuth: f32,
 }
 
+// render_mode values, matches the labels in egui_menu
+const NORMALS: i32 = 0;
+const PATH_TRACED: i32 = 4;
+
+// sample_pattern values, matches render::SamplePattern
+const RANDOM: i32 = 0;
+const STRATIFIED: i32 = 1;
+const SOBOL: i32 = 2;
+const BLUE_NOISE: i32 = 3;
+
+// sky values, matches render::Sky
+const NO_SKY: i32 = 0;
+const ENVIRONMENT_SKY: i32 = 2;
+const PHYSICAL_SKY: i32 = 3;
+
 @group(0) @binding(1)
 var<uniform> params: Params;
 
@@ -27,6 +56,13 @@ struct Camera {
     pixel_delta_v: vec3<f32>,
     viewport_upper_left: vec3<f32>,
     pixel00_loc: vec3<f32>,
+    look_at: vec3<f32>,
+    vfov: f32,
+    vup: vec3<f32>,
+    defocus_disk_u: vec3<f32>,
+    defocus_angle: f32,
+    defocus_disk_v: vec3<f32>,
+    focus_dist: f32,
 }
 
 @group(0) @binding(2)
@@ -37,25 +73,570 @@ struct Sphere {
     center: vec3<f32>,
     radius: f32,
     color: vec4<f32>,
+    material: i32,
+    fuzz: f32,
+    ior: f32,
+    intensity: f32,
+    emitter: u32,
+}
+
+// matches collidables::Material
+const LAMBERTIAN: i32 = 0;
+const METAL: i32 = 1;
+const DIELECTRIC: i32 = 2;
+const EMISSIVE: i32 = 3;
+
+const PI: f32 = 3.14159265;
+
+// bounces every path makes before russian roulette can end it
+const ROULETTE_DEPTH: i32 = 3;
+
+// matches collidables::Shape
+const PLANE: i32 = 0;
+const QUAD: i32 = 1;
+const BOX: i32 = 2;
+const DISK: i32 = 3;
+const CYLINDER: i32 = 4;
+const SPHERE: i32 = 5;
+
+// what origin, u, v and radius mean depends on the shape, see collidables::Primitive
+struct Primitive {
+    origin: vec3<f32>,
+    shape: i32,
+    u: vec3<f32>,
+    radius: f32,
+    v: vec3<f32>,
+    material: i32,
+    color: vec4<f32>,
+    fuzz: f32,
+    ior: f32,
+    intensity: f32,
+}
+
+// matches the geometry constants in instance.rs
+const PRIMITIVE_GEOMETRY: u32 = 0u;
+const MESH_GEOMETRY: u32 = 1u;
+
+// world_to_object holds the rows of an affine matrix, so vec4(p, 1.) * world_to_object moves a
+// world space point into object space. index is a primitive, or the root node of a model's bvh
+struct Instance {
+    world_to_object: mat3x4<f32>,
+    geometry: u32,
+    index: u32,
+    first: u32,
+    emitter: u32,
+}
+
+// matches light::LightKind
+const POINT_LIGHT: i32 = 0;
+const SPOT_LIGHT: i32 = 1;
+const DIRECTIONAL_LIGHT: i32 = 2;
+
+// matches light::Light, point and spot intensities fall off with the distance squared, angles
+// are in radians from the spot's axis
+struct Light {
+    position: vec3<f32>,
+    kind: i32,
+    direction: vec3<f32>,
+    intensity: f32,
+    color: vec3<f32>,
+    inner_angle: f32,
+    outer_angle: f32,
+}
+
+// matches light::Emitter, an emissive sphere or quad in world space. shape is SPHERE or QUAD and
+// emission is the color times the intensity
+struct Emitter {
+    origin: vec3<f32>,
+    shape: i32,
+    u: vec3<f32>,
+    radius: f32,
+    v: vec3<f32>,
+    area: f32,
+    emission: vec3<f32>,
+}
+
+// interior nodes have count 0 and their children at left_first and left_first + 1,
+// leaves cover count spheres starting at left_first
+struct BvhNode {
+    min: vec3<f32>,
+    left_first: u32,
+    max: vec3<f32>,
+    count: u32,
+}
+
+// matches mesh::Vertex, a zero normal means the face normal is used
+struct Vertex {
+    position: vec3<f32>,
+    u: f32,
+    normal: vec3<f32>,
+    v: f32,
+}
+
+struct Triangle {
+    indices: vec3<u32>,
+    material: u32,
+}
+
+struct SurfaceMaterial {
+    color: vec4<f32>,
+    material: i32,
+    fuzz: f32,
+    ior: f32,
+    intensity: f32,
+}
+
+#ifdef SCENE_TEXTURE
+// devices without a storage buffer for each scene array get them packed into textures, one vec4
+// per texel
+const SCENE_TEXTURE_WIDTH: i32 = 1024;
+
+@group(0) @binding(3)
+var sphere_texture: texture_2d<u32>;
+
+@group(0) @binding(5)
+var bvh_texture: texture_2d<u32>;
+
+@group(0) @binding(6)
+var vertex_texture: texture_2d<u32>;
+
+@group(0) @binding(7)
+var triangle_texture: texture_2d<u32>;
+
+@group(0) @binding(8)
+var material_texture: texture_2d<u32>;
+
+@group(0) @binding(9)
+var primitive_texture: texture_2d<u32>;
+
+@group(0) @binding(10)
+var instance_texture: texture_2d<u32>;
+
+@group(0) @binding(12)
+var light_texture: texture_2d<u32>;
+
+@group(0) @binding(13)
+var emitter_texture: texture_2d<u32>;
+
+fn texel_coords(index: i32) -> vec2<i32> {
+    return vec2<i32>(index % SCENE_TEXTURE_WIDTH, index / SCENE_TEXTURE_WIDTH);
+}
+
+fn get_sphere(index: i32) -> Sphere {
+    let a = textureLoad(sphere_texture, texel_coords(index * 4), 0);
+    let b = textureLoad(sphere_texture, texel_coords(index * 4 + 1), 0);
+    let c = textureLoad(sphere_texture, texel_coords(index * 4 + 2), 0);
+    let d = textureLoad(sphere_texture, texel_coords(index * 4 + 3), 0);
+
+    var sphere: Sphere;
+    sphere.center = bitcast<vec3<f32>>(a.xyz);
+    sphere.radius = bitcast<f32>(a.w);
+    sphere.color = bitcast<vec4<f32>>(b);
+    sphere.material = bitcast<i32>(c.x);
+    sphere.fuzz = bitcast<f32>(c.y);
+    sphere.ior = bitcast<f32>(c.z);
+    sphere.intensity = bitcast<f32>(c.w);
+    sphere.emitter = d.x;
+    return sphere;
+}
+
+fn get_node(index: i32) -> BvhNode {
+    let a = textureLoad(bvh_texture, texel_coords(index * 2), 0);
+    let b = textureLoad(bvh_texture, texel_coords(index * 2 + 1), 0);
+    return BvhNode(bitcast<vec3<f32>>(a.xyz), a.w, bitcast<vec3<f32>>(b.xyz), b.w);
+}
+
+fn get_vertex(index: i32) -> Vertex {
+    let a = textureLoad(vertex_texture, texel_coords(index * 2), 0);
+    let b = textureLoad(vertex_texture, texel_coords(index * 2 + 1), 0);
+    return Vertex(bitcast<vec3<f32>>(a.xyz), bitcast<f32>(a.w), bitcast<vec3<f32>>(b.xyz), bitcast<f32>(b.w));
+}
+
+fn get_triangle(index: i32) -> Triangle {
+    let a = textureLoad(triangle_texture, texel_coords(index), 0);
+    return Triangle(a.xyz, a.w);
+}
+
+fn get_material(index: i32) -> SurfaceMaterial {
+    let a = textureLoad(material_texture, texel_coords(index * 2), 0);
+    let b = textureLoad(material_texture, texel_coords(index * 2 + 1), 0);
+    return SurfaceMaterial(bitcast<vec4<f32>>(a), bitcast<i32>(b.x), bitcast<f32>(b.y), bitcast<f32>(b.z), bitcast<f32>(b.w));
+}
+
+fn get_primitive(index: i32) -> Primitive {
+    let a = textureLoad(primitive_texture, texel_coords(index * 5), 0);
+    let b = textureLoad(primitive_texture, texel_coords(index * 5 + 1), 0);
+    let c = textureLoad(primitive_texture, texel_coords(index * 5 + 2), 0);
+    let d = textureLoad(primitive_texture, texel_coords(index * 5 + 3), 0);
+    let e = textureLoad(primitive_texture, texel_coords(index * 5 + 4), 0);
+
+    var primitive: Primitive;
+    primitive.origin = bitcast<vec3<f32>>(a.xyz);
+    primitive.shape = bitcast<i32>(a.w);
+    primitive.u = bitcast<vec3<f32>>(b.xyz);
+    primitive.radius = bitcast<f32>(b.w);
+    primitive.v = bitcast<vec3<f32>>(c.xyz);
+    primitive.material = bitcast<i32>(c.w);
+    primitive.color = bitcast<vec4<f32>>(d);
+    primitive.fuzz = bitcast<f32>(e.x);
+    primitive.ior = bitcast<f32>(e.y);
+    primitive.intensity = bitcast<f32>(e.z);
+    return primitive;
+}
+
+fn get_instance(index: i32) -> Instance {
+    let a = textureLoad(instance_texture, texel_coords(index * 4), 0);
+    let b = textureLoad(instance_texture, texel_coords(index * 4 + 1), 0);
+    let c = textureLoad(instance_texture, texel_coords(index * 4 + 2), 0);
+    let d = textureLoad(instance_texture, texel_coords(index * 4 + 3), 0);
+    let world_to_object = mat3x4<f32>(bitcast<vec4<f32>>(a), bitcast<vec4<f32>>(b), bitcast<vec4<f32>>(c));
+    return Instance(world_to_object, d.x, d.y, d.z, d.w);
 }
 
-@group(0) @binding(3) 
-var<uniform> spheres: array<Sphere, 5>;
+fn get_light(index: i32) -> Light {
+    let a = textureLoad(light_texture, texel_coords(index * 4), 0);
+    let b = textureLoad(light_texture, texel_coords(index * 4 + 1), 0);
+    let c = textureLoad(light_texture, texel_coords(index * 4 + 2), 0);
+    let d = textureLoad(light_texture, texel_coords(index * 4 + 3), 0);
+    return Light(
+        bitcast<vec3<f32>>(a.xyz),
+        bitcast<i32>(a.w),
+        bitcast<vec3<f32>>(b.xyz),
+        bitcast<f32>(b.w),
+        bitcast<vec3<f32>>(c.xyz),
+        bitcast<f32>(c.w),
+        bitcast<f32>(d.x),
+    );
+}
+
+fn get_emitter(index: i32) -> Emitter {
+    let a = textureLoad(emitter_texture, texel_coords(index * 4), 0);
+    let b = textureLoad(emitter_texture, texel_coords(index * 4 + 1), 0);
+    let c = textureLoad(emitter_texture, texel_coords(index * 4 + 2), 0);
+    let d = textureLoad(emitter_texture, texel_coords(index * 4 + 3), 0);
+    return Emitter(
+        bitcast<vec3<f32>>(a.xyz),
+        bitcast<i32>(a.w),
+        bitcast<vec3<f32>>(b.xyz),
+        bitcast<f32>(b.w),
+        bitcast<vec3<f32>>(c.xyz),
+        bitcast<f32>(c.w),
+        bitcast<vec3<f32>>(d.xyz),
+    );
+}
+#else
+@group(0) @binding(3)
+var<storage, read> spheres: array<Sphere>;
+
+@group(0) @binding(5)
+var<storage, read> bvh: array<BvhNode>;
+
+fn get_sphere(index: i32) -> Sphere {
+    return spheres[index];
+}
+
+@group(0) @binding(6)
+var<storage, read> vertices: array<Vertex>;
+
+@group(0) @binding(7)
+var<storage, read> triangles: array<Triangle>;
+
+@group(0) @binding(8)
+var<storage, read> materials: array<SurfaceMaterial>;
+
+@group(0) @binding(9)
+var<storage, read> primitives: array<Primitive>;
+
+@group(0) @binding(10)
+var<storage, read> instances: array<Instance>;
+
+@group(0) @binding(12)
+var<storage, read> lights: array<Light>;
+
+@group(0) @binding(13)
+var<storage, read> emitters: array<Emitter>;
+
+fn get_node(index: i32) -> BvhNode {
+    return bvh[index];
+}
+
+fn get_vertex(index: i32) -> Vertex {
+    return vertices[index];
+}
+
+fn get_triangle(index: i32) -> Triangle {
+    return triangles[index];
+}
+
+fn get_material(index: i32) -> SurfaceMaterial {
+    return materials[index];
+}
+
+fn get_primitive(index: i32) -> Primitive {
+    return primitives[index];
+}
+
+fn get_instance(index: i32) -> Instance {
+    return instances[index];
+}
+
+fn get_light(index: i32) -> Light {
+    return lights[index];
+}
+
+fn get_emitter(index: i32) -> Emitter {
+    return emitters[index];
+}
+#endif
+
+#ifdef ACCUMULATION_TEXTURE
+// running sum of every sample taken for each pixel since the last reset. without storage buffers
+// this frame's sums go to one texture and last frame's are read from a copy of it
+@group(0) @binding(4)
+var accumulation: texture_storage_2d<rgba32float, write>;
 
-// https://www.shadertoy.com/view/4djSRW
-fn nrand(r: ptr<function,vec2<i32>>) -> f32 {
-    (*r).x = ((*r).x + 1) % 512;
-    if (*r).x == 0 {
-        (*r).y = ((*r).y + 1) % 512;
+@group(0) @binding(16)
+var previous_accumulation: texture_2d<f32>;
+
+fn load_accumulation(location: vec2<i32>) -> vec4<f32> {
+    return textureLoad(previous_accumulation, location, 0);
+}
+
+fn store_accumulation(location: vec2<i32>, sum: vec4<f32>) {
+    textureStore(accumulation, location, sum);
+}
+#else
+// running sum of every sample taken for each pixel since the last reset
+@group(0) @binding(4)
+var<storage, read_write> accumulation: array<vec4<f32>>;
+
+fn load_accumulation(location: vec2<i32>) -> vec4<f32> {
+    return accumulation[pixel_index(location)];
+}
+
+fn store_accumulation(location: vec2<i32>, sum: vec4<f32>) {
+    accumulation[pixel_index(location)] = sum;
+}
+#endif
+
+// pcg hash from "Hash Functions for GPU Rendering", Jarzynski and Olano 2020
+fn pcg_hash(input: u32) -> u32 {
+    let state = input * 747796405u + 2891336453u;
+    let word = ((state >> ((state >> 28u) + 4u)) ^ state) * 277803737u;
+    return (word >> 22u) ^ word;
+}
+
+// the state of one sample's random numbers, dimension counts how many have been drawn so the
+// sample patterns can give each one its own stratification
+struct Rng {
+    state: u32, // pcg state for the random pattern and the jitter inside strata
+    sample: u32, // which sample of this frame
+    index: u32, // which sample of the pixel, counted across frames
+    dimension: u32,
+    scramble: u32, // per pixel seed for shuffling strata and scrambling sobol points
+    location: vec2<i32>,
+}
+
+// every pixel, frame and sample starts its own sequence, params.seed picks a different set of them
+fn new_rng(location: vec2<i32>, sample: i32) -> Rng {
+    let seed = pcg_hash(u32(params.seed));
+    let frame_key = pcg_hash(u32(pixel_index(location)) ^ pcg_hash(u32(params.frame) ^ seed));
+    let index = u32(params.frame * params.samples + sample);
+
+    // sobol's index already carries on across frames, scrambling it again every frame would
+    // undo the stratification between them
+    var scramble = frame_key;
+    if params.sample_pattern == SOBOL {
+        scramble = pcg_hash(u32(pixel_index(location)) ^ seed);
     }
-    var p3 = fract(vec3<f32>((*r).xyx) * .1031);
-    p3 += dot(p3, p3.yzx + 33.33);
-    return (fract((p3.x + p3.y) * p3.z) * 2.) - 0.5;
+    return Rng(pcg_hash(frame_key ^ pcg_hash(u32(sample))), u32(sample), index, 0u, scramble, location);
+}
+
+// the state is rehashed for every number
+fn rand_u32(r: ptr<function,u32>) -> u32 {
+    *r = pcg_hash(*r);
+    return *r;
+}
+
+// the top 24 bits are all a float can hold
+fn to_unit_float(x: u32) -> f32 {
+    return f32(x >> 8u) / 16777216.;
+}
+
+fn hash_combine(seed: u32, value: u32) -> u32 {
+    return pcg_hash(seed ^ pcg_hash(value));
+}
+
+// the smallest power of two at least n, for n from 1
+fn power_of_two_above(n: u32) -> u32 {
+    var w = n - 1u;
+    w |= w >> 1u;
+    w |= w >> 2u;
+    w |= w >> 4u;
+    w |= w >> 8u;
+    w |= w >> 16u;
+    return w + 1u;
 }
 
-// todo: fix storage buffers or storage textures for web 
+// a random permutation of [0, count) picked by seed, "Correlated Multi-Jittered Sampling",
+// Kensler 2013. count has to be a power of two, the hash is a bijection on those, so it never has
+// to walk past values outside the range like the paper does for other counts
+fn permute(index: u32, count: u32, seed: u32) -> u32 {
+    let w = count - 1u;
 
-// @group(0) @binding(4)
+    var i = index;
+    i ^= seed;
+    i *= 0xe170893du;
+    i ^= seed >> 16u;
+    i ^= (i & w) >> 4u;
+    i ^= seed >> 8u;
+    i *= 0x0929eb3fu;
+    i ^= seed >> 23u;
+    i ^= (i & w) >> 1u;
+    i *= 1u | seed >> 27u;
+    i *= 0x6935fa69u;
+    i ^= (i & w) >> 11u;
+    i *= 0x74dcb303u;
+    i ^= (i & w) >> 2u;
+    i *= 0x9e501cc3u;
+    i ^= (i & w) >> 2u;
+    i *= 0xc860a3dfu;
+    i &= w;
+    i ^= i >> 5u;
+    return (i + seed) & w;
+}
+
+// direction numbers for the first four sobol dimensions, 32 bits each, from Joe and Kuo
+var<private> sobol_directions: array<u32, 128> = array<u32, 128>(
+    0x80000000u, 0x40000000u, 0x20000000u, 0x10000000u,
+    0x08000000u, 0x04000000u, 0x02000000u, 0x01000000u,
+    0x00800000u, 0x00400000u, 0x00200000u, 0x00100000u,
+    0x00080000u, 0x00040000u, 0x00020000u, 0x00010000u,
+    0x00008000u, 0x00004000u, 0x00002000u, 0x00001000u,
+    0x00000800u, 0x00000400u, 0x00000200u, 0x00000100u,
+    0x00000080u, 0x00000040u, 0x00000020u, 0x00000010u,
+    0x00000008u, 0x00000004u, 0x00000002u, 0x00000001u,
+    0x80000000u, 0xc0000000u, 0xa0000000u, 0xf0000000u,
+    0x88000000u, 0xcc000000u, 0xaa000000u, 0xff000000u,
+    0x80800000u, 0xc0c00000u, 0xa0a00000u, 0xf0f00000u,
+    0x88880000u, 0xcccc0000u, 0xaaaa0000u, 0xffff0000u,
+    0x80008000u, 0xc000c000u, 0xa000a000u, 0xf000f000u,
+    0x88008800u, 0xcc00cc00u, 0xaa00aa00u, 0xff00ff00u,
+    0x80808080u, 0xc0c0c0c0u, 0xa0a0a0a0u, 0xf0f0f0f0u,
+    0x88888888u, 0xccccccccu, 0xaaaaaaaau, 0xffffffffu,
+    0x80000000u, 0xc0000000u, 0x60000000u, 0x90000000u,
+    0xe8000000u, 0x5c000000u, 0x8e000000u, 0xc5000000u,
+    0x68800000u, 0x9cc00000u, 0xee600000u, 0x55900000u,
+    0x80680000u, 0xc09c0000u, 0x60ee0000u, 0x90550000u,
+    0xe8808000u, 0x5cc0c000u, 0x8e606000u, 0xc5909000u,
+    0x6868e800u, 0x9c9c5c00u, 0xeeee8e00u, 0x5555c500u,
+    0x8000e880u, 0xc0005cc0u, 0x60008e60u, 0x9000c590u,
+    0xe8006868u, 0x5c009c9cu, 0x8e00eeeeu, 0xc5005555u,
+    0x80000000u, 0xc0000000u, 0x20000000u, 0x50000000u,
+    0xf8000000u, 0x74000000u, 0xa2000000u, 0x93000000u,
+    0xd8800000u, 0x25400000u, 0x59e00000u, 0xe6d00000u,
+    0x78080000u, 0xb40c0000u, 0x82020000u, 0xc3050000u,
+    0x208f8000u, 0x51474000u, 0xfbea2000u, 0x75d93000u,
+    0xa0858800u, 0x914e5400u, 0xdbe79e00u, 0x25db6d00u,
+    0x58800080u, 0xe54000c0u, 0x79e00020u, 0xb6d00050u,
+    0x800800f8u, 0xc00c0074u, 0x200200a2u, 0x50050093u,
+);
+
+fn sobol(index: u32, dimension: u32) -> u32 {
+    var x = 0u;
+    var i = index;
+    for (var bit = 0u; i != 0u; bit++) {
+        if (i & 1u) != 0u {
+            x ^= sobol_directions[dimension * 32u + bit];
+        }
+        i >>= 1u;
+    }
+    return x;
+}
+
+// "Practical Hash-based Owen Scrambling", Burley 2020
+fn laine_karras_permutation(value: u32, seed: u32) -> u32 {
+    var x = value + seed;
+    x ^= x * 0x6c50b47cu;
+    x ^= x * 0xb82f1e52u;
+    x ^= x * 0xc7afe638u;
+    x ^= x * 0x8d22f6e6u;
+    return x;
+}
+
+fn nested_uniform_scramble(x: u32, seed: u32) -> u32 {
+    return reverseBits(laine_karras_permutation(reverseBits(x), seed));
+}
+
+// dimensions past the fourth start another 4d sobol sequence, shuffled and scrambled with a
+// different seed so the groups don't line up with each other
+fn owen_sobol(index: u32, dimension: u32, scramble: u32) -> f32 {
+    let seed = hash_combine(scramble, dimension / 4u);
+    let shuffled = nested_uniform_scramble(index, seed);
+    let x = sobol(shuffled, dimension % 4u);
+    return to_unit_float(nested_uniform_scramble(x, hash_combine(seed, dimension % 4u)));
+}
+
+// 64x64 void and cluster noise with a separate pattern in each channel
+@group(0) @binding(11)
+var blue_noise: texture_2d<f32>;
+
+// the tile is offset differently for each group of four dimensions, then every sample steps the
+// pixel's value along an r2 sequence so the error stays blue across samples
+fn blue_noise_sample(index: u32, dimension: u32, location: vec2<i32>) -> f32 {
+    let size = vec2<i32>(textureDimensions(blue_noise));
+    let offset_hash = hash_combine(pcg_hash(u32(params.seed)), dimension / 4u);
+    let offset = vec2<i32>(i32(offset_hash & 0xffffu), i32(offset_hash >> 16u));
+    let noise = textureLoad(blue_noise, (location + offset) % size, 0)[dimension % 4u];
+
+    var step = 0.7548776662;
+    if dimension % 2u == 1u {
+        step = 0.5698402910;
+    }
+    return fract(noise + f32(index) * step);
+}
+
+// uniform in [0, 1), drawn from the sample pattern picked in params
+fn rand_float(r: ptr<function,Rng>) -> f32 {
+    let dimension = (*r).dimension;
+    (*r).dimension += 1u;
+
+    if params.sample_pattern == STRATIFIED {
+        // a latin hypercube, every dimension shuffles the strata on its own. there are a power of
+        // two of them, the ones left over when samples isn't one stay empty at random
+        let count = power_of_two_above(u32(params.samples));
+        let stratum = permute((*r).sample, count, hash_combine((*r).scramble, dimension));
+        return (f32(stratum) + to_unit_float(rand_u32(&(*r).state))) / f32(count);
+    } else if params.sample_pattern == SOBOL {
+        return owen_sobol((*r).index, dimension, (*r).scramble);
+    } else if params.sample_pattern == BLUE_NOISE {
+        return blue_noise_sample((*r).index, dimension, (*r).location);
+    }
+    return to_unit_float(rand_u32(&(*r).state));
+}
+
+// a point in the unit square, pairs start on an even dimension so they come from the same sobol
+// group and blue noise channels
+fn rand_vec2(r: ptr<function,Rng>) -> vec2<f32> {
+    (*r).dimension += (*r).dimension % 2u;
+
+    if params.sample_pattern == STRATIFIED {
+        // jittered on a grid with a power of two side, the cells left over when samples doesn't
+        // fill it are skipped at random
+        let dimension = (*r).dimension;
+        (*r).dimension += 2u;
+        let side = power_of_two_above(u32(ceil(sqrt(f32(params.samples)))));
+        let cell = permute((*r).sample, side * side, hash_combine((*r).scramble, dimension));
+        let jitter = vec2<f32>(to_unit_float(rand_u32(&(*r).state)), to_unit_float(rand_u32(&(*r).state)));
+        return (vec2<f32>(f32(cell % side), f32(cell / side)) + jitter) / f32(side);
+    }
+
+    let x = rand_float(r);
+    let y = rand_float(r);
+    return vec2<f32>(x, y);
+}
+
+// @group(0) @binding(16)
 // var<storage> noise: array<vec4<f32>>;
 
 // fn nrand(r: ptr<function,vec2<i32>>) -> f32 {
@@ -71,33 +652,48 @@ fn nrand(r: ptr<function,vec2<i32>>) -> f32 {
 //     return ((pixel.x + pixel.y + pixel.z) / 1.5) - 1.;
 // }
 
-fn nrand_vec3(r: ptr<function,vec2<i32>>) -> vec3<f32> {
-    let x = nrand(r);
-    let y = nrand(r);
-    let z = nrand(r);
-    return vec3<f32>(x, y, z);
+// uniform over the sphere, the height is uniform and picks the ring, the angle a point on it
+fn rand_unit_vector(r: ptr<function,Rng>) -> vec3<f32> {
+    let u = rand_vec2(r);
+    let z = 1. - 2. * u.x;
+    let ring = sqrt(max(0., 1. - z * z));
+    let phi = 2. * PI * u.y;
+    return vec3<f32>(ring * cos(phi), ring * sin(phi), z);
 }
 
-fn rand_in_unit_sphere(r: ptr<function,vec2<i32>>) -> vec3<f32> {
+// uniform over the ball, the cube root spreads the radius by volume
+fn rand_in_unit_sphere(r: ptr<function,Rng>) -> vec3<f32> {
+    let direction = rand_unit_vector(r);
+    return direction * pow(rand_float(r), 1. / 3.);
+}
 
-    // bail out after 100 reps
-    for (var i = 0; i < 100; i++) {
-        let v = nrand_vec3(r);
-        if v.x * v.x + v.y * v.y + v.z * v.z < 1.001 {
-            return v;
-        }
+// Shirley and Chiu's concentric mapping, keeps neighbouring samples close so the stratification of
+// the sample patterns carries over to the disk
+fn concentric_disk(u: vec2<f32>) -> vec2<f32> {
+    let offset = 2. * u - 1.;
+    if offset.x == 0. && offset.y == 0. {
+        return vec2<f32>(0.);
     }
 
-    return nrand_vec3(r);
+    if abs(offset.x) > abs(offset.y) {
+        let theta = (PI / 4.) * (offset.y / offset.x);
+        return offset.x * vec2<f32>(cos(theta), sin(theta));
+    }
+    let theta = (PI / 2.) - (PI / 4.) * (offset.x / offset.y);
+    return offset.y * vec2<f32>(cos(theta), sin(theta));
 }
 
-fn random_on_hemisphere(normal: vec3<f32>, r: ptr<function,vec2<i32>>) -> vec3<f32> {
-    let on_unit_sphere = normalize(rand_in_unit_sphere(r));
-    if dot(on_unit_sphere, normal) > 0.0 {
-        return on_unit_sphere;
-    } else {
-        return -on_unit_sphere;
-    }
+fn rand_in_unit_disk(r: ptr<function,Rng>) -> vec2<f32> {
+    return concentric_disk(rand_vec2(r));
+}
+
+// lifting a uniform disk sample onto the hemisphere gives directions with pdf cos(theta) / pi
+fn cosine_on_hemisphere(normal: vec3<f32>, r: ptr<function,Rng>) -> vec3<f32> {
+    let d = rand_in_unit_disk(r);
+    let z = sqrt(max(0., 1. - dot(d, d)));
+    let t = tangent(normal);
+    let b = cross(normal, t);
+    return normalize(d.x * t + d.y * b + z * normal);
 }
 
 struct Ray {
@@ -115,7 +711,13 @@ struct HitRecord {
     color: vec4<f32>,
     t: f32,
     front_face: bool,
-    hit: bool
+    hit: bool,
+    material: i32,
+    fuzz: f32,
+    ior: f32,
+    intensity: f32,
+    uv: vec2<f32>,
+    emitter: u32, // one past the index of the emitter that was hit, 0 for other surfaces
 }
 
 fn contains(interval: vec2<f32>, value: f32) -> bool {
@@ -157,13 +759,246 @@ fn hit_sphere(sphere: Sphere, ray: Ray, interval: vec2<f32>) -> HitRecord {
 
     var color: vec4<f32>;
 
-    if params.render_mode == 0 {
+    if params.render_mode == NORMALS {
         color = vec4<f32>(0.5 * (normal + 1.), 1.);
     } else {
         color = sphere.color;
     }
 
-    return HitRecord(point, normal, color, root, front_face, true);
+    // spherical coordinates of the outward normal
+    let outward = (point - sphere.center) / abs(sphere.radius);
+    let uv = vec2<f32>((atan2(-outward.z, outward.x) + PI) / (2. * PI), acos(clamp(-outward.y, -1., 1.)) / PI);
+
+    return HitRecord(point, normal, color, root, front_face, true, sphere.material, sphere.fuzz, sphere.ior, sphere.intensity, uv, sphere.emitter);
+}
+
+// Moller-Trumbore, normals and uvs are interpolated from the vertices
+fn hit_triangle(index: i32, ray: Ray, interval: vec2<f32>) -> HitRecord {
+    let triangle = get_triangle(index);
+    let v0 = get_vertex(i32(triangle.indices.x));
+    let v1 = get_vertex(i32(triangle.indices.y));
+    let v2 = get_vertex(i32(triangle.indices.z));
+
+    let edge1 = v1.position - v0.position;
+    let edge2 = v2.position - v0.position;
+    let p = cross(ray.direction, edge2);
+    let determinant = dot(edge1, p);
+
+    // parallel to the triangle
+    if abs(determinant) < 1e-8 {
+        return HitRecord();
+    }
+
+    let inverse_determinant = 1. / determinant;
+    let s = ray.origin - v0.position;
+    let b1 = dot(s, p) * inverse_determinant;
+    if b1 < 0. || b1 > 1. {
+        return HitRecord();
+    }
+
+    let q = cross(s, edge1);
+    let b2 = dot(ray.direction, q) * inverse_determinant;
+    if b2 < 0. || b1 + b2 > 1. {
+        return HitRecord();
+    }
+
+    let t = dot(edge2, q) * inverse_determinant;
+    if !surrounds(interval, t) {
+        return HitRecord();
+    }
+
+    let b0 = 1. - b1 - b2;
+    var normal = b0 * v0.normal + b1 * v1.normal + b2 * v2.normal;
+    if dot(normal, normal) == 0. {
+        normal = cross(edge1, edge2);
+    }
+    normal = normalize(normal);
+
+    let front_face = dot(ray.direction, normal) < 0.;
+    if !front_face {
+        normal = normal * -1.;
+    }
+
+    let uv = b0 * vec2<f32>(v0.u, v0.v) + b1 * vec2<f32>(v1.u, v1.v) + b2 * vec2<f32>(v2.u, v2.v);
+    let material = get_material(i32(triangle.material));
+
+    var color: vec4<f32>;
+    if params.render_mode == NORMALS {
+        color = vec4<f32>(0.5 * (normal + 1.), 1.);
+    } else {
+        color = material.color;
+    }
+
+    return HitRecord(at(ray, t), normal, color, t, front_face, true, material.material, material.fuzz, material.ior, material.intensity, uv, 0u);
+}
+
+// ray parameter where the ray crosses the plane, or a miss when it runs parallel
+fn intersect_plane(point: vec3<f32>, normal: vec3<f32>, ray: Ray) -> f32 {
+    let denominator = dot(normal, ray.direction);
+    if abs(denominator) < 1e-8 {
+        return BVH_MISS;
+    }
+    return dot(normal, point - ray.origin) / denominator;
+}
+
+fn intersect_disk(center: vec3<f32>, normal: vec3<f32>, radius: f32, ray: Ray) -> f32 {
+    let t = intersect_plane(center, normal, ray);
+    let offset = at(ray, t) - center;
+    if t == BVH_MISS || dot(offset, offset) > radius * radius {
+        return BVH_MISS;
+    }
+    return t;
+}
+
+// a vector perpendicular to the normal, with their cross product it gives uv axes on flat shapes
+fn tangent(normal: vec3<f32>) -> vec3<f32> {
+    if abs(normal.x) > 0.9 {
+        return normalize(cross(normal, vec3<f32>(0., 1., 0.)));
+    }
+    return normalize(cross(normal, vec3<f32>(1., 0., 0.)));
+}
+
+struct ShapeHit {
+    t: f32,
+    normal: vec3<f32>, // outward, not yet flipped to face the ray
+    uv: vec2<f32>,
+}
+
+fn closer(a: ShapeHit, b: ShapeHit, interval: vec2<f32>) -> ShapeHit {
+    if surrounds(interval, b.t) && (!surrounds(interval, a.t) || b.t < a.t) {
+        return b;
+    }
+    return a;
+}
+
+fn hit_shape(primitive: Primitive, ray: Ray, interval: vec2<f32>) -> ShapeHit {
+    let miss = ShapeHit(BVH_MISS, vec3<f32>(0.), vec2<f32>(0.));
+
+    if primitive.shape == PLANE {
+        let normal = normalize(primitive.u);
+        let t = intersect_plane(primitive.origin, normal, ray);
+        let offset = at(ray, t) - primitive.origin;
+        let s = tangent(normal);
+        return ShapeHit(t, normal, fract(vec2<f32>(dot(offset, s), dot(offset, cross(normal, s)))));
+    } else if primitive.shape == QUAD {
+        let n = cross(primitive.u, primitive.v);
+        let t = intersect_plane(primitive.origin, n, ray);
+        let offset = at(ray, t) - primitive.origin;
+        let w = n / dot(n, n);
+        let alpha = dot(w, cross(offset, primitive.v));
+        let beta = dot(w, cross(primitive.u, offset));
+        if t == BVH_MISS || alpha < 0. || alpha > 1. || beta < 0. || beta > 1. {
+            return miss;
+        }
+        return ShapeHit(t, normalize(n), vec2<f32>(alpha, beta));
+    } else if primitive.shape == BOX {
+        let box_min = min(primitive.origin, primitive.u);
+        let box_max = max(primitive.origin, primitive.u);
+        let t0 = (box_min - ray.origin) / ray.direction;
+        let t1 = (box_max - ray.origin) / ray.direction;
+        let t_near = max(max(min(t0.x, t1.x), min(t0.y, t1.y)), min(t0.z, t1.z));
+        let t_far = min(min(max(t0.x, t1.x), max(t0.y, t1.y)), max(t0.z, t1.z));
+        if t_near > t_far {
+            return miss;
+        }
+        var t = t_near;
+        if !surrounds(interval, t) {
+            t = t_far;
+        }
+
+        // the face that was hit is the axis where the point is furthest out, relative to the size
+        let half_size = max((box_max - box_min) * 0.5, vec3<f32>(1e-6));
+        let local = (at(ray, t) - (box_min + box_max) * 0.5) / half_size;
+        let size = abs(local);
+        var normal = vec3<f32>(0., 0., sign(local.z));
+        var uv = local.xy;
+        if size.x >= size.y && size.x >= size.z {
+            normal = vec3<f32>(sign(local.x), 0., 0.);
+            uv = local.yz;
+        } else if size.y >= size.z {
+            normal = vec3<f32>(0., sign(local.y), 0.);
+            uv = local.xz;
+        }
+        return ShapeHit(t, normal, uv * 0.5 + 0.5);
+    } else if primitive.shape == DISK {
+        let normal = normalize(primitive.u);
+        let t = intersect_disk(primitive.origin, normal, primitive.radius, ray);
+        let offset = (at(ray, t) - primitive.origin) / primitive.radius;
+        let s = tangent(normal);
+        let angle = atan2(dot(offset, cross(normal, s)), dot(offset, s));
+        return ShapeHit(t, normal, vec2<f32>((angle + PI) / (2. * PI), length(offset)));
+    } else if primitive.shape == CYLINDER {
+        let height = length(primitive.u);
+        let axis = primitive.u / height;
+        let top = primitive.origin + primitive.u;
+        var closest = ShapeHit(intersect_disk(primitive.origin, axis, primitive.radius, ray), -axis, vec2<f32>(0.));
+        closest = closer(closest, ShapeHit(intersect_disk(top, axis, primitive.radius, ray), axis, vec2<f32>(1.)), interval);
+
+        // the infinite cylinder around the axis, cut off at the caps
+        let offset = ray.origin - primitive.origin;
+        let d = ray.direction - axis * dot(ray.direction, axis);
+        let o = offset - axis * dot(offset, axis);
+        let a = dot(d, d);
+        let half_b = dot(o, d);
+        let c = dot(o, o) - primitive.radius * primitive.radius;
+        let discriminant = half_b * half_b - a * c;
+        if a > 0. && discriminant >= 0. {
+            let sqrt_discriminant = sqrt(discriminant);
+            for (var i = 0; i < 2; i++) {
+                let t = (-half_b + (f32(i) * 2. - 1.) * sqrt_discriminant) / a;
+                let along = dot(offset + ray.direction * t, axis);
+                if along >= 0. && along <= height {
+                    let side = at(ray, t) - (primitive.origin + axis * along);
+                    let s = tangent(axis);
+                    let angle = atan2(dot(side, cross(axis, s)), dot(side, s));
+                    let hit = ShapeHit(t, side / primitive.radius, vec2<f32>((angle + PI) / (2. * PI), along / height));
+                    closest = closer(closest, hit, interval);
+                }
+            }
+        }
+        return closest;
+    } else if primitive.shape == SPHERE {
+        let offset = ray.origin - primitive.origin;
+        let a = dot(ray.direction, ray.direction);
+        let half_b = dot(offset, ray.direction);
+        let c = dot(offset, offset) - primitive.radius * primitive.radius;
+        let discriminant = half_b * half_b - a * c;
+        if discriminant < 0. {
+            return miss;
+        }
+        let sqrt_discriminant = sqrt(discriminant);
+        var t = (-half_b - sqrt_discriminant) / a;
+        if !surrounds(interval, t) {
+            t = (-half_b + sqrt_discriminant) / a;
+        }
+        let normal = (at(ray, t) - primitive.origin) / abs(primitive.radius);
+        let uv = vec2<f32>((atan2(-normal.z, normal.x) + PI) / (2. * PI), acos(clamp(-normal.y, -1., 1.)) / PI);
+        return ShapeHit(t, normal, uv);
+    }
+
+    return miss;
+}
+
+fn hit_primitive(primitive: Primitive, ray: Ray, interval: vec2<f32>) -> HitRecord {
+    let shape_hit = hit_shape(primitive, ray, interval);
+    if !surrounds(interval, shape_hit.t) {
+        return HitRecord();
+    }
+
+    var normal = shape_hit.normal;
+    let front_face = dot(ray.direction, normal) < 0.;
+    if !front_face {
+        normal = normal * -1.;
+    }
+
+    var color: vec4<f32>;
+    if params.render_mode == NORMALS {
+        color = vec4<f32>(0.5 * (normal + 1.), 1.);
+    } else {
+        color = primitive.color;
+    }
+
+    return HitRecord(at(ray, shape_hit.t), normal, color, shape_hit.t, front_face, true, primitive.material, primitive.fuzz, primitive.ior, primitive.intensity, shape_hit.uv, 0u);
 }
 
 
@@ -173,105 +1008,892 @@ fn init(@builtin(global_invocation_id) invocation_id: vec3<u32>, @builtin(num_wo
 
     let color = vec4<f32>(0.5, 0.5, 0.5, 1.0);
     textureStore(texture, location, color);
+
+    store_accumulation(location, vec4<f32>(0., 0., 0., 0.));
+}
+
+fn pixel_index(location: vec2<i32>) -> i32 {
+    let dimensions = textureDimensions(texture);
+    return location.y * i32(dimensions.x) + location.x;
 }
 
 @compute @workgroup_size(8, 8, 1)
 fn update(@builtin(global_invocation_id) invocation_id: vec3<u32>, @builtin(num_workgroups) num_workgroups: vec3<u32>) {
     var location = vec2<i32>(i32(invocation_id.x + u32(params.x)), i32(invocation_id.y + u32(params.y)));
 
-    var seed = vec2<i32>(location);
-
     let pixel_center = camera.pixel00_loc + (f32(location.x) * camera.pixel_delta_u) + (f32(location.y) * camera.pixel_delta_v);
-    let ray_direction = pixel_center - camera.camera_center;
 
     var color = vec4<f32>(0., 0., 0., 1.);
     for (var i: i32 = 0; i < params.samples; i++) {
-        let ray = Ray(camera.camera_center, ray_direction + pixel_sample_square(&seed));
-        color += ray_color(ray, &seed) / f32(params.samples);
+        var rng = new_rng(location, i);
+        let pixel_sample = pixel_center + pixel_sample_square(&rng);
+        let ray_origin = defocus_disk_sample(&rng);
+        let ray = Ray(ray_origin, pixel_sample - ray_origin);
+        if params.render_mode == PATH_TRACED {
+            color += path_trace(ray, &rng) / f32(params.samples);
+        } else {
+            color += ray_color(ray, &rng) / f32(params.samples);
+        }
     }
 
-    storageBarrier();
+    var sum = color;
+    if params.frame != 0 {
+        sum += load_accumulation(location);
+    }
+    store_accumulation(location, sum);
 
-    textureStore(texture, location, color);
+    // the accumulation buffer stays linear, gamma correct radiance for display
+    var average = sum / f32(params.frame + 1);
+    if params.render_mode == PATH_TRACED {
+        average = sqrt(max(average, vec4<f32>(0.)));
+    }
+    textureStore(texture, location, vec4<f32>(average.rgb, 1.));
+}
+
+// a random point on the camera lens, or the camera center for a pinhole camera
+fn defocus_disk_sample(r: ptr<function,Rng>) -> vec3<f32> {
+    if camera.defocus_angle <= 0. {
+        return camera.camera_center;
+    }
+    let p = rand_in_unit_disk(r);
+    return camera.camera_center + (p.x * camera.defocus_disk_u) + (p.y * camera.defocus_disk_v);
 }
 
-fn pixel_sample_square(r: ptr<function,vec2<i32>>) -> vec3<f32> {
-    return (camera.pixel_delta_u * nrand(r)) + (camera.pixel_delta_v * nrand(r));
+fn pixel_sample_square(r: ptr<function,Rng>) -> vec3<f32> {
+    let offset = rand_vec2(r) - 0.5;
+    return (camera.pixel_delta_u * offset.x) + (camera.pixel_delta_v * offset.y);
 }
 
-fn test_hit_spheres(ray: Ray) -> HitRecord {
+// distance to where the ray enters the box, or a miss if it's further than t_max
+fn hit_aabb(node: BvhNode, ray: Ray, inv_direction: vec3<f32>, t_max: f32) -> f32 {
+    let t0 = (node.min - ray.origin) * inv_direction;
+    let t1 = (node.max - ray.origin) * inv_direction;
+    let t_near = max(max(min(t0.x, t1.x), min(t0.y, t1.y)), min(t0.z, t1.z));
+    let t_far = min(min(max(t0.x, t1.x), max(t0.y, t1.y)), max(t0.z, t1.z));
 
-    var closest_hit = HitRecord();
-    closest_hit.t = 10000.;
+    if t_near > t_far || t_far < 0. || t_near > t_max {
+        return BVH_MISS;
+    }
+    return t_near;
+}
 
-    for (var i: i32 = 0; i < params.sphere_count / 2; i++) {
-        let sphere = spheres[i];
-        let interval = vec2<f32>(0.05, closest_hit.t);
-        let hit = hit_sphere(sphere, ray, interval);
+const BVH_MISS: f32 = 1e30;
+// Bvh::build stops splitting at MAX_DEPTH, one less than this, so a traversal never runs out of room
+const BVH_STACK_SIZE: i32 = 64;
+
+// which kind of primitive a bvh's leaves point at
+const SPHERES: i32 = 0;
+const TRIANGLES: i32 = 1;
+
+// pushes the children of an interior node that the ray reaches, the nearer one last so it is
+// visited first and the further one can be culled by its hits
+fn push_children(node: BvhNode, ray: Ray, inv_direction: vec3<f32>, t_max: f32, stack: ptr<function,array<i32, BVH_STACK_SIZE>>, stack_size: ptr<function,i32>) {
+    let left = i32(node.left_first);
+    let right = left + 1;
+    let left_t = hit_aabb(get_node(left), ray, inv_direction, t_max);
+    let right_t = hit_aabb(get_node(right), ray, inv_direction, t_max);
+    var near = left;
+    var far = right;
+    var far_t = right_t;
+    if right_t < left_t {
+        near = right;
+        far = left;
+        far_t = left_t;
+    }
 
-        if hit.hit && hit.t < closest_hit.t {
-            closest_hit = hit;
+    if *stack_size + 2 > BVH_STACK_SIZE {
+        return;
+    }
+    if far_t != BVH_MISS {
+        (*stack)[*stack_size] = far;
+        *stack_size += 1;
+    }
+    if min(left_t, right_t) != BVH_MISS {
+        (*stack)[*stack_size] = near;
+        *stack_size += 1;
+    }
+}
+
+// leaves index into the spheres, or into the triangles from first on
+fn traverse_bvh(ray: Ray, root: i32, primitives: i32, first: i32, closest: HitRecord) -> HitRecord {
+
+    var closest_hit = closest;
+
+    let inv_direction = 1. / ray.direction;
+    var stack = array<i32, BVH_STACK_SIZE>();
+    stack[0] = root;
+    var stack_size = 1;
+
+    while stack_size > 0 {
+        stack_size -= 1;
+        let node = get_node(stack[stack_size]);
+        if hit_aabb(node, ray, inv_direction, closest_hit.t) == BVH_MISS {
+            continue;
+        }
+
+        if node.count > 0u {
+            for (var i = i32(node.left_first); i < i32(node.left_first + node.count); i++) {
+                let interval = vec2<f32>(0.05, closest_hit.t);
+                var hit: HitRecord;
+                if primitives == SPHERES {
+                    hit = hit_sphere(get_sphere(i), ray, interval);
+                } else {
+                    hit = hit_triangle(first + i, ray, interval);
+                }
+
+                if hit.hit && hit.t < closest_hit.t {
+                    closest_hit = hit;
+                }
+            }
+            continue;
+        }
+
+        push_children(node, ray, inv_direction, closest_hit.t, &stack, &stack_size);
+    }
+
+    return closest_hit;
+}
+
+// intersects in object space, the direction isn't normalized so t carries over to the world ray
+fn hit_instance(instance: Instance, ray: Ray, closest: HitRecord) -> HitRecord {
+    let m = instance.world_to_object;
+    let object_ray = Ray(vec4<f32>(ray.origin, 1.) * m, vec4<f32>(ray.direction, 0.) * m);
+
+    var miss = HitRecord();
+    miss.t = closest.t;
+    var hit: HitRecord;
+    if instance.geometry == MESH_GEOMETRY {
+        hit = traverse_bvh(object_ray, i32(instance.index), TRIANGLES, i32(instance.first), miss);
+    } else {
+        hit = hit_primitive(get_primitive(i32(instance.index)), object_ray, vec2<f32>(0.05, closest.t));
+    }
+    if !hit.hit || hit.t >= closest.t {
+        return closest;
+    }
+
+    // normals go back with the transpose of world_to_object
+    hit.point = at(ray, hit.t);
+    hit.emitter = instance.emitter;
+    hit.normal = normalize((m * hit.normal).xyz);
+    if params.render_mode == NORMALS {
+        hit.color = vec4<f32>(0.5 * (hit.normal + 1.), 1.);
+    }
+    return hit;
+}
+
+// the unbounded instances come first in the buffer, the leaves index the ones after them
+fn traverse_instances(ray: Ray, closest: HitRecord) -> HitRecord {
+
+    var closest_hit = closest;
+
+    let inv_direction = 1. / ray.direction;
+    var stack = array<i32, BVH_STACK_SIZE>();
+    stack[0] = params.instance_root;
+    var stack_size = 1;
+
+    while stack_size > 0 {
+        stack_size -= 1;
+        let node = get_node(stack[stack_size]);
+        if hit_aabb(node, ray, inv_direction, closest_hit.t) == BVH_MISS {
+            continue;
         }
+
+        if node.count > 0u {
+            for (var i = i32(node.left_first); i < i32(node.left_first + node.count); i++) {
+                closest_hit = hit_instance(get_instance(params.unbounded_count + i), ray, closest_hit);
+            }
+            continue;
+        }
+
+        push_children(node, ray, inv_direction, closest_hit.t, &stack, &stack_size);
+    }
+
+    return closest_hit;
+}
+
+fn test_hit_scene(ray: Ray) -> HitRecord {
+    return hit_scene_before(ray, 10000.);
+}
+
+// only hits closer than t_max, shadow rays stop at the light
+fn hit_scene_before(ray: Ray, t_max: f32) -> HitRecord {
+
+    var closest_hit = HitRecord();
+    closest_hit.t = t_max;
+
+    if params.sphere_count > 0 {
+        closest_hit = traverse_bvh(ray, 0, SPHERES, 0, closest_hit);
+    }
+    if params.instance_count > 0 {
+        closest_hit = traverse_instances(ray, closest_hit);
+    }
+
+    for (var i = 0; i < params.unbounded_count; i++) {
+        closest_hit = hit_instance(get_instance(i), ray, closest_hit);
     }
 
     return closest_hit;
 }
 
-fn ray_color(ray: Ray, r: ptr<function,vec2<i32>>) -> vec4<f32> {
+// the hit colors are folded in as they come, so the depth isn't limited by an array of hits
+struct HitColors {
+    count: i32,
+    sum: vec4<f32>,
+    blended: vec4<f32>, // each hit weighted half as much as the one before
+    last: vec4<f32>,
+}
+
+fn add_hit_color(colors: ptr<function,HitColors>, color: vec4<f32>) {
+    (*colors).count += 1;
+    (*colors).sum += color;
+    (*colors).blended += color / pow(2., f32((*colors).count));
+    (*colors).last = color;
+}
+
+fn ray_color(ray: Ray, r: ptr<function,Rng>) -> vec4<f32> {
 
     var ray = ray;
 
-    var hit_colours = array<vec4<f32>, 10>();
-    var hits = 0;
+    var colors = HitColors(0, vec4<f32>(0.), vec4<f32>(0.), vec4<f32>(0.));
 
     let bg_color = background_color(ray);
     var has_hit = false;
-    while hits < params.depth {
-        let closest_hit = test_hit_spheres(ray);
+    while colors.count < params.depth {
+        let closest_hit = test_hit_scene(ray);
 
         if closest_hit.hit {
-            hit_colours[hits] = closest_hit.color;
-
-            let direction = random_on_hemisphere(closest_hit.normal, r);
-            ray = Ray(closest_hit.point, direction);
-            hits += 1;
+            add_hit_color(&colors, closest_hit.color);
             has_hit = true;
+
+            let scattered = scatter(ray, closest_hit, r);
+            if scattered.absorbed {
+                if closest_hit.material != EMISSIVE && colors.count < params.depth {
+                    add_hit_color(&colors, vec4<f32>(0., 0., 0., 1.));
+                }
+                break;
+            }
+            ray = Ray(closest_hit.point, scattered.direction);
         } else {
 
-            if hits > 0 {
-                hit_colours[hits] = vec4<f32>(0., 0., 0., 1.);
-                hits += 1;
+            if colors.count > 0 {
+                add_hit_color(&colors, vec4<f32>(0., 0., 0., 1.));
             }
 
             break;
         }
     }
 
-    var color = vec4<f32>(0., 0., 0., 1.);
-
     if has_hit {
 
         if params.render_mode == 2 { // blended
-            for (var i: i32 = 0; i < hits; i++) {
-                color += hit_colours[i] / pow(2., f32(i + 1));
-            }
-            return color / f32(hits);
+            return colors.blended / f32(colors.count);
         } else if params.render_mode == 3 { // last hit
-            return hit_colours[hits - 1];
+            return colors.last;
         } else { // normals/averaged
-            for (var i: i32 = 0; i < hits; i++) {
-                color += hit_colours[i];
-            }
-            return color / f32(hits);
+            return colors.sum / f32(colors.count);
         }
     } else {
         return bg_color;
     }
 }
 
+// tracks the fraction of light carried back along the path, and adds whatever the path hits that glows
+fn path_trace(ray: Ray, r: ptr<function,Rng>) -> vec4<f32> {
+    var ray = ray;
+    var throughput = vec3<f32>(1., 1., 1.);
+    var radiance = vec3<f32>(0., 0., 0.);
+    // the pdf of the bounce that made the ray, 0 for camera rays and mirror-like bounces
+    var bsdf_pdf = 0.;
+
+    for (var bounce: i32 = 0; bounce < params.depth; bounce++) {
+        let hit = test_hit_scene(ray);
+
+        if !hit.hit && params.sky == PHYSICAL_SKY {
+            // the sun is sampled directly at glossy hits, so bounces from them only get their share
+            var sun_weight = 1.;
+            if params.mis != 0 && bsdf_pdf > 0. {
+                sun_weight = power_heuristic(bsdf_pdf, sun_pdf());
+            }
+            radiance += throughput * (physical_sky(ray.direction) + sun_radiance(ray.direction) * sun_weight);
+            break;
+        }
+
+        if !hit.hit {
+            // the bounce could also have sampled the environment map in this direction
+            var weight = 1.;
+            if params.mis != 0 && params.sky == ENVIRONMENT_SKY && bsdf_pdf > 0. {
+                weight = power_heuristic(bsdf_pdf, environment_pdf(ray.direction));
+            }
+            radiance += throughput * background_color(ray).rgb * weight;
+            break;
+        }
+
+        if hit.material == EMISSIVE {
+            // the bounce could also have sampled this emitter directly, so it only gets its share
+            var weight = 1.;
+            if params.mis != 0 && hit.emitter > 0u && bsdf_pdf > 0. {
+                let emitter = get_emitter(i32(hit.emitter) - 1);
+                let light_pdf = emitter_pdf(emitter, ray.origin, hit.point) / f32(params.emitter_count);
+                weight = power_heuristic(bsdf_pdf, light_pdf);
+            }
+            radiance += throughput * hit.color.rgb * hit.intensity * weight;
+            break;
+        }
+
+        // rays can never hit the lights, so diffuse surfaces look for them directly
+        if hit.material == LAMBERTIAN {
+            radiance += throughput * sample_lights(hit);
+        }
+
+        let glossy = hit.material == LAMBERTIAN || (hit.material == METAL && hit.fuzz > 0.);
+        if params.mis != 0 && params.emitter_count > 0 && glossy {
+            radiance += throughput * sample_emitters(ray, hit, r);
+        }
+        if params.mis != 0 && params.sky == ENVIRONMENT_SKY && glossy {
+            radiance += throughput * sample_sky(ray, hit, r);
+        }
+        if params.mis != 0 && params.sky == PHYSICAL_SKY && glossy {
+            radiance += throughput * sample_sun(ray, hit, r);
+        }
+
+        let scattered = scatter(ray, hit, r);
+        if scattered.absorbed {
+            break;
+        }
+
+        throughput *= scattered.attenuation;
+        bsdf_pdf = scattered.pdf;
+        ray = Ray(hit.point, scattered.direction);
+
+        // past the first few bounces, dim paths are ended at random and the survivors brightened
+        // to make up for them, so long paths cost little and the average stays the same
+        if bounce + 1 >= ROULETTE_DEPTH {
+            let survival = min(max(throughput.r, max(throughput.g, throughput.b)), 0.95);
+            if rand_float(r) >= survival {
+                break;
+            }
+            throughput /= survival;
+        }
+    }
+
+    return vec4<f32>(radiance, 1.);
+}
+
+// light reaching a diffuse hit straight from each of the lights, those with something in the way
+// are left out
+fn sample_lights(hit: HitRecord) -> vec3<f32> {
+    var total = vec3<f32>(0.);
+
+    for (var i = 0; i < params.light_count; i++) {
+        let light = get_light(i);
+
+        var to_light: vec3<f32>;
+        var distance: f32;
+        var irradiance = light.color * light.intensity;
+        if light.kind == DIRECTIONAL_LIGHT {
+            to_light = -normalize(light.direction);
+            distance = 10000.;
+        } else {
+            let offset = light.position - hit.point;
+            distance = length(offset);
+            to_light = offset / distance;
+            irradiance /= distance * distance;
+
+            if light.kind == SPOT_LIGHT {
+                let cos_outer = cos(light.outer_angle);
+                let cos_inner = max(cos(light.inner_angle), cos_outer + 0.0001);
+                let cos_axis = dot(-to_light, normalize(light.direction));
+                irradiance *= smoothstep(cos_outer, cos_inner, cos_axis);
+            }
+        }
+
+        let cos_theta = dot(to_light, hit.normal);
+        if cos_theta <= 0. {
+            continue;
+        }
+        if hit_scene_before(Ray(hit.point, to_light), distance).hit {
+            continue;
+        }
+        total += lambertian_brdf(hit) * irradiance * cos_theta;
+    }
+
+    return total;
+}
+
+struct Scatter {
+    direction: vec3<f32>,
+    attenuation: vec3<f32>, // brdf * cos(theta) / pdf, what the throughput is multiplied by
+    pdf: f32, // per solid angle, 0 when only one direction was possible
+    absorbed: bool,
+}
+
+fn scatter(ray: Ray, hit: HitRecord, r: ptr<function,Rng>) -> Scatter {
+    if hit.material == EMISSIVE {
+        return Scatter(vec3<f32>(0.), vec3<f32>(0.), 0., true);
+    }
+
+    // metal and glass scatter by their color, the rest of the brdf cancels against the pdf. mirrors
+    // and glass only have one direction to go in
+    if hit.material == METAL {
+        let reflected = reflect(normalize(ray.direction), hit.normal);
+        let direction = reflected + hit.fuzz * rand_in_unit_sphere(r);
+
+        var pdf = 0.;
+        if hit.fuzz > 0. {
+            pdf = metal_pdf(reflected, hit.fuzz, direction);
+        }
+        // fuzzed below the surface, the ray is absorbed
+        return Scatter(direction, hit.color.rgb, pdf, dot(direction, hit.normal) <= 0.);
+    }
+
+    if hit.material == DIELECTRIC {
+        return Scatter(refract_or_reflect(ray, hit, r), hit.color.rgb, 0., false);
+    }
+
+    let direction = cosine_on_hemisphere(hit.normal, r);
+    let cos_theta = dot(direction, hit.normal);
+    let pdf = cos_theta / PI;
+    // grazing directions have no pdf to divide by and carry no light anyway
+    if pdf <= 0. {
+        return Scatter(direction, vec3<f32>(0.), 0., true);
+    }
+    let brdf = lambertian_brdf(hit);
+    return Scatter(direction, brdf * cos_theta / pdf, pdf, false);
+}
+
+// the density of reflected + fuzz * p over directions, with p uniform in the unit ball. the
+// direction's ray passes through the ball from t0 to t1, and the cone's volume in there is the
+// share of the ball's that scatters that way
+fn metal_pdf(reflected: vec3<f32>, fuzz: f32, direction: vec3<f32>) -> f32 {
+    let along = dot(normalize(direction), reflected);
+    let discriminant = along * along - 1. + fuzz * fuzz;
+    if discriminant <= 0. {
+        return 0.;
+    }
+
+    let t1 = along + sqrt(discriminant);
+    let t0 = max(along - sqrt(discriminant), 0.);
+    if t1 <= 0. {
+        return 0.;
+    }
+    let volume = 4. / 3. * PI * fuzz * fuzz * fuzz;
+    return (t1 * t1 * t1 - t0 * t0 * t0) / (3. * volume);
+}
+
+struct BsdfValue {
+    value: vec3<f32>, // brdf * cos(theta)
+    pdf: f32,         // of scatter picking the same direction
+}
+
+// what scatter would have made of a direction it didn't pick itself, for sampling the emitters
+fn evaluate_bsdf(ray: Ray, hit: HitRecord, direction: vec3<f32>) -> BsdfValue {
+    let cos_theta = dot(direction, hit.normal);
+    if cos_theta <= 0. {
+        return BsdfValue(vec3<f32>(0.), 0.);
+    }
+
+    if hit.material == LAMBERTIAN {
+        return BsdfValue(lambertian_brdf(hit) * cos_theta, cos_theta / PI);
+    }
+    if hit.material == METAL && hit.fuzz > 0. {
+        let reflected = reflect(normalize(ray.direction), hit.normal);
+        let pdf = metal_pdf(reflected, hit.fuzz, direction);
+        return BsdfValue(hit.color.rgb * pdf, pdf);
+    }
+    return BsdfValue(vec3<f32>(0.), 0.);
+}
+
+// Veach's power heuristic with a power of two, the weight of the strategy with density a
+fn power_heuristic(a: f32, b: f32) -> f32 {
+    let a2 = a * a;
+    let b2 = b * b;
+    if a2 + b2 <= 0. {
+        return 0.;
+    }
+    return a2 / (a2 + b2);
+}
+
+// 1 - cos of the widest angle a sphere covers from outside it, written so that small far away
+// spheres don't lose it all to rounding
+fn sphere_cone(emitter: Emitter, origin: vec3<f32>) -> f32 {
+    let to_center = emitter.origin - origin;
+    let distance_squared = dot(to_center, to_center);
+    let radius_squared = emitter.radius * emitter.radius;
+    if distance_squared <= radius_squared {
+        return 0.;
+    }
+    let sin_squared = radius_squared / distance_squared;
+    return sin_squared / (1. + sqrt(1. - sin_squared));
+}
+
+fn quad_pdf(emitter: Emitter, direction: vec3<f32>, distance: f32) -> f32 {
+    let normal = normalize(cross(emitter.u, emitter.v));
+    let cos_light = abs(dot(direction, normal));
+    if cos_light < 1e-6 {
+        return 0.;
+    }
+    return distance * distance / (cos_light * emitter.area);
+}
+
+struct EmitterSample {
+    direction: vec3<f32>,
+    distance: f32,
+    pdf: f32, // per solid angle, 0 when the emitter can't be sampled from here
+}
+
+// spheres are sampled over the cone they cover and quads over their area
+fn sample_emitter(emitter: Emitter, origin: vec3<f32>, u: vec2<f32>) -> EmitterSample {
+    if emitter.shape == SPHERE {
+        let cone = sphere_cone(emitter, origin);
+        if cone <= 0. {
+            return EmitterSample(vec3<f32>(0.), 0., 0.);
+        }
+
+        let to_center = emitter.origin - origin;
+        let center_distance = length(to_center);
+        let axis = to_center / center_distance;
+        let t = tangent(axis);
+        let b = cross(axis, t);
+        let cos_theta = 1. - u.x * cone;
+        let sin_theta = sqrt(max(0., 1. - cos_theta * cos_theta));
+        let phi = 2. * PI * u.y;
+        let direction = normalize(cos_theta * axis + sin_theta * (cos(phi) * t + sin(phi) * b));
+
+        // the near side of the sphere along the direction
+        let along = center_distance * cos_theta;
+        let off_axis = center_distance * center_distance - along * along;
+        let distance = along - sqrt(max(0., emitter.radius * emitter.radius - off_axis));
+        return EmitterSample(direction, distance, 1. / (2. * PI * cone));
+    }
+
+    let point = emitter.origin + u.x * emitter.u + u.y * emitter.v;
+    let offset = point - origin;
+    let distance = length(offset);
+    let direction = offset / distance;
+    return EmitterSample(direction, distance, quad_pdf(emitter, direction, distance));
+}
+
+// how likely sample_emitter was to pick point, seen from origin
+fn emitter_pdf(emitter: Emitter, origin: vec3<f32>, point: vec3<f32>) -> f32 {
+    if emitter.shape == SPHERE {
+        let cone = sphere_cone(emitter, origin);
+        if cone <= 0. {
+            return 0.;
+        }
+        return 1. / (2. * PI * cone);
+    }
+
+    let offset = point - origin;
+    let distance = length(offset);
+    return quad_pdf(emitter, offset / distance, distance);
+}
+
+// one emitter picked at random, weighed against scatter finding the same point
+fn sample_emitters(ray: Ray, hit: HitRecord, r: ptr<function,Rng>) -> vec3<f32> {
+    let count = params.emitter_count;
+    let pick = min(i32(rand_float(r) * f32(count)), count - 1);
+    let emitter = get_emitter(pick);
+
+    let sample = sample_emitter(emitter, hit.point, rand_vec2(r));
+    if sample.pdf <= 0. {
+        return vec3<f32>(0.);
+    }
+    let bsdf = evaluate_bsdf(ray, hit, sample.direction);
+    if bsdf.pdf <= 0. {
+        return vec3<f32>(0.);
+    }
+    // stopping just short of the emitter itself
+    if hit_scene_before(Ray(hit.point, sample.direction), sample.distance * 0.999).hit {
+        return vec3<f32>(0.);
+    }
+
+    let light_pdf = sample.pdf / f32(count);
+    return bsdf.value * emitter.emission * power_heuristic(light_pdf, bsdf.pdf) / light_pdf;
+}
+
+fn lambertian_brdf(hit: HitRecord) -> vec3<f32> {
+    return hit.color.rgb / PI;
+}
+
+fn refract_or_reflect(ray: Ray, hit: HitRecord, r: ptr<function,Rng>) -> vec3<f32> {
+    // the normal always faces the ray, so front_face says whether we are entering or leaving
+    var refraction_ratio = hit.ior;
+    if hit.front_face {
+        refraction_ratio = 1. / hit.ior;
+    }
+
+    let unit_direction = normalize(ray.direction);
+    let cos_theta = min(dot(-unit_direction, hit.normal), 1.);
+    let sin_theta = sqrt(1. - cos_theta * cos_theta);
+
+    let total_internal_reflection = refraction_ratio * sin_theta > 1.;
+    if total_internal_reflection || reflectance(cos_theta, refraction_ratio) > rand_float(r) {
+        return reflect(unit_direction, hit.normal);
+    }
+    return refract(unit_direction, hit.normal, refraction_ratio);
+}
+
+// Schlick's approximation
+fn reflectance(cosine: f32, refraction_ratio: f32) -> f32 {
+    let r0 = pow((1. - refraction_ratio) / (1. + refraction_ratio), 2.);
+    return r0 + (1. - r0) * pow(1. - cosine, 5.);
+}
+
 fn background_color(ray: Ray) -> vec4<f32> {
+    if params.sky == NO_SKY {
+        return vec4<f32>(0., 0., 0., 1.);
+    }
+    if params.sky == ENVIRONMENT_SKY {
+        return vec4<f32>(environment_color(ray.direction), 1.);
+    }
+    if params.sky == PHYSICAL_SKY {
+        return vec4<f32>(physical_sky(ray.direction) + sun_radiance(ray.direction), 1.);
+    }
+
     let direction = normalize(ray.direction);
     let value = (direction.y + 1.) / 2.;
     let rgb = ((1.0 - value) * vec3<f32>(1., 1., 1.)) + (value * vec3<f32>(0.5, 0.7, 1.));
     return vec4<f32>(rgb, 1.);
-}
\ No newline at end of file
+}
+// an equirectangular hdr image, read a texel at a time since rgba32float can't be filtered
+@group(0) @binding(14)
+var environment: texture_2d<f32>;
+
+// environment::environment_cdf, a cdf over each row's texels, then one over the rows and the sum
+// of all the weights
+@group(0) @binding(15)
+var environment_cdf: texture_2d<f32>;
+
+// matches environment::luminance
+fn luminance(color: vec3<f32>) -> f32 {
+    return dot(color, vec3<f32>(0.2126, 0.7152, 0.0722));
+}
+
+fn rotate_y(v: vec3<f32>, angle: f32) -> vec3<f32> {
+    let c = cos(angle);
+    let s = sin(angle);
+    return vec3<f32>(c * v.x + s * v.z, v.y, c * v.z - s * v.x);
+}
+
+// u goes around from -z with the image's center in front, v from straight up to straight down
+fn environment_uv(direction: vec3<f32>) -> vec2<f32> {
+    let d = rotate_y(normalize(direction), -params.sky_rotation);
+    let u = 0.5 + atan2(d.x, -d.z) / (2. * PI);
+    let v = acos(clamp(d.y, -1., 1.)) / PI;
+    return vec2<f32>(u, v);
+}
+
+fn environment_direction(uv: vec2<f32>) -> vec3<f32> {
+    let theta = uv.y * PI;
+    let phi = (uv.x - 0.5) * 2. * PI;
+    let d = vec3<f32>(sin(theta) * sin(phi), cos(theta), -sin(theta) * cos(phi));
+    return rotate_y(d, params.sky_rotation);
+}
+
+fn environment_texel(direction: vec3<f32>) -> vec2<i32> {
+    let size = vec2<i32>(textureDimensions(environment));
+    let texel = vec2<i32>(environment_uv(direction) * vec2<f32>(size));
+    return clamp(texel, vec2<i32>(0), size - 1);
+}
+
+fn environment_color(direction: vec3<f32>) -> vec3<f32> {
+    return textureLoad(environment, environment_texel(direction), 0).rgb * params.sky_intensity;
+}
+
+fn cdf_at(x: i32, row: i32) -> f32 {
+    if x < 0 {
+        return 0.;
+    }
+    return textureLoad(environment_cdf, vec2<i32>(x, row), 0).r;
+}
+
+// the first of count entries in row whose cdf is past u
+fn search_cdf(row: i32, count: i32, u: f32) -> i32 {
+    var low = 0;
+    var high = count - 1;
+    while low < high {
+        let middle = (low + high) / 2;
+        if cdf_at(middle, row) > u {
+            high = middle;
+        } else {
+            low = middle + 1;
+        }
+    }
+    return low;
+}
+
+// the sum of the texel weights, 0 when the map is black and can't be sampled
+fn environment_total() -> f32 {
+    let size = vec2<i32>(textureDimensions(environment_cdf));
+    return cdf_at(size.x - 1, size.y - 1);
+}
+
+// a texel picked by its weight, then a point inside it. where u fell within the picked entries
+// is uniform, so it places the point without more random numbers
+fn sample_environment(u: vec2<f32>) -> vec3<f32> {
+    let size = vec2<i32>(textureDimensions(environment));
+    let y = search_cdf(size.y, size.y, u.x);
+    let x = search_cdf(y, size.x, u.y);
+
+    let y_low = cdf_at(y - 1, size.y);
+    let x_low = cdf_at(x - 1, y);
+    let fy = clamp((u.x - y_low) / max(cdf_at(y, size.y) - y_low, 1e-8), 0., 1.);
+    let fx = clamp((u.y - x_low) / max(cdf_at(x, y) - x_low, 1e-8), 0., 1.);
+    let uv = (vec2<f32>(f32(x), f32(y)) + vec2<f32>(fx, fy)) / vec2<f32>(size);
+    return environment_direction(uv);
+}
+
+// the texel's share of the weights spread over its solid angle, which shrinks with sin(theta)
+// towards the poles
+fn environment_pdf(direction: vec3<f32>) -> f32 {
+    let total = environment_total();
+    let d = normalize(direction);
+    let sin_theta = sqrt(max(0., 1. - d.y * d.y));
+    if total <= 0. || sin_theta <= 0. {
+        return 0.;
+    }
+
+    let size = vec2<i32>(textureDimensions(environment));
+    let texel = environment_texel(d);
+    let row_sin = sin(PI * (f32(texel.y) + 0.5) / f32(size.y));
+    let weight = max(luminance(textureLoad(environment, texel, 0).rgb), 0.) * row_sin;
+    return weight / total * f32(size.x * size.y) / (2. * PI * PI * sin_theta);
+}
+
+// a bright direction of the environment map, weighed against scatter finding it
+fn sample_sky(ray: Ray, hit: HitRecord, r: ptr<function,Rng>) -> vec3<f32> {
+    if environment_total() <= 0. {
+        return vec3<f32>(0.);
+    }
+    let direction = sample_environment(rand_vec2(r));
+    let light_pdf = environment_pdf(direction);
+    if light_pdf <= 0. {
+        return vec3<f32>(0.);
+    }
+    let bsdf = evaluate_bsdf(ray, hit, direction);
+    if bsdf.pdf <= 0. {
+        return vec3<f32>(0.);
+    }
+    if hit_scene_before(Ray(hit.point, direction), 10000.).hit {
+        return vec3<f32>(0.);
+    }
+    return bsdf.value * environment_color(direction) * power_heuristic(light_pdf, bsdf.pdf) / light_pdf;
+}
+
+// the preetham, shirley and smits daylight model. the sky is in kcd/m^2 and the sun in klux, scaled
+// down so a clear sky is about as bright as the gradient
+const SKY_SCALE: f32 = 0.05;
+// the sun's illuminance above the atmosphere, facing it
+const SUN_ILLUMINANCE: f32 = 127.5;
+// the angle the sun's disk covers from its center to its edge
+const SUN_RADIUS: f32 = 0.00465;
+
+fn sun_direction() -> vec3<f32> {
+    let c = cos(params.sun_elevation);
+    return vec3<f32>(c * sin(params.sun_azimuth), sin(params.sun_elevation), -c * cos(params.sun_azimuth));
+}
+
+// the perez distribution, how the sky's brightness changes with the angle theta from the zenith
+// and gamma from the sun
+fn perez(theta: f32, gamma: f32, a: vec3<f32>, b: vec3<f32>, c: vec3<f32>, d: vec3<f32>, e: vec3<f32>) -> vec3<f32> {
+    let cos_gamma = cos(gamma);
+    return (1. + a * exp(b / cos(theta))) * (1. + c * exp(d * gamma) + e * cos_gamma * cos_gamma);
+}
+
+fn xyz_to_rgb(xyz: vec3<f32>) -> vec3<f32> {
+    return vec3<f32>(
+        3.2406 * xyz.x - 1.5372 * xyz.y - 0.4986 * xyz.z,
+        -0.9689 * xyz.x + 1.8758 * xyz.y + 0.0415 * xyz.z,
+        0.0557 * xyz.x - 0.2040 * xyz.y + 1.0570 * xyz.z,
+    );
+}
+
+// the sky without the sun. there is no ground, so below the horizon the sky keeps its horizon color
+fn physical_sky(direction: vec3<f32>) -> vec3<f32> {
+    let t = params.turbidity;
+    let sun = sun_direction();
+    let theta_sun = acos(clamp(sun.y, 0., 1.));
+    let d = normalize(direction);
+    let theta = acos(clamp(d.y, 0.001, 1.));
+    let gamma = acos(clamp(dot(d, sun), -1., 1.));
+
+    // luminance Y and chromaticity x, y
+    let a = vec3<f32>(0.1787 * t - 1.4630, -0.0193 * t - 0.2592, -0.0167 * t - 0.2608);
+    let b = vec3<f32>(-0.3554 * t + 0.4275, -0.0665 * t + 0.0008, -0.0950 * t + 0.0092);
+    let c = vec3<f32>(-0.0227 * t + 5.3251, -0.0004 * t + 0.2125, -0.0079 * t + 0.2102);
+    let dd = vec3<f32>(0.1206 * t - 2.5771, -0.0641 * t - 0.8989, -0.0441 * t - 1.6537);
+    let e = vec3<f32>(-0.0670 * t + 0.3703, -0.0033 * t + 0.0452, -0.0109 * t + 0.0529);
+
+    let chi = (4. / 9. - t / 120.) * (PI - 2. * theta_sun);
+    let zenith_luminance = (4.0453 * t - 4.9710) * tan(chi) - 0.2155 * t + 2.4192;
+    let angles = vec4<f32>(theta_sun * theta_sun * theta_sun, theta_sun * theta_sun, theta_sun, 1.);
+    let zenith_x = t * t * dot(vec4<f32>(0.00166, -0.00375, 0.00209, 0.), angles)
+        + t * dot(vec4<f32>(-0.02903, 0.06377, -0.03202, 0.00394), angles)
+        + dot(vec4<f32>(0.11693, -0.21196, 0.06052, 0.25886), angles);
+    let zenith_y = t * t * dot(vec4<f32>(0.00275, -0.00610, 0.00317, 0.), angles)
+        + t * dot(vec4<f32>(-0.04214, 0.08970, -0.04153, 0.00516), angles)
+        + dot(vec4<f32>(0.15346, -0.26756, 0.06670, 0.26688), angles);
+    let zenith = vec3<f32>(max(zenith_luminance, 0.), zenith_x, zenith_y);
+
+    let yxy = zenith * perez(theta, gamma, a, b, c, dd, e) / perez(0., theta_sun, a, b, c, dd, e);
+    let xyz = vec3<f32>(yxy.y / yxy.z * yxy.x, yxy.x, (1. - yxy.y - yxy.z) / yxy.z * yxy.x);
+    return max(xyz_to_rgb(xyz), vec3<f32>(0.)) * SKY_SCALE * params.sky_intensity;
+}
+
+// the share of sunlight that makes it through the air at red, green and blue wavelengths, lost
+// to rayleigh scattering and to haze, which grows with turbidity
+fn sun_transmittance() -> vec3<f32> {
+    let theta_sun = acos(clamp(sin(params.sun_elevation), 0., 1.));
+    let degrees = theta_sun * 180. / PI;
+    let air_mass = 1. / (cos(theta_sun) + 0.15 * pow(93.885 - degrees, -1.253));
+    let wavelengths = vec3<f32>(0.65, 0.55, 0.45); // micrometers
+    let rayleigh = 0.008735 * pow(wavelengths, vec3<f32>(-4.08));
+    let beta = 0.04608 * params.turbidity - 0.04586;
+    let aerosol = beta * pow(wavelengths, vec3<f32>(-1.3));
+    return exp(-(rayleigh + aerosol) * air_mass);
+}
+
+// 1 - cos(SUN_RADIUS), written so it doesn't round away
+fn sun_cone() -> f32 {
+    let s = sin(SUN_RADIUS / 2.);
+    return 2. * s * s;
+}
+
+fn sun_pdf() -> f32 {
+    return 1. / (2. * PI * sun_cone());
+}
+
+// the disk is evenly bright, its illuminance spread over the little solid angle it covers
+fn sun_disk() -> vec3<f32> {
+    let illuminance = SUN_ILLUMINANCE * SKY_SCALE * params.sky_intensity;
+    return sun_transmittance() * illuminance * sun_pdf();
+}
+
+fn sun_radiance(direction: vec3<f32>) -> vec3<f32> {
+    if 1. - dot(normalize(direction), sun_direction()) > sun_cone() {
+        return vec3<f32>(0.);
+    }
+    return sun_disk();
+}
+
+// a direction inside the sun's disk, weighed against scatter finding it
+fn sample_sun(ray: Ray, hit: HitRecord, r: ptr<function,Rng>) -> vec3<f32> {
+    let axis = sun_direction();
+    let t = tangent(axis);
+    let b = cross(axis, t);
+    let u = rand_vec2(r);
+    let cos_theta = 1. - u.x * sun_cone();
+    let sin_theta = sqrt(max(0., 1. - cos_theta * cos_theta));
+    let phi = 2. * PI * u.y;
+    let direction = normalize(cos_theta * axis + sin_theta * (cos(phi) * t + sin(phi) * b));
+
+    let bsdf = evaluate_bsdf(ray, hit, direction);
+    if bsdf.pdf <= 0. {
+        return vec3<f32>(0.);
+    }
+    if hit_scene_before(Ray(hit.point, direction), 10000.).hit {
+        return vec3<f32>(0.);
+    }
+    let light_pdf = sun_pdf();
+    return bsdf.value * sun_disk() * power_heuristic(light_pdf, bsdf.pdf) / light_pdf;
+}
@@ -0,0 +1,493 @@
+use bevy::{
+    prelude::*,
+    render::{extract_resource::ExtractResource, render_resource::ShaderType},
+};
+use bytemuck::{Pod, Zeroable};
+
+use crate::collidables::Spheres;
+
+// leaves with this many primitives or fewer are never split
+const MAX_LEAF_SIZE: usize = 4;
+const SAH_BINS: usize = 16;
+
+// the shader walks trees with a fixed size stack, which needs one slot per level plus one.
+// matches BVH_STACK_SIZE - 1 in simple.wgsl
+const MAX_DEPTH: usize = 63;
+
+// refitting keeps the old tree shape, so rebuild every so often to keep it tight
+const REFITS_BEFORE_REBUILD: u32 = 60;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Aabb {
+    pub min: Vec3,
+    pub max: Vec3,
+}
+
+impl Aabb {
+    pub const EMPTY: Aabb = Aabb {
+        min: Vec3::splat(f32::INFINITY),
+        max: Vec3::splat(f32::NEG_INFINITY),
+    };
+
+    pub fn union(&self, other: &Aabb) -> Aabb {
+        Aabb {
+            min: self.min.min(other.min),
+            max: self.max.max(other.max),
+        }
+    }
+
+    pub fn grow(&mut self, point: Vec3) {
+        self.min = self.min.min(point);
+        self.max = self.max.max(point);
+    }
+
+    pub fn centroid(&self) -> Vec3 {
+        (self.min + self.max) * 0.5
+    }
+
+    pub fn area(&self) -> f32 {
+        let extent = self.max - self.min;
+        if extent.min_element() < 0. {
+            return 0.;
+        }
+        2. * (extent.x * extent.y + extent.y * extent.z + extent.z * extent.x)
+    }
+
+    // same slab test as hit_aabb in simple.wgsl
+    fn hit(&self, origin: Vec3, inv_direction: Vec3, t_max: f32) -> bool {
+        let t0 = (self.min - origin) * inv_direction;
+        let t1 = (self.max - origin) * inv_direction;
+        let t_near = t0.min(t1).max_element();
+        let t_far = t0.max(t1).min_element();
+        t_near <= t_far && t_far >= 0. && t_near <= t_max
+    }
+}
+
+// two vec4s, so it packs into the fallback texture as well as a storage buffer.
+// interior nodes have count 0 and their children at left_first and left_first + 1,
+// leaves cover count primitives starting at left_first
+#[derive(ShaderType, Pod, Zeroable, Clone, Copy, Default, Debug, PartialEq)]
+#[repr(C)]
+pub struct BvhNode {
+    pub min: [f32; 3],
+    pub left_first: u32,
+    pub max: [f32; 3],
+    pub count: u32,
+}
+
+impl BvhNode {
+    fn bounds(&self) -> Aabb {
+        Aabb {
+            min: Vec3::from(self.min),
+            max: Vec3::from(self.max),
+        }
+    }
+
+    fn set_bounds(&mut self, bounds: Aabb) {
+        self.min = bounds.min.into();
+        self.max = bounds.max.into();
+    }
+}
+
+// primitives are uploaded in `order`, so every leaf covers a contiguous range of them
+#[derive(Resource, ExtractResource, Clone, Default, Debug)]
+pub struct Bvh {
+    pub nodes: Vec<BvhNode>,
+    pub order: Vec<u32>,
+    refits: u32,
+}
+
+impl Bvh {
+    // binned surface area heuristic
+    pub fn build(bounds: &[Aabb]) -> Self {
+        Bvh::build_to_depth(bounds, MAX_DEPTH)
+    }
+
+    // nodes max_depth levels below the root are left as leaves however many primitives they hold
+    fn build_to_depth(bounds: &[Aabb], max_depth: usize) -> Self {
+        let mut bvh = Bvh {
+            nodes: Vec::with_capacity(bounds.len() * 2),
+            order: (0..bounds.len() as u32).collect(),
+            refits: 0,
+        };
+        if bounds.is_empty() {
+            return bvh;
+        }
+
+        let centroids: Vec<Vec3> = bounds.iter().map(Aabb::centroid).collect();
+        bvh.nodes.push(BvhNode {
+            left_first: 0,
+            count: bounds.len() as u32,
+            ..default()
+        });
+        bvh.subdivide(0, max_depth, bounds, &centroids);
+        bvh
+    }
+
+    fn subdivide(
+        &mut self,
+        node_index: usize,
+        levels_left: usize,
+        bounds: &[Aabb],
+        centroids: &[Vec3],
+    ) {
+        let first = self.nodes[node_index].left_first as usize;
+        let count = self.nodes[node_index].count as usize;
+        let range = first..first + count;
+
+        let mut node_bounds = Aabb::EMPTY;
+        let mut centroid_bounds = Aabb::EMPTY;
+        for &i in &self.order[range.clone()] {
+            node_bounds = node_bounds.union(&bounds[i as usize]);
+            centroid_bounds.grow(centroids[i as usize]);
+        }
+        self.nodes[node_index].set_bounds(node_bounds);
+
+        if count <= MAX_LEAF_SIZE || levels_left == 0 {
+            return;
+        }
+        let Some((axis, split, cost)) =
+            self.find_split(range.clone(), bounds, centroids, &centroid_bounds)
+        else {
+            return;
+        };
+        if cost >= count as f32 * node_bounds.area() {
+            return;
+        }
+
+        // partition the range so everything left of the split comes first
+        let extent = centroid_bounds.max[axis] - centroid_bounds.min[axis];
+        let bin_of = |i: u32| {
+            let offset = (centroids[i as usize][axis] - centroid_bounds.min[axis]) / extent;
+            ((offset * SAH_BINS as f32) as usize).min(SAH_BINS - 1)
+        };
+        let mut left_count = 0;
+        for i in range.clone() {
+            if bin_of(self.order[i]) < split {
+                self.order.swap(i, first + left_count);
+                left_count += 1;
+            }
+        }
+        if left_count == 0 || left_count == count {
+            return;
+        }
+
+        let left = self.nodes.len();
+        self.nodes.push(BvhNode {
+            left_first: first as u32,
+            count: left_count as u32,
+            ..default()
+        });
+        self.nodes.push(BvhNode {
+            left_first: (first + left_count) as u32,
+            count: (count - left_count) as u32,
+            ..default()
+        });
+        self.nodes[node_index].left_first = left as u32;
+        self.nodes[node_index].count = 0;
+
+        self.subdivide(left, levels_left - 1, bounds, centroids);
+        self.subdivide(left + 1, levels_left - 1, bounds, centroids);
+    }
+
+    // the axis and bin to split at with the lowest estimated cost
+    fn find_split(
+        &self,
+        range: std::ops::Range<usize>,
+        bounds: &[Aabb],
+        centroids: &[Vec3],
+        centroid_bounds: &Aabb,
+    ) -> Option<(usize, usize, f32)> {
+        let mut best: Option<(usize, usize, f32)> = None;
+
+        for axis in 0..3 {
+            let extent = centroid_bounds.max[axis] - centroid_bounds.min[axis];
+            if extent <= 0. {
+                continue;
+            }
+
+            let mut bins = [(Aabb::EMPTY, 0usize); SAH_BINS];
+            for &i in &self.order[range.clone()] {
+                let offset = (centroids[i as usize][axis] - centroid_bounds.min[axis]) / extent;
+                let bin = ((offset * SAH_BINS as f32) as usize).min(SAH_BINS - 1);
+                bins[bin].0 = bins[bin].0.union(&bounds[i as usize]);
+                bins[bin].1 += 1;
+            }
+
+            // sweep from both ends so every split position is evaluated in linear time
+            let mut left_costs = [0.; SAH_BINS];
+            let mut left_bounds = Aabb::EMPTY;
+            let mut left_count = 0;
+            for split in 1..SAH_BINS {
+                left_bounds = left_bounds.union(&bins[split - 1].0);
+                left_count += bins[split - 1].1;
+                left_costs[split] = left_count as f32 * left_bounds.area();
+            }
+
+            let mut right_bounds = Aabb::EMPTY;
+            let mut right_count = 0;
+            for split in (1..SAH_BINS).rev() {
+                right_bounds = right_bounds.union(&bins[split].0);
+                right_count += bins[split].1;
+                let cost = left_costs[split] + right_count as f32 * right_bounds.area();
+                if best.map_or(true, |(_, _, best_cost)| cost < best_cost) {
+                    best = Some((axis, split, cost));
+                }
+            }
+        }
+
+        best
+    }
+
+    // the nearest hit found by hit_primitive, which is given the position in order of each
+    // primitive in the leaves the ray reaches
+    pub fn hit(
+        &self,
+        origin: Vec3,
+        direction: Vec3,
+        mut hit_primitive: impl FnMut(usize) -> Option<f32>,
+    ) -> Option<f32> {
+        if self.nodes.is_empty() {
+            return None;
+        }
+        let inv_direction = direction.recip();
+        let mut closest: Option<f32> = None;
+        let mut stack = vec![0];
+        while let Some(node_index) = stack.pop() {
+            let node = self.nodes[node_index];
+            let t_max = closest.unwrap_or(f32::INFINITY);
+            if !node.bounds().hit(origin, inv_direction, t_max) {
+                continue;
+            }
+            let first = node.left_first as usize;
+            if node.count == 0 {
+                stack.extend([first, first + 1]);
+                continue;
+            }
+            for i in first..first + node.count as usize {
+                if let Some(t) = hit_primitive(i).filter(|t| *t < t_max) {
+                    closest = Some(closest.map_or(t, |closest| closest.min(t)));
+                }
+            }
+        }
+        closest
+    }
+
+    // adds this tree to a shared node array, returns where its root ended up
+    pub fn append_to(&self, nodes: &mut Vec<BvhNode>) -> u32 {
+        let root = nodes.len() as u32;
+        nodes.extend(self.nodes.iter().map(|node| {
+            let mut node = *node;
+            if node.count == 0 {
+                node.left_first += root;
+            }
+            node
+        }));
+        root
+    }
+
+    // updates the bounds for moved primitives without changing the tree
+    pub fn refit(&mut self, bounds: &[Aabb]) {
+        // children are always stored after their parent
+        for node_index in (0..self.nodes.len()).rev() {
+            let node = self.nodes[node_index];
+            let first = node.left_first as usize;
+            let new_bounds = if node.count > 0 {
+                self.order[first..first + node.count as usize]
+                    .iter()
+                    .fold(Aabb::EMPTY, |acc, &i| acc.union(&bounds[i as usize]))
+            } else {
+                self.nodes[first]
+                    .bounds()
+                    .union(&self.nodes[first + 1].bounds())
+            };
+            self.nodes[node_index].set_bounds(new_bounds);
+        }
+        self.refits += 1;
+    }
+
+    // refits while the same primitives are moving, rebuilds when they change or have moved a lot
+    pub fn update(&mut self, bounds: &[Aabb]) {
+        if bounds.len() == self.order.len() && self.refits < REFITS_BEFORE_REBUILD {
+            self.refit(bounds);
+        } else {
+            *self = Bvh::build(bounds);
+        }
+    }
+}
+
+// keep the tree in step with the spheres, animation only moves them so a refit is enough
+pub fn update_bvh(spheres: Res<Spheres>, mut bvh: ResMut<Bvh>) {
+    if !spheres.is_changed() {
+        return;
+    }
+
+    let bounds: Vec<Aabb> = spheres.spheres.iter().map(|s| s.bounds()).collect();
+    bvh.update(&bounds);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // boxes scattered with a fixed lcg, so failures reproduce
+    fn scattered(count: usize, seed: u32) -> Vec<Aabb> {
+        let mut state = seed;
+        let mut next = || {
+            state = state.wrapping_mul(1664525).wrapping_add(1013904223);
+            (state >> 8) as f32 / (1 << 24) as f32
+        };
+        (0..count)
+            .map(|_| {
+                let center = Vec3::new(next(), next(), next()) * 100. - 50.;
+                let half = Vec3::new(next(), next(), next()) * 2. + 0.01;
+                Aabb {
+                    min: center - half,
+                    max: center + half,
+                }
+            })
+            .collect()
+    }
+
+    fn contains(outer: &Aabb, inner: &Aabb) -> bool {
+        outer.min.cmple(inner.min).all() && outer.max.cmpge(inner.max).all()
+    }
+
+    // every primitive is in order once, every leaf range is covered once, and every node's bounds
+    // hold its children or its primitives
+    fn check_to_depth(bvh: &Bvh, bounds: &[Aabb], max_depth: usize) {
+        let mut sorted = bvh.order.clone();
+        sorted.sort();
+        assert_eq!(sorted, (0..bounds.len() as u32).collect::<Vec<_>>());
+        if bounds.is_empty() {
+            assert!(bvh.nodes.is_empty());
+            return;
+        }
+
+        let mut covered = vec![0; bounds.len()];
+        let mut stack = vec![(0, 0)];
+        while let Some((node_index, depth)) = stack.pop() {
+            assert!(depth <= max_depth);
+            let node = bvh.nodes[node_index];
+            let first = node.left_first as usize;
+            if node.count > 0 {
+                for &i in &bvh.order[first..first + node.count as usize] {
+                    assert!(contains(&node.bounds(), &bounds[i as usize]));
+                    covered[i as usize] += 1;
+                }
+            } else {
+                // children come after their parent, which refit relies on
+                assert!(first > node_index);
+                for child in [first, first + 1] {
+                    assert!(contains(&node.bounds(), &bvh.nodes[child].bounds()));
+                    stack.push((child, depth + 1));
+                }
+            }
+        }
+        assert!(covered.iter().all(|&count| count == 1));
+    }
+
+    fn check(bvh: &Bvh, bounds: &[Aabb]) {
+        check_to_depth(bvh, bounds, MAX_DEPTH);
+    }
+
+    #[test]
+    fn build_covers_every_primitive_once() {
+        for count in [0, 1, 3, MAX_LEAF_SIZE + 1, 100, 1000] {
+            let bounds = scattered(count, count as u32);
+            check(&Bvh::build(&bounds), &bounds);
+        }
+    }
+
+    #[test]
+    fn deep_trees_are_capped() {
+        let bounds = scattered(1000, 4);
+        for max_depth in [0, 1, 4] {
+            check_to_depth(&Bvh::build_to_depth(&bounds, max_depth), &bounds, max_depth);
+        }
+    }
+
+    #[test]
+    fn build_handles_identical_boxes() {
+        let bounds = vec![scattered(1, 7)[0]; 50];
+        check(&Bvh::build(&bounds), &bounds);
+    }
+
+    // where a ray enters a box, or where it starts if that is inside
+    fn enter(bounds: &Aabb, origin: Vec3, direction: Vec3) -> Option<f32> {
+        let t0 = (bounds.min - origin) / direction;
+        let t1 = (bounds.max - origin) / direction;
+        let t_near = t0.min(t1).max_element();
+        let t_far = t0.max(t1).min_element();
+        (t_near <= t_far && t_far >= 0.).then_some(t_near.max(0.))
+    }
+
+    #[test]
+    fn hit_finds_the_nearest_primitive() {
+        let bounds = scattered(500, 5);
+        let bvh = Bvh::build(&bounds);
+        let targets = scattered(50, 6);
+        for target in &targets {
+            let origin = Vec3::new(0., 0., 80.);
+            let direction = target.centroid() - origin;
+            let expected = bounds
+                .iter()
+                .filter_map(|b| enter(b, origin, direction))
+                .min_by(|a, b| a.total_cmp(b));
+            let found = bvh.hit(origin, direction, |i| {
+                enter(&bounds[bvh.order[i] as usize], origin, direction)
+            });
+            assert_eq!(found, expected);
+        }
+        assert_eq!(Bvh::default().hit(Vec3::ZERO, Vec3::X, |_| Some(1.)), None);
+    }
+
+    #[test]
+    fn refit_keeps_the_shape_and_grows_the_bounds() {
+        let bounds = scattered(200, 1);
+        let mut bvh = Bvh::build(&bounds);
+        let shape: Vec<_> = bvh.nodes.iter().map(|n| (n.left_first, n.count)).collect();
+
+        let moved: Vec<Aabb> = bounds
+            .iter()
+            .enumerate()
+            .map(|(i, b)| {
+                let offset = Vec3::splat(i as f32 % 7. - 3.);
+                Aabb {
+                    min: b.min + offset,
+                    max: b.max + offset,
+                }
+            })
+            .collect();
+        bvh.refit(&moved);
+
+        let refitted: Vec<_> = bvh.nodes.iter().map(|n| (n.left_first, n.count)).collect();
+        assert_eq!(shape, refitted);
+        check(&bvh, &moved);
+    }
+
+    #[test]
+    fn update_rebuilds_after_enough_refits() {
+        let bounds = scattered(50, 2);
+        let mut bvh = Bvh::build(&bounds);
+        for refit in 1..=REFITS_BEFORE_REBUILD {
+            bvh.update(&bounds);
+            assert_eq!(bvh.refits, refit);
+        }
+        bvh.update(&bounds);
+        assert_eq!(bvh.refits, 0);
+        check(&bvh, &bounds);
+    }
+
+    #[test]
+    fn update_rebuilds_when_the_count_changes() {
+        let mut bvh = Bvh::build(&scattered(50, 3));
+        bvh.update(&scattered(50, 3));
+        assert_eq!(bvh.refits, 1);
+
+        let bounds = scattered(60, 3);
+        bvh.update(&bounds);
+        assert_eq!(bvh.refits, 0);
+        check(&bvh, &bounds);
+    }
+}
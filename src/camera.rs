@@ -1,19 +1,37 @@
-use crate::SIZE;
+use crate::{
+    collidables::{Primitives, Spheres},
+    instance::InstanceBvh,
+    mesh::Triangles,
+    SIZE,
+};
 
 use bevy::{
     core::Zeroable,
+    input::mouse::{MouseMotion, MouseScrollUnit, MouseWheel},
     prelude::*,
     render::{extract_resource::ExtractResource, render_resource::ShaderType},
+    window::PrimaryWindow,
 };
+use bevy_egui::EguiContexts;
 use bytemuck::Pod;
 
 #[derive(
-    ShaderType, Pod, Zeroable, Clone, Copy, Resource, Reflect, ExtractResource, Default, Debug,
+    ShaderType,
+    Pod,
+    Zeroable,
+    Clone,
+    Copy,
+    Resource,
+    Reflect,
+    ExtractResource,
+    Default,
+    Debug,
+    PartialEq,
 )]
 #[repr(C)]
 pub struct Camera {
-    pub camera_center: [f32; 3],
-    _padding1: u32, // https://stackoverflow.com/a/75525055
+    pub camera_center: [f32; 3], // look from
+    _padding1: u32,              // https://stackoverflow.com/a/75525055
     pub viewport_u: [f32; 3],
     _padding2: u32,
     pub viewport_v: [f32; 3],
@@ -26,61 +44,105 @@ pub struct Camera {
     _padding6: u32,
     pub pixel00_loc: [f32; 3],
     _padding7: u32,
+    pub look_at: [f32; 3],
+    pub vfov: f32, // vertical field of view in degrees
+    pub vup: [f32; 3],
+    _padding8: u32,
+    pub defocus_disk_u: [f32; 3],
+    pub defocus_angle: f32, // variation angle of rays through each pixel in degrees, 0 is a pinhole
+    pub defocus_disk_v: [f32; 3],
+    pub focus_dist: f32, // distance from look from to the plane of perfect focus
 }
 
 impl Camera {
     pub fn create_camera() -> Self {
+        Camera::look_at([0., 0., 0.], [0., 0., -1.], [0., 1., 0.], 90.)
+    }
+
+    pub fn look_at(look_from: [f32; 3], look_at: [f32; 3], vup: [f32; 3], vfov: f32) -> Self {
+        let mut camera = Camera {
+            camera_center: look_from,
+            look_at,
+            vup,
+            vfov,
+            focus_dist: 1.,
+            ..default()
+        };
+        camera.update_viewport();
+        camera
+    }
+
+    // recalculates everything the shader needs from look from, look at, vup, the field of view
+    // and the lens settings
+    pub fn update_viewport(&mut self) {
         let aspect_ratio = SIZE.0 as f32 / SIZE.1 as f32;
 
+        let look_from = Vec3::from(self.camera_center);
+        let look_at = Vec3::from(self.look_at);
+        let vup = Vec3::from(self.vup);
+
+        // looking at itself, or straight along vup, has no well defined orientation
+        let Some(w) = (look_from - look_at).try_normalize() else {
+            return;
+        };
+        let Some(u) = vup.cross(w).try_normalize() else {
+            return;
+        };
+        let v = w.cross(u);
+
         // Camera
-        let viewport_height = 2.;
+        let h = (self.vfov.to_radians() / 2.).tan();
+        let viewport_height = 2. * h * self.focus_dist;
         let viewport_width = viewport_height * aspect_ratio;
-        let camera_center = Vec3::splat(0.);
-        let focal_length = 1.0;
 
         // Calculate the vectors across the horizontal and down the vertical viewport edges.
-        let viewport_u = Vec3 {
-            x: viewport_width,
-            y: 0.,
-            z: 0.,
-        };
-        let viewport_v = Vec3 {
-            x: 0.,
-            y: -viewport_height,
-            z: 0.,
-        };
+        let viewport_u = viewport_width * u;
+        let viewport_v = viewport_height * -v;
 
         // Calculate the horizontal and vertical delta vectors from pixel to pixel.
         let pixel_delta_u = viewport_u / SIZE.0 as f32;
         let pixel_delta_v = viewport_v / SIZE.1 as f32;
 
         // Calculate the location of the upper left pixel.
-        let viewport_upper_left = camera_center
-            - Vec3 {
-                x: 0.,
-                y: 0.,
-                z: focal_length,
-            }
-            - viewport_u / 2.
-            - viewport_v / 2.;
+        let viewport_upper_left =
+            look_from - (self.focus_dist * w) - viewport_u / 2. - viewport_v / 2.;
 
         let pixel00_loc = viewport_upper_left + 0.5 * (pixel_delta_u + pixel_delta_v);
 
-        Camera {
-            camera_center: camera_center.into(),
-            _padding1: 0,
-            viewport_u: viewport_u.into(),
-            _padding2: 0,
-            viewport_v: viewport_v.into(),
-            _padding3: 0,
-            pixel_delta_u: pixel_delta_u.into(),
-            _padding4: 0,
-            pixel_delta_v: pixel_delta_v.into(),
-            _padding5: 0,
-            viewport_upper_left: viewport_upper_left.into(),
-            _padding6: 0,
-            pixel00_loc: pixel00_loc.into(),
-            _padding7: 0,
+        self.viewport_u = viewport_u.into();
+        self.viewport_v = viewport_v.into();
+        self.pixel_delta_u = pixel_delta_u.into();
+        self.pixel_delta_v = pixel_delta_v.into();
+        self.viewport_upper_left = viewport_upper_left.into();
+        self.pixel00_loc = pixel00_loc.into();
+
+        // Calculate the camera defocus disk basis vectors.
+        let defocus_radius = self.focus_dist * (self.defocus_angle.to_radians() / 2.).tan();
+        self.defocus_disk_u = (u * defocus_radius).into();
+        self.defocus_disk_v = (v * defocus_radius).into();
+    }
+
+    // ray from the center of the lens through the middle of a pixel
+    pub fn pixel_ray(&self, pixel: Vec2) -> (Vec3, Vec3) {
+        let origin = Vec3::from(self.camera_center);
+        let pixel_center = Vec3::from(self.pixel00_loc)
+            + pixel.x.floor() * Vec3::from(self.pixel_delta_u)
+            + pixel.y.floor() * Vec3::from(self.pixel_delta_v);
+        (origin, pixel_center - origin)
+    }
+
+    // camera space basis, w points backwards from the view direction
+    fn basis(&self) -> Option<(Vec3, Vec3, Vec3)> {
+        let w = (Vec3::from(self.camera_center) - Vec3::from(self.look_at)).try_normalize()?;
+        let u = Vec3::from(self.vup).cross(w).try_normalize()?;
+        Some((u, w.cross(u), w))
+    }
+
+    // move the plane of focus through a point, measured along the view direction
+    pub fn focus_on(&mut self, point: Vec3) {
+        let look_from = Vec3::from(self.camera_center);
+        if let Some(forward) = (Vec3::from(self.look_at) - look_from).try_normalize() {
+            self.focus_dist = (point - look_from).dot(forward).max(0.01);
         }
     }
 
@@ -88,3 +150,193 @@ impl Camera {
         std::mem::size_of::<Camera>() as u64 + 4 // todo: figure out alignment, and why this is needed
     }
 }
+
+pub fn update_camera(mut camera: ResMut<Camera>) {
+    if camera.is_changed() {
+        camera.bypass_change_detection().update_viewport();
+    }
+}
+
+// while active, the next click on the render sets the focus distance to whatever is under the cursor
+#[derive(Resource, Reflect, Default, Debug)]
+pub struct FocusPicker {
+    pub active: bool,
+}
+
+// which pixel of the render sprite is under the cursor, assumes the sprite is unscaled at the origin
+pub fn cursor_pixel(
+    windows: &Query<&Window, With<PrimaryWindow>>,
+    views: &Query<(&bevy::prelude::Camera, &GlobalTransform)>,
+) -> Option<Vec2> {
+    let cursor = windows.get_single().ok()?.cursor_position()?;
+    let (view, transform) = views.get_single().ok()?;
+    let world = view.viewport_to_world_2d(transform, cursor)?;
+
+    let pixel = Vec2::new(world.x + SIZE.0 as f32 / 2., SIZE.1 as f32 / 2. - world.y);
+    let on_sprite =
+        pixel.x >= 0. && pixel.y >= 0. && pixel.x < SIZE.0 as f32 && pixel.y < SIZE.1 as f32;
+    on_sprite.then_some(pixel)
+}
+
+pub fn pick_focus(
+    mut picker: ResMut<FocusPicker>,
+    mut camera: ResMut<Camera>,
+    spheres: Res<Spheres>,
+    primitives: Res<Primitives>,
+    instance_bvh: Res<InstanceBvh>,
+    triangles: Res<Triangles>,
+    buttons: Res<Input<MouseButton>>,
+    windows: Query<&Window, With<PrimaryWindow>>,
+    views: Query<(&bevy::prelude::Camera, &GlobalTransform)>,
+    mut contexts: EguiContexts,
+) {
+    if !picker.active || !buttons.just_pressed(MouseButton::Left) {
+        return;
+    }
+    if contexts.ctx_mut().is_pointer_over_area() {
+        return;
+    }
+    let Some(pixel) = cursor_pixel(&windows, &views) else {
+        return;
+    };
+    picker.active = false;
+
+    let (origin, direction) = camera.pixel_ray(pixel);
+    let hit = [
+        spheres.hit(origin, direction),
+        instance_bvh.hit(&primitives, &triangles, origin, direction),
+    ]
+    .into_iter()
+    .flatten()
+    .min_by(|a, b| a.total_cmp(b));
+    if let Some(t) = hit {
+        camera.focus_on(origin + direction * t);
+    }
+}
+
+#[derive(Resource, Debug)]
+pub struct CameraControls {
+    pub orbit_speed: f32, // radians per pixel dragged
+    pub fly_speed: f32,   // units per second
+    dragging: Option<MouseButton>,
+}
+
+impl Default for CameraControls {
+    fn default() -> Self {
+        CameraControls {
+            orbit_speed: 0.005,
+            fly_speed: 1.,
+            dragging: None,
+        }
+    }
+}
+
+// left drag orbits around look at, right drag pans, scroll dollies and WASD/QE flies
+pub fn camera_controls(
+    mut controls: ResMut<CameraControls>,
+    mut camera: ResMut<Camera>,
+    picker: Res<FocusPicker>,
+    buttons: Res<Input<MouseButton>>,
+    keys: Res<Input<KeyCode>>,
+    time: Res<Time>,
+    mut motion: EventReader<MouseMotion>,
+    mut wheel: EventReader<MouseWheel>,
+    windows: Query<&Window, With<PrimaryWindow>>,
+    views: Query<(&bevy::prelude::Camera, &GlobalTransform)>,
+    mut contexts: EguiContexts,
+) {
+    let ctx = contexts.ctx_mut();
+    let over_render = !ctx.is_pointer_over_area() && cursor_pixel(&windows, &views).is_some();
+
+    // drags have to start on the render, but can carry on over the panels
+    for button in [MouseButton::Left, MouseButton::Right] {
+        let clicking_focus = button == MouseButton::Left && picker.active;
+        if buttons.just_pressed(button) && over_render && !clicking_focus {
+            controls.dragging = Some(button);
+        }
+    }
+    if let Some(button) = controls.dragging {
+        if !buttons.pressed(button) {
+            controls.dragging = None;
+        }
+    }
+
+    let drag: Vec2 = motion.iter().map(|m| m.delta).sum();
+    let scroll: f32 = wheel
+        .iter()
+        .map(|w| match w.unit {
+            MouseScrollUnit::Line => w.y,
+            MouseScrollUnit::Pixel => w.y / 100.,
+        })
+        .sum();
+
+    let mut fly = Vec3::ZERO;
+    if !ctx.wants_keyboard_input() {
+        let axes = [
+            (KeyCode::D, Vec3::X),
+            (KeyCode::A, Vec3::NEG_X),
+            (KeyCode::E, Vec3::Y),
+            (KeyCode::Q, Vec3::NEG_Y),
+            (KeyCode::W, Vec3::NEG_Z),
+            (KeyCode::S, Vec3::Z),
+        ];
+        for (key, axis) in axes {
+            if keys.pressed(key) {
+                fly += axis;
+            }
+        }
+    }
+
+    let orbiting = controls.dragging == Some(MouseButton::Left) && drag != Vec2::ZERO;
+    let panning = controls.dragging == Some(MouseButton::Right) && drag != Vec2::ZERO;
+    let dollying = over_render && scroll != 0.;
+    let flying = fly != Vec3::ZERO;
+
+    // only touch the camera when it actually moves, any change restarts accumulation
+    if !(orbiting || panning || dollying || flying) {
+        return;
+    }
+    let Some((u, v, w)) = camera.basis() else {
+        return;
+    };
+
+    let look_from = Vec3::from(camera.camera_center);
+    let look_at = Vec3::from(camera.look_at);
+    let up = Vec3::from(camera.vup).normalize();
+    let mut offset = look_from - look_at;
+    let mut target = look_at;
+
+    if orbiting {
+        let yaw = Quat::from_axis_angle(up, -drag.x * controls.orbit_speed);
+        let pitch = Quat::from_axis_angle(u, -drag.y * controls.orbit_speed);
+        let pitched = pitch * offset;
+
+        // stop short of the poles, past them the view would flip over
+        if pitched.normalize().dot(up).abs() < 0.99 {
+            offset = pitched;
+        }
+        offset = yaw * offset;
+    }
+
+    if panning {
+        // move the scene with the cursor at the distance of look at
+        let world_per_pixel =
+            2. * offset.length() * (camera.vfov.to_radians() / 2.).tan() / SIZE.1 as f32;
+        target += (-drag.x * u + drag.y * v) * world_per_pixel;
+    }
+
+    if dollying {
+        offset *= (1. - scroll * 0.1).max(0.1);
+        if offset.length() < 0.05 {
+            offset = offset.normalize() * 0.05;
+        }
+    }
+
+    if flying {
+        let distance = controls.fly_speed * time.delta_seconds();
+        target += (fly.x * u + fly.y * v + fly.z * w) * distance;
+    }
+
+    camera.look_at = target.into();
+    camera.camera_center = (target + offset).into();
+}
@@ -1,48 +1,656 @@
-use bevy::render::{extract_resource::ExtractResource, render_resource::Buffer};
+use bevy::render::extract_resource::ExtractResource;
 
 use bevy::{prelude::*, render::render_resource::ShaderType};
 use bytemuck::{Pod, Zeroable};
 
-use crate::render::RenderTime;
+use crate::{
+    bvh::Aabb,
+    objects::{Oscillation, RtMaterial, RtPrimitive, RtSphere},
+    render::RenderTime,
+    storage::SceneArray,
+};
 
-const MAX_SPHERES: usize = 10;
-
-#[derive(Resource, Debug)]
+// the spheres on the gpu, a storage buffer or a data texture depending on the device
+#[derive(Resource)]
 pub struct SphereBuffer {
-    pub buffer: Option<Buffer>,
+    pub buffer: SceneArray<Sphere>,
+}
+
+#[derive(Resource)]
+pub struct PrimitiveBuffer {
+    pub buffer: SceneArray<Primitive>,
+}
+
+// matches the material constants in simple.wgsl
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Material {
+    Lambertian = 0,
+    Metal = 1,
+    Dielectric = 2,
+    Emissive = 3,
 }
 
-#[derive(
-    ShaderType, Pod, Zeroable, Clone, Copy, Resource, Reflect, ExtractResource, Default, Debug,
-)]
+impl Material {
+    pub const ALL: [Material; 4] = [
+        Material::Lambertian,
+        Material::Metal,
+        Material::Dielectric,
+        Material::Emissive,
+    ];
+
+    pub fn from_index(index: i32) -> Self {
+        match index {
+            1 => Material::Metal,
+            2 => Material::Dielectric,
+            3 => Material::Emissive,
+            _ => Material::Lambertian,
+        }
+    }
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            Material::Lambertian => "Lambertian",
+            Material::Metal => "Metal",
+            Material::Dielectric => "Dielectric",
+            Material::Emissive => "Emissive",
+        }
+    }
+}
+
+#[derive(ShaderType, Pod, Zeroable, Clone, Copy, Reflect, Default, Debug, PartialEq)]
 #[repr(C)]
+pub struct Sphere {
+    pub center: [f32; 3],
+    pub radius: f32, // a negative radius flips the normals, which makes a hollow dielectric
+
+    pub color: [f32; 4],
+    pub material: i32,
+    pub fuzz: f32, // how far metal reflections are scattered, 0 is a perfect mirror
+    pub ior: f32,  // index of refraction for dielectrics
+    pub intensity: f32, // how strongly an emissive sphere glows in its color
+
+    pub emitter: u32, // set by light::collect_emitters
+    _padding: [u32; 3],
+}
+
+impl Sphere {
+    pub fn new(center: [f32; 3], radius: f32, color: [f32; 3], material: Material) -> Self {
+        Sphere {
+            center,
+            radius,
+            color: [color[0], color[1], color[2], 1.0],
+            material: material as i32,
+            fuzz: 0.,
+            ior: 1.5,
+            intensity: 1.,
+            emitter: 0,
+            _padding: [0; 3],
+        }
+    }
+
+    // the gpu form of a sphere entity
+    pub fn from_parts(sphere: &RtSphere, material: &RtMaterial, transform: &Transform) -> Self {
+        Sphere {
+            center: transform.translation.to_array(),
+            radius: sphere.radius,
+            color: material.color,
+            material: material.material,
+            fuzz: material.fuzz,
+            ior: material.ior,
+            intensity: material.intensity,
+            emitter: 0,
+            _padding: [0; 3],
+        }
+    }
+
+    pub fn with_fuzz(mut self, fuzz: f32) -> Self {
+        self.fuzz = fuzz;
+        self
+    }
+
+    pub fn with_ior(mut self, ior: f32) -> Self {
+        self.ior = ior;
+        self
+    }
+
+    pub fn with_intensity(mut self, intensity: f32) -> Self {
+        self.intensity = intensity;
+        self
+    }
+
+    pub fn bounds(&self) -> Aabb {
+        let radius = Vec3::splat(self.radius.abs());
+        Aabb {
+            min: Vec3::from(self.center) - radius,
+            max: Vec3::from(self.center) + radius,
+        }
+    }
+
+    // same as hit_sphere in simple.wgsl, returns the ray parameter of the nearest hit in front
+    pub fn hit(&self, origin: Vec3, direction: Vec3) -> Option<f32> {
+        let origin_to_center = origin - Vec3::from(self.center);
+        let a = direction.dot(direction);
+        let half_b = origin_to_center.dot(direction);
+        let c = origin_to_center.dot(origin_to_center) - self.radius * self.radius;
+
+        let discriminant = half_b * half_b - a * c;
+        if discriminant < 0. {
+            return None;
+        }
+
+        let sqrt_discriminant = discriminant.sqrt();
+        [
+            (-half_b - sqrt_discriminant) / a,
+            (-half_b + sqrt_discriminant) / a,
+        ]
+        .into_iter()
+        .find(|t| *t > 0.001)
+    }
+}
+
+#[derive(Clone, Resource, Reflect, ExtractResource, Default, Debug, PartialEq)]
 pub struct Spheres {
-    pub spheres: [[f32; 4]; MAX_SPHERES],
+    pub spheres: Vec<Sphere>,
 }
 
 impl Spheres {
     pub fn default_scene() -> Self {
-        let mut spheres = Spheres::default();
-        spheres.spheres[0] = [-0.5, 0., -1., 0.5];
-        spheres.spheres[1] = [0.7, 0.1, 0.1, 1.0];
+        Spheres {
+            spheres: vec![
+                Sphere::new([-0.5, 0., -1.], 0.5, [0.7, 0.1, 0.1], Material::Lambertian),
+                Sphere::new([0.5, 0., -1.], 0.25, [0.8, 0.8, 0.8], Material::Metal).with_fuzz(0.2),
+                Sphere::new([0.5, 0., -1.], 0.25, [0.1, 0.1, 0.7], Material::Lambertian),
+            ],
+        }
+    }
+
+    pub fn hit(&self, origin: Vec3, direction: Vec3) -> Option<f32> {
+        self.spheres
+            .iter()
+            .filter_map(|sphere| sphere.hit(origin, direction))
+            .min_by(|a, b| a.total_cmp(b))
+    }
+}
+
+// matches the shape constants in simple.wgsl
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Shape {
+    Plane = 0,
+    Quad = 1,
+    Box = 2,
+    Disk = 3,
+    Cylinder = 4,
+    Sphere = 5,
+}
+
+impl Shape {
+    pub const ALL: [Shape; 6] = [
+        Shape::Plane,
+        Shape::Quad,
+        Shape::Box,
+        Shape::Disk,
+        Shape::Cylinder,
+        Shape::Sphere,
+    ];
+
+    pub fn from_index(index: i32) -> Self {
+        match index {
+            1 => Shape::Quad,
+            2 => Shape::Box,
+            3 => Shape::Disk,
+            4 => Shape::Cylinder,
+            5 => Shape::Sphere,
+            _ => Shape::Plane,
+        }
+    }
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            Shape::Plane => "Plane",
+            Shape::Quad => "Quad",
+            Shape::Box => "Box",
+            Shape::Disk => "Disk",
+            Shape::Cylinder => "Cylinder",
+            Shape::Sphere => "Sphere",
+        }
+    }
+}
+
+// every shape that isn't a mesh, what origin, u, v and radius mean depends on the shape. unlike
+// Spheres these can be placed with instances, which is how a sphere gets stretched or rotated
+#[derive(ShaderType, Pod, Zeroable, Clone, Copy, Reflect, Default, Debug, PartialEq)]
+#[repr(C)]
+pub struct Primitive {
+    pub origin: [f32; 3], // plane point, quad corner, box min, cylinder base or disk and sphere center
+    pub shape: i32,
+    pub u: [f32; 3], // plane and disk normal, first quad edge, box max or cylinder axis
+    pub radius: f32, // disks, cylinders and spheres
+    pub v: [f32; 3], // second quad edge
+    pub material: i32,
+
+    pub color: [f32; 4],
+    pub fuzz: f32,
+    pub ior: f32,
+    pub intensity: f32,
+    _padding: u32,
+}
+
+impl Primitive {
+    fn new(
+        shape: Shape,
+        origin: [f32; 3],
+        u: [f32; 3],
+        color: [f32; 3],
+        material: Material,
+    ) -> Self {
+        Primitive {
+            origin,
+            shape: shape as i32,
+            u,
+            color: [color[0], color[1], color[2], 1.0],
+            material: material as i32,
+            ior: 1.5,
+            intensity: 1.,
+            ..default()
+        }
+    }
+
+    pub fn plane(point: [f32; 3], normal: [f32; 3], color: [f32; 3], material: Material) -> Self {
+        Primitive::new(Shape::Plane, point, normal, color, material)
+    }
+
+    // a parallelogram spanned by two edges from one corner
+    pub fn quad(
+        corner: [f32; 3],
+        u: [f32; 3],
+        v: [f32; 3],
+        color: [f32; 3],
+        material: Material,
+    ) -> Self {
+        Primitive {
+            v,
+            ..Primitive::new(Shape::Quad, corner, u, color, material)
+        }
+    }
+
+    pub fn cuboid(min: [f32; 3], max: [f32; 3], color: [f32; 3], material: Material) -> Self {
+        Primitive::new(Shape::Box, min, max, color, material)
+    }
+
+    pub fn disk(
+        center: [f32; 3],
+        normal: [f32; 3],
+        radius: f32,
+        color: [f32; 3],
+        material: Material,
+    ) -> Self {
+        Primitive {
+            radius,
+            ..Primitive::new(Shape::Disk, center, normal, color, material)
+        }
+    }
+
+    // capped at both ends, the axis runs from the center of the base to the center of the top
+    pub fn cylinder(
+        base: [f32; 3],
+        axis: [f32; 3],
+        radius: f32,
+        color: [f32; 3],
+        material: Material,
+    ) -> Self {
+        Primitive {
+            radius,
+            ..Primitive::new(Shape::Cylinder, base, axis, color, material)
+        }
+    }
+
+    pub fn sphere(center: [f32; 3], radius: f32, color: [f32; 3], material: Material) -> Self {
+        Primitive {
+            radius,
+            ..Primitive::new(Shape::Sphere, center, [0.; 3], color, material)
+        }
+    }
+
+    // the gpu form of a primitive entity
+    pub fn from_parts(primitive: &RtPrimitive, material: &RtMaterial) -> Self {
+        Primitive {
+            origin: primitive.origin,
+            shape: primitive.shape as i32,
+            u: primitive.u,
+            radius: primitive.radius,
+            v: primitive.v,
+            material: material.material,
+            color: material.color,
+            fuzz: material.fuzz,
+            ior: material.ior,
+            intensity: material.intensity,
+            _padding: 0,
+        }
+    }
+
+    pub fn with_fuzz(mut self, fuzz: f32) -> Self {
+        self.fuzz = fuzz;
+        self
+    }
+
+    pub fn with_ior(mut self, ior: f32) -> Self {
+        self.ior = ior;
+        self
+    }
+
+    pub fn with_intensity(mut self, intensity: f32) -> Self {
+        self.intensity = intensity;
+        self
+    }
+
+    pub fn kind(&self) -> Shape {
+        Shape::from_index(self.shape)
+    }
+
+    // planes go on forever, so they are the only shape without bounds
+    pub fn bounds(&self) -> Option<Aabb> {
+        let origin = Vec3::from(self.origin);
+        let u = Vec3::from(self.u);
+        let v = Vec3::from(self.v);
+
+        // flat shapes get a little thickness so the box is never empty along an axis
+        let pad = Vec3::splat(1e-4);
+        let bounds = match self.kind() {
+            Shape::Plane => return None,
+            Shape::Quad => {
+                let mut bounds = Aabb::EMPTY;
+                for corner in [origin, origin + u, origin + v, origin + u + v] {
+                    bounds.grow(corner);
+                }
+                bounds
+            }
+            Shape::Box => Aabb {
+                min: origin.min(u),
+                max: origin.max(u),
+            },
+            Shape::Disk => disk_bounds(origin, u, self.radius),
+            Shape::Cylinder => {
+                disk_bounds(origin, u, self.radius).union(&disk_bounds(origin + u, u, self.radius))
+            }
+            Shape::Sphere => Aabb {
+                min: origin - self.radius.abs(),
+                max: origin + self.radius.abs(),
+            },
+        };
+        Some(Aabb {
+            min: bounds.min - pad,
+            max: bounds.max + pad,
+        })
+    }
+
+    // same as hit_primitive in simple.wgsl, returns the ray parameter of the nearest hit in front
+    pub fn hit(&self, origin: Vec3, direction: Vec3) -> Option<f32> {
+        let corner = Vec3::from(self.origin);
+        let u = Vec3::from(self.u);
+        let v = Vec3::from(self.v);
+
+        let hits = match self.kind() {
+            Shape::Plane => vec![hit_plane(corner, u, origin, direction)],
+            Shape::Quad => {
+                let normal = u.cross(v);
+                let t = hit_plane(corner, normal, origin, direction);
+                let inside = t.is_some_and(|t| {
+                    let p = origin + direction * t - corner;
+                    let w = normal / normal.dot(normal);
+                    let alpha = w.dot(p.cross(v));
+                    let beta = w.dot(u.cross(p));
+                    (0. ..=1.).contains(&alpha) && (0. ..=1.).contains(&beta)
+                });
+                vec![t.filter(|_| inside)]
+            }
+            Shape::Box => {
+                let inv_direction = direction.recip();
+                let t0 = (corner.min(u) - origin) * inv_direction;
+                let t1 = (corner.max(u) - origin) * inv_direction;
+                let t_near = t0.min(t1).max_element();
+                let t_far = t0.max(t1).min_element();
+                if t_near > t_far {
+                    return None;
+                }
+                vec![Some(t_near), Some(t_far)]
+            }
+            Shape::Disk => vec![hit_disk(corner, u, self.radius, origin, direction)],
+            Shape::Cylinder => {
+                let axis = u.normalize_or_zero();
+                let height = u.length();
+                let mut hits = vec![
+                    hit_disk(corner, u, self.radius, origin, direction),
+                    hit_disk(corner + u, u, self.radius, origin, direction),
+                ];
+
+                // the infinite cylinder around the axis, cut off at the caps
+                let offset = origin - corner;
+                let d = direction - axis * direction.dot(axis);
+                let o = offset - axis * offset.dot(axis);
+                let a = d.dot(d);
+                let half_b = o.dot(d);
+                let c = o.dot(o) - self.radius * self.radius;
+                let discriminant = half_b * half_b - a * c;
+                if a > 0. && discriminant >= 0. {
+                    let sqrt_discriminant = discriminant.sqrt();
+                    for t in [
+                        (-half_b - sqrt_discriminant) / a,
+                        (-half_b + sqrt_discriminant) / a,
+                    ] {
+                        let y = (offset + direction * t).dot(axis);
+                        if (0. ..=height).contains(&y) {
+                            hits.push(Some(t));
+                        }
+                    }
+                }
+                hits
+            }
+            Shape::Sphere => {
+                let sphere = Sphere::new(self.origin, self.radius, [0.; 3], Material::Lambertian);
+                vec![sphere.hit(origin, direction)]
+            }
+        };
+
+        hits.into_iter()
+            .flatten()
+            .filter(|t| *t > 0.001)
+            .min_by(|a, b| a.total_cmp(b))
+    }
+}
+
+fn hit_plane(point: Vec3, normal: Vec3, origin: Vec3, direction: Vec3) -> Option<f32> {
+    let denominator = normal.dot(direction);
+    if denominator.abs() < 1e-8 {
+        return None;
+    }
+    Some(normal.dot(point - origin) / denominator)
+}
+
+fn hit_disk(center: Vec3, normal: Vec3, radius: f32, origin: Vec3, direction: Vec3) -> Option<f32> {
+    hit_plane(center, normal, origin, direction)
+        .filter(|t| (origin + direction * *t).distance_squared(center) <= radius * radius)
+}
+
+// how far a disk reaches along each axis depends on how much its normal points away from it
+fn disk_bounds(center: Vec3, normal: Vec3, radius: f32) -> Aabb {
+    let normal = normal.normalize_or_zero();
+    let extent = (Vec3::ONE - normal * normal).max(Vec3::ZERO).powf(0.5) * radius;
+    Aabb {
+        min: center - extent,
+        max: center + extent,
+    }
+}
+
+#[derive(Clone, Resource, Reflect, ExtractResource, Default, Debug, PartialEq)]
+pub struct Primitives {
+    pub primitives: Vec<Primitive>,
+}
+
+impl Primitives {
+    pub fn default_scene() -> Self {
+        Primitives {
+            primitives: vec![Primitive::plane(
+                [0., -0.5, 0.],
+                [0., 1., 0.],
+                [0.5, 0.5, 0.5],
+                Material::Lambertian,
+            )],
+        }
+    }
+}
 
-        spheres.spheres[2] = [0.5, 0., -1., 0.25];
-        spheres.spheres[3] = [0.1, 0.7, 0.1, 1.0];
+#[derive(Resource, Reflect, Debug, PartialEq)]
+pub struct SphereAnimation {
+    pub enabled: bool,
+}
 
-        spheres.spheres[4] = [0.5, 0., -1., 0.25];
-        spheres.spheres[5] = [0.1, 0.1, 0.7, 1.0];
+impl Default for SphereAnimation {
+    fn default() -> Self {
+        SphereAnimation { enabled: true }
+    }
+}
 
-        spheres.spheres[6] = [0., -100.5, -1., 100.];
-        spheres.spheres[7] = [0.5, 0.5, 0.5, 1.0];
+pub fn update_spheres(
+    mut query: Query<(&Oscillation, &mut Transform)>,
+    time: Res<RenderTime>,
+    animation: Res<SphereAnimation>,
+) {
+    // a still scene lets the accumulation buffer converge
+    if !animation.enabled {
+        return;
+    }
 
-        spheres
+    for (oscillation, mut transform) in &mut query {
+        transform.translation[oscillation.axis] = (time.time + oscillation.phase).sin();
     }
 }
 
-pub fn update_spheres(spheres: ResMut<Spheres>, time: Res<RenderTime>) {
-    let elapsed = time.time;
-    let inner = spheres.into_inner();
-    inner.spheres[0][0] = elapsed.sin();
-    inner.spheres[2][0] = elapsed.cos();
-    inner.spheres[4][1] = elapsed.cos();
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn shapes() -> [Primitive; 6] {
+        let color = [1.; 3];
+        [
+            Primitive::plane([0., 0., -1.], [0., 0., 1.], color, Material::Lambertian),
+            Primitive::quad(
+                [-1., -1., -1.],
+                [2., 0., 0.],
+                [0., 2., 0.],
+                color,
+                Material::Lambertian,
+            ),
+            Primitive::cuboid([-1.; 3], [1.; 3], color, Material::Lambertian),
+            Primitive::disk([0., 0., -1.], [0., 0., 1.], 1., color, Material::Lambertian),
+            Primitive::cylinder([0., 0., -1.], [0., 0., 2.], 1., color, Material::Lambertian),
+            Primitive::sphere([0.; 3], 1., color, Material::Lambertian),
+        ]
+    }
+
+    fn contains(bounds: &Aabb, point: Vec3) -> bool {
+        bounds.min.cmple(point).all() && bounds.max.cmpge(point).all()
+    }
+
+    // points on the edges and rims of each shape, the parts furthest from its center
+    fn outline(primitive: &Primitive) -> Vec<Vec3> {
+        let origin = Vec3::from(primitive.origin);
+        let u = Vec3::from(primitive.u);
+        let v = Vec3::from(primitive.v);
+        let (axis_a, axis_b) = u.normalize_or_zero().any_orthonormal_pair();
+        let rim = |center: Vec3| {
+            (0..64).map(move |i| {
+                let phi = i as f32 / 64. * std::f32::consts::TAU;
+                center + (axis_a * phi.cos() + axis_b * phi.sin()) * primitive.radius
+            })
+        };
+        match primitive.kind() {
+            Shape::Plane => vec![],
+            Shape::Quad => vec![origin, origin + u, origin + v, origin + u + v],
+            Shape::Box => vec![origin, u],
+            Shape::Disk => rim(origin).collect(),
+            Shape::Cylinder => rim(origin).chain(rim(origin + u)).collect(),
+            Shape::Sphere => [Vec3::X, Vec3::Y, Vec3::Z]
+                .into_iter()
+                .flat_map(|axis| [1., -1.].map(|sign| origin + axis * sign * primitive.radius))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn each_shape_is_hit_where_it_is() {
+        // straight down -z from z = 5, the plane, quad and disk sit at z = -1, the rest reach z = 1
+        let expected = [6., 6., 4., 6., 4., 4.];
+        for (primitive, t) in shapes().iter().zip(expected) {
+            let hit = primitive.hit(Vec3::new(0., 0., 5.), Vec3::NEG_Z);
+            assert_eq!(hit, Some(t), "{}", primitive.kind().label());
+        }
+    }
+
+    #[test]
+    fn rays_beside_bounded_shapes_miss() {
+        for primitive in &shapes()[1..] {
+            let hit = primitive.hit(Vec3::new(2., 0., 5.), Vec3::NEG_Z);
+            assert_eq!(hit, None, "{}", primitive.kind().label());
+        }
+        // anything that isn't parallel hits a plane
+        assert_eq!(
+            shapes()[0].hit(Vec3::new(2., 0., 5.), Vec3::NEG_Z),
+            Some(6.)
+        );
+        assert_eq!(shapes()[0].hit(Vec3::new(0., 0., 5.), Vec3::X), None);
+    }
+
+    #[test]
+    fn cylinders_are_hit_on_the_side_and_cut_off_at_the_caps() {
+        let cylinder = &shapes()[4];
+        assert_eq!(cylinder.hit(Vec3::new(5., 0., 0.), Vec3::NEG_X), Some(4.));
+        assert_eq!(cylinder.hit(Vec3::new(5., 0., 1.5), Vec3::NEG_X), None);
+    }
+
+    #[test]
+    fn rays_from_inside_hit_the_far_side() {
+        let cuboid = &shapes()[2];
+        assert_eq!(cuboid.hit(Vec3::ZERO, Vec3::X), Some(1.));
+        let sphere = &shapes()[5];
+        assert_eq!(sphere.hit(Vec3::ZERO, Vec3::Y), Some(1.));
+    }
+
+    #[test]
+    fn bounds_contain_every_shape_but_planes() {
+        for primitive in shapes() {
+            let Some(bounds) = primitive.bounds() else {
+                assert_eq!(primitive.kind(), Shape::Plane);
+                continue;
+            };
+            for point in outline(&primitive) {
+                assert!(
+                    contains(&bounds, point),
+                    "{} {:?} outside {:?}",
+                    primitive.kind().label(),
+                    point,
+                    bounds
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn flat_shapes_get_some_thickness() {
+        let quad = shapes()[1].bounds().unwrap();
+        assert!(quad.max.z > quad.min.z);
+        let disk = shapes()[3].bounds().unwrap();
+        assert!(disk.max.z > disk.min.z);
+    }
+
+    #[test]
+    fn tilted_disks_only_reach_as_far_as_their_rim() {
+        let bounds = disk_bounds(Vec3::ZERO, Vec3::new(1., 1., 0.), 1.);
+        let half = std::f32::consts::FRAC_1_SQRT_2;
+        assert!(bounds.max.abs_diff_eq(Vec3::new(half, half, 1.), 1e-6));
+        assert!(bounds.min.abs_diff_eq(-Vec3::new(half, half, 1.), 1e-6));
+
+        let flat = disk_bounds(Vec3::new(0., 2., 0.), Vec3::Y, 3.);
+        assert_eq!(flat.min, Vec3::new(-3., 2., -3.));
+        assert_eq!(flat.max, Vec3::new(3., 2., 3.));
+    }
 }
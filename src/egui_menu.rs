@@ -1,9 +1,18 @@
 use std::any::{self};
 
 use crate::{
-    camera::Camera,
-    collidables::Spheres,
-    render::{Params, RenderTime},
+    camera::{Camera, CameraControls, FocusPicker},
+    collidables::{Material, Primitive, Shape, Sphere, SphereAnimation},
+    environment::Environment,
+    export::{can_save, SaveImage},
+    light::{Light, LightKind},
+    mesh::{Meshes, Triangles},
+    objects::{
+        light_bundle, mesh_bundle, primitive_bundle, sphere_bundle, RtLight, RtMaterial, RtMesh,
+        RtPrimitive, RtSphere,
+    },
+    render::{OneShot, Params, Progress, RenderTime, SamplePattern, Sky, PATH_TRACED},
+    scene::{CurrentScene, OpenScene, SaveScene},
     AppState,
 };
 use bevy::{prelude::*, reflect::TypeInfo};
@@ -24,14 +33,49 @@ fn ui_system(
     mut contexts: EguiContexts,
     mut next_state: ResMut<NextState<AppState>>,
     state: Res<State<AppState>>,
-    mut camera: ResMut<Camera>,
-    time: Res<RenderTime>,
-    mut params: ResMut<Params>,
-    mut spheres: ResMut<Spheres>,
+    mut camera_ref: ResMut<Camera>,
+    (time, progress): (Res<RenderTime>, Res<Progress>),
+    mut params_ref: ResMut<Params>,
+    mut commands: Commands,
+    (mut sphere_query, mut primitive_query, mut mesh_query, mut light_query): (
+        Query<(Entity, &mut RtSphere, &mut RtMaterial, &mut Transform)>,
+        Query<(Entity, &mut RtPrimitive, &mut RtMaterial, &mut Transform), Without<RtSphere>>,
+        Query<(Entity, &RtMesh, &mut Transform), (Without<RtSphere>, Without<RtPrimitive>)>,
+        Query<
+            (Entity, &mut RtLight, &mut Transform),
+            (Without<RtSphere>, Without<RtPrimitive>, Without<RtMesh>),
+        >,
+    ),
+    mut animation: ResMut<SphereAnimation>,
+    mut one_shot_ref: ResMut<OneShot>,
+    mut save_image: EventWriter<SaveImage>,
+    // systems take at most 16 parameters
+    (mut current_scene, mut open_scene, mut save_scene): (
+        ResMut<CurrentScene>,
+        EventWriter<OpenScene>,
+        EventWriter<SaveScene>,
+    ),
+    (mut meshes, triangles, mut mesh_path, asset_server, mut environment, mut environment_path): (
+        ResMut<Meshes>,
+        Res<Triangles>,
+        Local<String>,
+        Res<AssetServer>,
+        ResMut<Environment>,
+        Local<String>,
+    ),
+    mut focus_picker: ResMut<FocusPicker>,
+    mut controls: ResMut<CameraControls>,
     type_registry: Res<AppTypeRegistry>,
 ) {
     let ctx = contexts.ctx_mut();
 
+    // widgets take a &mut every frame, so edit copies and only write back real changes,
+    // otherwise the accumulated samples would be thrown away every frame
+    let mut camera = *camera_ref;
+    let mut params = *params_ref;
+    let mut animate = animation.enabled;
+    let mut one_shot = *one_shot_ref;
+
     // let ui_enabled = match state.get() {
     //     AppState::Waiting => true,
     //     AppState::Running => false,
@@ -76,6 +120,22 @@ fn ui_system(
                         AppState::Reset => {}
                     }
                 }
+
+                // there's no file system to save to in the browser
+                #[cfg(not(target_arch = "wasm32"))]
+                {
+                    let save_button =
+                        Button::new("Save image").min_size(bevy_egui::egui::Vec2::new(100., 30.));
+                    if ui
+                        .add_enabled(can_save(&one_shot, state.get()), save_button)
+                        .on_disabled_hover_text(
+                            "one-shot renders can be saved once every tile is done",
+                        )
+                        .clicked()
+                    {
+                        save_image.send_default();
+                    }
+                }
             });
 
             // ui.set_enabled(ui_enabled);
@@ -84,9 +144,11 @@ fn ui_system(
 
             ui.heading("Rendering Controls");
 
+            // a one shot render only visits each tile once, so allow a lot more samples
+            let max_samples = if one_shot.enabled { 2000 } else { 200 };
             ui.horizontal(|ui| {
                 ui.label("sample count");
-                ui.add(egui::Slider::new(&mut params.samples, 1..=200).show_value(false));
+                ui.add(egui::Slider::new(&mut params.samples, 1..=max_samples).show_value(false));
                 ui.label(format!("{}", params.samples));
             });
 
@@ -96,86 +158,434 @@ fn ui_system(
                 ui.label(format!("{}", params.depth));
             });
 
+            // the same seed renders the same image, a new one gives fresh noise
+            ui.horizontal(|ui| {
+                ui.label("seed");
+                ui.add(egui::DragValue::new(&mut params.seed));
+                if ui.small_button("New").clicked() {
+                    params.seed = params.seed.wrapping_add(1);
+                }
+            });
+
+            ui.horizontal(|ui| {
+                ui.label("sample pattern");
+                egui::ComboBox::from_id_source("sample pattern")
+                    .selected_text(SamplePattern::from_index(params.sample_pattern).label())
+                    .show_ui(ui, |ui| {
+                        for option in SamplePattern::ALL {
+                            ui.selectable_value(
+                                &mut params.sample_pattern,
+                                option as i32,
+                                option.label(),
+                            );
+                        }
+                    });
+            });
+
             ui.horizontal(|ui| {
                 ui.label("Render Mode");
-                ui.add(egui::Slider::new(&mut params.render_mode, 0..=3).show_value(false));
+                ui.add(
+                    egui::Slider::new(&mut params.render_mode, 0..=PATH_TRACED).show_value(false),
+                );
 
                 ui.label(match params.render_mode {
                     0 => "Normals",
                     1 => "Average",
                     2 => "Blended",
                     3 => "Last Hit",
+                    PATH_TRACED => "Path Traced",
                     _ => "default",
                 });
             });
 
-            // todo: toggle between one shot and continuous
-
-            // ui.horizontal(|ui| {
-            //     ui.label("step size");
-            //     ui.add_enabled(
-            //         params.x < 0, // todo: refactor this to be more clear of intent
-            //         egui::Slider::from_get_set(6.0..=9.0, |v: Option<f64>| {
-            //             if let Some(v) = v {
-            //                 params.size = v.exp2() as i32;
-            //                 params.x = -params.size;
-            //             }
-            //             (params.size as f64).log2()
-            //         })
-            //         .integer()
-            //         .show_value(false),
-            //     );
-            //     ui.label(format!("{}", params.size));
-            // });
-
-            ui.allocate_space(egui::Vec2::new(1.0, 20.0));
-
-            ui.heading("Spheres");
+            ui.horizontal(|ui| {
+                ui.label("sky");
+                egui::ComboBox::from_id_source("sky")
+                    .selected_text(Sky::from_index(params.sky).label())
+                    .show_ui(ui, |ui| {
+                        for option in Sky::ALL {
+                            ui.selectable_value(&mut params.sky, option as i32, option.label());
+                        }
+                    });
+            });
 
-            egui::ScrollArea::vertical().show(ui, |ui| {
+            if params.sky == Sky::Environment as i32 {
                 ui.horizontal(|ui| {
-                    ui.label("count");
+                    ui.label("assets/");
                     ui.add(
-                        egui::Slider::from_get_set(1.0..=4.0, |v| {
-                            if let Some(v) = v {
-                                params.spheres = v as i32 * 2;
-                            }
-                            (params.spheres / 2) as f64
-                        })
-                        .integer(),
+                        egui::TextEdit::singleline(&mut *environment_path)
+                            .hint_text("textures/sky.hdr"),
                     );
                 });
+                if ui.button("Load environment").clicked() && !environment_path.is_empty() {
+                    environment.load(&environment_path, &asset_server);
+                }
+                if !environment.path.is_empty() {
+                    ui.label(format!("showing {}", environment.path));
+                }
 
-                for i in 0..params.spheres / 2 {
-                    ui.label(format!("{}", i));
+                let mut rotation = params.sky_rotation.to_degrees();
+                if ui
+                    .add(egui::Slider::new(&mut rotation, -180.0..=180.0).text("rotation"))
+                    .changed()
+                {
+                    params.sky_rotation = rotation.to_radians();
+                }
+            }
 
-                    let labels = ["x", "y", "z", "r"];
-                    let ranges = [-2.0..=2.0, -2.0..=2.0, -2.0..=0., 0.0..=1.0];
+            // the model only covers the sun above the horizon
+            if params.sky == Sky::Physical as i32 {
+                ui.add(egui::Slider::new(&mut params.turbidity, 2.0..=10.0).text("turbidity"));
+                let mut elevation = params.sun_elevation.to_degrees();
+                if ui
+                    .add(egui::Slider::new(&mut elevation, 0.0..=90.0).text("sun elevation"))
+                    .changed()
+                {
+                    params.sun_elevation = elevation.to_radians();
+                }
+                let mut azimuth = params.sun_azimuth.to_degrees();
+                if ui
+                    .add(egui::Slider::new(&mut azimuth, -180.0..=180.0).text("sun azimuth"))
+                    .changed()
+                {
+                    params.sun_azimuth = azimuth.to_radians();
+                }
+            }
 
-                    for j in 0..4 {
+            if params.sky == Sky::Environment as i32 || params.sky == Sky::Physical as i32 {
+                ui.add(
+                    egui::Slider::new(&mut params.sky_intensity, 0.0..=10.0)
+                        .logarithmic(true)
+                        .text("intensity"),
+                );
+            }
+
+            // compare against plain path tracing, which only finds emissive objects by chance
+            let mut mis = params.mis != 0;
+            if ui.checkbox(&mut mis, "sample emitters (MIS)").changed() {
+                params.mis = mis as i32;
+            }
+
+            ui.add_enabled(
+                state.get() != &AppState::Running,
+                egui::Checkbox::new(&mut one_shot.enabled, "one shot"),
+            );
+
+            ui.horizontal(|ui| {
+                ui.label("tile size");
+                ui.add_enabled(
+                    one_shot.enabled && state.get() != &AppState::Running,
+                    egui::Slider::from_get_set(3.0..=9.0, |v: Option<f64>| {
+                        if let Some(v) = v {
+                            one_shot.tile_size = v.exp2() as i32;
+                        }
+                        (one_shot.tile_size as f64).log2()
+                    })
+                    .integer()
+                    .show_value(false),
+                );
+                ui.label(format!("{}", one_shot.tile_size));
+            });
+
+            if one_shot.enabled {
+                let tiles = one_shot.tile_count();
+                ui.add(
+                    egui::ProgressBar::new(progress.count as f32 / tiles as f32)
+                        .text(format!("tile {} of {}", progress.count, tiles)),
+                );
+            } else {
+                ui.label(format!("accumulated frames: {}", progress.frame + 1));
+            }
+
+            ui.allocate_space(egui::Vec2::new(1.0, 20.0));
+
+            ui.heading("Scene");
+
+            ui.horizontal(|ui| {
+                ui.label("assets/");
+                ui.text_edit_singleline(&mut current_scene.path);
+            });
+
+            ui.horizontal(|ui| {
+                if ui.button("Open scene").clicked() {
+                    open_scene.send(OpenScene {
+                        path: current_scene.path.clone(),
+                    });
+                }
+
+                #[cfg(not(target_arch = "wasm32"))]
+                if ui.button("Save scene").clicked() {
+                    save_scene.send(SaveScene {
+                        path: current_scene.path.clone(),
+                    });
+                }
+            });
+
+            ui.allocate_space(egui::Vec2::new(1.0, 20.0));
+
+            ui.heading("Meshes");
+
+            ui.horizontal(|ui| {
+                ui.label("assets/");
+                ui.add(
+                    egui::TextEdit::singleline(&mut *mesh_path)
+                        .hint_text("models/cube.obj or .gltf/.glb"),
+                );
+            });
+            if ui.button("Load model").clicked() && !mesh_path.is_empty() {
+                meshes.import(&mesh_path, &asset_server);
+                commands.spawn(mesh_bundle(&mesh_path, Transform::IDENTITY));
+            }
+
+            // sorted like gather_scene does, so the list doesn't jump around
+            let mut mesh_entities: Vec<_> = mesh_query.iter_mut().collect();
+            mesh_entities.sort_by_key(|(entity, ..)| *entity);
+            for (entity, mesh, mut transform) in mesh_entities {
+                egui::CollapsingHeader::new(&mesh.path)
+                    .id_source(("mesh", entity))
+                    .show(ui, |ui| {
                         ui.horizontal(|ui| {
-                            ui.add(
-                                egui::Slider::new(
-                                    &mut spheres.spheres[(i * 2) as usize][j],
-                                    ranges[j].clone(),
-                                )
-                                .text(labels[j]),
+                            // copies share the file and its bvh
+                            if ui.button("Duplicate").clicked() {
+                                commands.spawn(mesh_bundle(&mesh.path, *transform));
+                            }
+                            if ui.button("Remove").clicked() {
+                                commands.entity(entity).despawn();
+                            }
+                        });
+
+                        let mut edited = *transform;
+                        transform_controls(ui, &mut edited);
+                        transform.set_if_neq(edited);
+                    });
+            }
+            ui.label(format!("triangles: {}", triangles.count()));
+
+            ui.allocate_space(egui::Vec2::new(1.0, 20.0));
+
+            ui.heading("Spheres");
+
+            ui.checkbox(&mut animate, "animate");
+
+            egui::ScrollArea::vertical().show(ui, |ui| {
+                if ui.button("Add sphere").clicked() {
+                    commands.spawn(sphere_bundle(&Sphere::new(
+                        [0., 0., -1.],
+                        0.25,
+                        [0.5, 0.5, 0.5],
+                        Material::Lambertian,
+                    )));
+                }
+
+                let mut sphere_entities: Vec<_> = sphere_query.iter_mut().collect();
+                sphere_entities.sort_by_key(|(entity, ..)| *entity);
+                for (i, (entity, mut sphere, mut material, mut transform)) in
+                    sphere_entities.into_iter().enumerate()
+                {
+                    // collapsed by default so big scenes stay usable
+                    egui::CollapsingHeader::new(format!("Sphere {}", i))
+                        .id_source(("sphere", entity))
+                        .show(ui, |ui| {
+                            if ui.button("Remove").clicked() {
+                                commands.entity(entity).despawn();
+                            }
+
+                            let mut center = transform.translation.to_array();
+                            vector_controls(ui, &mut center);
+
+                            let mut radius = sphere.radius;
+                            ui.horizontal(|ui| {
+                                // negative radii are hollow, only useful inside a dielectric
+                                ui.add(egui::DragValue::new(&mut radius).speed(0.01).prefix("r: "));
+                            });
+
+                            let mut edited = *material;
+                            color_sliders(ui, &mut edited.color);
+                            material_controls(
+                                ui,
+                                ("material", entity),
+                                &mut edited.material,
+                                &mut edited.fuzz,
+                                &mut edited.ior,
+                                &mut edited.intensity,
                             );
+
+                            transform.set_if_neq(Transform {
+                                translation: Vec3::from(center),
+                                ..*transform
+                            });
+                            sphere.set_if_neq(RtSphere { radius });
+                            material.set_if_neq(edited);
                         });
+                }
+
+                ui.allocate_space(egui::Vec2::new(1.0, 20.0));
+
+                ui.heading("Primitives");
+
+                ui.horizontal_wrapped(|ui| {
+                    for shape in Shape::ALL {
+                        if ui.small_button(format!("Add {}", shape.label())).clicked() {
+                            commands.spawn(primitive_bundle(
+                                &new_primitive(shape),
+                                Transform::IDENTITY,
+                            ));
+                        }
                     }
+                });
 
-                    let labels = ["r", "g", "b"];
-                    for j in 0..3 {
-                        ui.horizontal(|ui| {
-                            ui.add(
-                                egui::Slider::new(
-                                    &mut spheres.spheres[((2 * i) + 1) as usize][j],
-                                    0.0..=1.0,
-                                )
-                                .text(labels[j]),
+                let mut primitive_entities: Vec<_> = primitive_query.iter_mut().collect();
+                primitive_entities.sort_by_key(|(entity, ..)| *entity);
+                for (i, (entity, mut primitive, mut material, mut transform)) in
+                    primitive_entities.into_iter().enumerate()
+                {
+                    let shape = primitive.shape;
+                    egui::CollapsingHeader::new(format!("{} {}", shape.label(), i))
+                        .id_source(("primitive", entity))
+                        .show(ui, |ui| {
+                            ui.horizontal(|ui| {
+                                if ui.button("Duplicate").clicked() {
+                                    let copy = Primitive::from_parts(&primitive, &material);
+                                    commands.spawn(primitive_bundle(&copy, *transform));
+                                }
+                                if ui.button("Remove").clicked() {
+                                    commands.entity(entity).despawn();
+                                }
+                            });
+
+                            let (origin, u, v) = match shape {
+                                Shape::Plane => ("Point", Some("Normal"), None),
+                                Shape::Quad => ("Corner", Some("Edge u"), Some("Edge v")),
+                                Shape::Box => ("Min", Some("Max"), None),
+                                Shape::Disk => ("Center", Some("Normal"), None),
+                                Shape::Cylinder => ("Base", Some("Axis"), None),
+                                Shape::Sphere => ("Center", None, None),
+                            };
+                            let mut edited = *primitive;
+                            ui.label(origin);
+                            vector_controls(ui, &mut edited.origin);
+                            if let Some(u) = u {
+                                ui.label(u);
+                                vector_controls(ui, &mut edited.u);
+                            }
+                            if let Some(v) = v {
+                                ui.label(v);
+                                vector_controls(ui, &mut edited.v);
+                            }
+
+                            if matches!(shape, Shape::Disk | Shape::Cylinder | Shape::Sphere) {
+                                ui.horizontal(|ui| {
+                                    ui.add(
+                                        egui::DragValue::new(&mut edited.radius)
+                                            .speed(0.01)
+                                            .clamp_range(0.0..=f32::INFINITY)
+                                            .prefix("r: "),
+                                    );
+                                });
+                            }
+                            primitive.set_if_neq(edited);
+
+                            let mut edited = *transform;
+                            transform_controls(ui, &mut edited);
+                            transform.set_if_neq(edited);
+
+                            let mut edited = *material;
+                            color_sliders(ui, &mut edited.color);
+                            material_controls(
+                                ui,
+                                ("primitive material", entity),
+                                &mut edited.material,
+                                &mut edited.fuzz,
+                                &mut edited.ior,
+                                &mut edited.intensity,
                             );
+                            material.set_if_neq(edited);
                         });
+                }
+
+                ui.allocate_space(egui::Vec2::new(1.0, 20.0));
+
+                ui.heading("Lights");
+
+                ui.horizontal_wrapped(|ui| {
+                    for kind in LightKind::ALL {
+                        if ui.small_button(format!("Add {}", kind.label())).clicked() {
+                            commands.spawn(light_bundle(&new_light(kind)));
+                        }
                     }
+                });
+
+                let mut light_entities: Vec<_> = light_query.iter_mut().collect();
+                light_entities.sort_by_key(|(entity, ..)| *entity);
+                for (i, (entity, mut light, mut transform)) in
+                    light_entities.into_iter().enumerate()
+                {
+                    egui::CollapsingHeader::new(format!("{} light {}", light.kind.label(), i))
+                        .id_source(("light", entity))
+                        .show(ui, |ui| {
+                            ui.horizontal(|ui| {
+                                if ui.button("Duplicate").clicked() {
+                                    let copy = Light::from_parts(&light, &transform);
+                                    commands.spawn(light_bundle(&copy));
+                                }
+                                if ui.button("Remove").clicked() {
+                                    commands.entity(entity).despawn();
+                                }
+                            });
+
+                            let mut edited = *light;
+                            egui::ComboBox::from_id_source(("light kind", entity))
+                                .selected_text(edited.kind.label())
+                                .show_ui(ui, |ui| {
+                                    for option in LightKind::ALL {
+                                        ui.selectable_value(
+                                            &mut edited.kind,
+                                            option,
+                                            option.label(),
+                                        );
+                                    }
+                                });
+
+                            let mut placed = *transform;
+                            if edited.kind != LightKind::Directional {
+                                let mut position = placed.translation.to_array();
+                                ui.label("Position");
+                                vector_controls(ui, &mut position);
+                                placed.translation = Vec3::from(position);
+                            }
+                            if edited.kind != LightKind::Point {
+                                aim_controls(ui, &mut placed);
+                            }
+                            transform.set_if_neq(placed);
+
+                            color_sliders(ui, &mut edited.color);
+                            ui.horizontal(|ui| {
+                                ui.add(
+                                    egui::Slider::new(&mut edited.intensity, 0.0..=100.0)
+                                        .logarithmic(true)
+                                        .text("intensity"),
+                                );
+                            });
+
+                            // shown in degrees, only written back when moved so the radians don't
+                            // drift and restart the render
+                            if edited.kind == LightKind::Spot {
+                                for (angle, label) in [
+                                    (&mut edited.inner_angle, "inner"),
+                                    (&mut edited.outer_angle, "outer"),
+                                ] {
+                                    let mut degrees = angle.to_degrees();
+                                    ui.horizontal(|ui| {
+                                        let slider =
+                                            egui::Slider::new(&mut degrees, 0.0..=90.0).text(label);
+                                        if ui.add(slider).changed() {
+                                            *angle = degrees.to_radians();
+                                        }
+                                    });
+                                }
+                            }
+                            light.set_if_neq(edited);
+                        });
                 }
             });
         });
@@ -207,23 +617,57 @@ fn ui_system(
             }
 
             ui.allocate_space(egui::Vec2::new(1.0, 10.0));
-            ui.label("Camera Center");
+            let vectors = [
+                ("Look From", &mut camera.camera_center),
+                ("Look At", &mut camera.look_at),
+                ("Up", &mut camera.vup),
+            ];
+            for (name, vector) in vectors {
+                ui.label(name);
+                vector_controls(ui, vector);
+                ui.allocate_space(egui::Vec2::new(1.0, 10.0));
+            }
 
-            let labels = ["x", "y", "z"];
-            for j in 0..3 {
-                ui.horizontal(|ui| {
-                    ui.add(
-                        egui::Slider::from_get_set(-1.0..=1.0, |v: Option<f64>| {
-                            if let Some(v) = v {
-                                camera.camera_center[j] = v as f32;
-                            }
-                            camera.camera_center[j] as f64
-                        })
-                        .text(labels[j]),
-                    );
-                });
+            ui.horizontal(|ui| {
+                ui.add(egui::Slider::new(&mut camera.vfov, 10.0..=120.0).text("fov"));
+            });
+
+            ui.allocate_space(egui::Vec2::new(1.0, 10.0));
+            ui.label("Depth of Field");
+
+            ui.horizontal(|ui| {
+                ui.add(egui::Slider::new(&mut camera.defocus_angle, 0.0..=10.0).text("aperture"));
+            });
+
+            ui.horizontal(|ui| {
+                ui.add(
+                    egui::Slider::new(&mut camera.focus_dist, 0.1..=10.0)
+                        .logarithmic(true)
+                        .text("focus"),
+                );
+            });
+
+            let pick_text = if focus_picker.active {
+                "click the render..."
+            } else {
+                "Pick focus"
+            };
+            if ui.button(pick_text).clicked() {
+                focus_picker.active = !focus_picker.active;
             }
 
+            ui.allocate_space(egui::Vec2::new(1.0, 10.0));
+            ui.label("Controls");
+            ui.label("drag to orbit, right drag to pan, scroll to dolly, WASD/QE to fly");
+
+            ui.horizontal(|ui| {
+                ui.add(
+                    egui::Slider::new(&mut controls.fly_speed, 0.1..=10.0)
+                        .logarithmic(true)
+                        .text("fly speed"),
+                );
+            });
+
             ui.with_layout(egui::Layout::bottom_up(egui::Align::Center), |ui| {
                 ui.add(egui::Hyperlink::from_label_and_url(
                     "fork me on github",
@@ -243,6 +687,161 @@ fn ui_system(
                 ui.heading("Time");
             });
         });
+
+    camera_ref.set_if_neq(camera);
+    params_ref.set_if_neq(params);
+    animation.set_if_neq(SphereAnimation { enabled: animate });
+    one_shot_ref.set_if_neq(one_shot);
+}
+
+// something small in front of the default camera, to be moved into place
+fn new_primitive(shape: Shape) -> Primitive {
+    let color = [0.5, 0.5, 0.5];
+    let material = Material::Lambertian;
+    match shape {
+        Shape::Plane => Primitive::plane([0., -0.5, 0.], [0., 1., 0.], color, material),
+        Shape::Quad => Primitive::quad(
+            [-0.25, -0.25, -1.],
+            [0.5, 0., 0.],
+            [0., 0.5, 0.],
+            color,
+            material,
+        ),
+        Shape::Box => Primitive::cuboid([-0.25, -0.5, -1.25], [0.25, 0., -0.75], color, material),
+        Shape::Disk => Primitive::disk([0., 0., -1.], [0., 0., 1.], 0.25, color, material),
+        Shape::Cylinder => {
+            Primitive::cylinder([0., -0.5, -1.], [0., 0.5, 0.], 0.25, color, material)
+        }
+        Shape::Sphere => Primitive::sphere([0., 0., -1.], 0.25, color, material),
+    }
+}
+
+// pointing down and a little in front of the default camera
+fn new_light(kind: LightKind) -> Light {
+    let color = [1., 1., 1.];
+    match kind {
+        LightKind::Point => Light::point([0., 1., -1.], color, 1.),
+        LightKind::Spot => Light::spot(
+            [0., 1., -1.],
+            [0., -1., 0.],
+            color,
+            2.,
+            20f32.to_radians(),
+            30f32.to_radians(),
+        ),
+        LightKind::Directional => Light::directional([-0.5, -1., -0.5], color, 1.),
+    }
+}
+
+// drag values rather than sliders, scene files can put things anywhere. they move faster the
+// further they are from 0, so big scenes don't take forever to drag around
+fn vector_controls(ui: &mut egui::Ui, vector: &mut [f32; 3]) {
+    ui.horizontal(|ui| {
+        for (value, label) in vector.iter_mut().zip(["x", "y", "z"]) {
+            let speed = (value.abs() * 0.01).max(0.01);
+            ui.add(
+                egui::DragValue::new(value)
+                    .speed(speed)
+                    .prefix(format!("{}: ", label)),
+            );
+        }
+    });
+}
+
+// rotation is shown as euler angles in degrees, applied around y, then x, then z
+fn transform_controls(ui: &mut egui::Ui, transform: &mut Transform) {
+    let mut translation = transform.translation.to_array();
+    ui.label("Translation");
+    vector_controls(ui, &mut translation);
+    transform.translation = Vec3::from(translation);
+
+    // going through the quaternion every frame would make the angles drift, so they are only
+    // written back when a slider moves
+    let (y, x, z) = transform.rotation.to_euler(EulerRot::YXZ);
+    let mut angles = [x, y, z].map(f32::to_degrees);
+    let mut rotated = false;
+    ui.label("Rotation");
+    for (j, label) in ["x", "y", "z"].into_iter().enumerate() {
+        ui.horizontal(|ui| {
+            let slider = egui::Slider::new(&mut angles[j], -180.0..=180.0).text(label);
+            rotated |= ui.add(slider).changed();
+        });
+    }
+    if rotated {
+        let [x, y, z] = angles.map(f32::to_radians);
+        transform.rotation = Quat::from_euler(EulerRot::YXZ, y, x, z);
+    }
+
+    let mut scale = transform.scale.to_array();
+    ui.label("Scale");
+    vector_controls(ui, &mut scale);
+    transform.scale = Vec3::from(scale);
+}
+
+// the way a spot or directional light points, as degrees of pitch above the horizon and yaw around
+// y. like the rotation in transform_controls, the angles are only written back when moved
+fn aim_controls(ui: &mut egui::Ui, transform: &mut Transform) {
+    let (yaw, pitch, _) = transform.rotation.to_euler(EulerRot::YXZ);
+    let mut angles = [pitch, yaw].map(f32::to_degrees);
+    let mut aimed = false;
+    ui.label("Direction");
+    for (j, (label, limit)) in [("pitch", 90.), ("yaw", 180.)].into_iter().enumerate() {
+        ui.horizontal(|ui| {
+            let slider = egui::Slider::new(&mut angles[j], -limit..=limit).text(label);
+            aimed |= ui.add(slider).changed();
+        });
+    }
+    if aimed {
+        let [pitch, yaw] = angles.map(f32::to_radians);
+        transform.rotation = Quat::from_euler(EulerRot::YXZ, yaw, pitch, 0.);
+    }
+}
+
+// only the rgb of a material color, lights have no alpha
+fn color_sliders(ui: &mut egui::Ui, color: &mut [f32]) {
+    let labels = ["r", "g", "b"];
+    for j in 0..3 {
+        ui.horizontal(|ui| {
+            ui.add(egui::Slider::new(&mut color[j], 0.0..=1.0).text(labels[j]));
+        });
+    }
+}
+
+// the material picker plus whichever setting the chosen material uses
+fn material_controls(
+    ui: &mut egui::Ui,
+    id: impl std::hash::Hash,
+    material: &mut i32,
+    fuzz: &mut f32,
+    ior: &mut f32,
+    intensity: &mut f32,
+) {
+    egui::ComboBox::from_id_source(id)
+        .selected_text(Material::from_index(*material).label())
+        .show_ui(ui, |ui| {
+            for option in Material::ALL {
+                ui.selectable_value(material, option as i32, option.label());
+            }
+        });
+
+    match Material::from_index(*material) {
+        Material::Metal => {
+            ui.horizontal(|ui| {
+                ui.add(egui::Slider::new(fuzz, 0.0..=1.0).text("fuzz"));
+            });
+        }
+        Material::Dielectric => {
+            ui.horizontal(|ui| {
+                ui.add(egui::Slider::new(ior, 1.0..=2.5).text("ior"));
+            });
+        }
+        Material::Emissive => {
+            ui.horizontal(|ui| {
+                ui.add(egui::Slider::new(intensity, 0.0..=20.0).text("intensity"));
+            });
+        }
+        Material::Lambertian => {}
+    }
 }
 
 fn data_for_resource<T: Resource + Reflect + GetField>(
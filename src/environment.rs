@@ -0,0 +1,231 @@
+use bevy::{
+    asset::LoadState,
+    prelude::*,
+    render::{extract_resource::ExtractResource, render_resource::*},
+};
+
+// an equirectangular hdr image lighting the scene from every direction, and the table the shader
+// picks its bright texels from. a black texel stands in until a file is loaded
+#[derive(Resource, Clone, ExtractResource)]
+pub struct Environment {
+    pub path: String, // relative to assets, empty for the placeholder
+    pub image: Handle<Image>,
+    pub cdf: Handle<Image>,
+    // the file being loaded, path, image and cdf are swapped together once it is ready
+    loading: Option<(String, Handle<Image>)>,
+}
+
+impl Environment {
+    pub fn load(&mut self, path: &str, asset_server: &AssetServer) {
+        self.loading = Some((path.to_string(), asset_server.load(path)));
+    }
+}
+
+pub fn setup_environment(mut commands: Commands, mut images: ResMut<Assets<Image>>) {
+    let image = Image::new(
+        Extent3d {
+            width: 1,
+            height: 1,
+            depth_or_array_layers: 1,
+        },
+        TextureDimension::D2,
+        bytemuck::cast_slice(&[0f32; 4]).to_vec(),
+        TextureFormat::Rgba32Float,
+    );
+    let cdf = environment_cdf(&image).unwrap();
+    commands.insert_resource(Environment {
+        path: String::new(),
+        image: images.add(image),
+        cdf: images.add(cdf),
+        loading: None,
+    });
+}
+
+pub fn update_environment(
+    mut environment: ResMut<Environment>,
+    mut images: ResMut<Assets<Image>>,
+    asset_server: Res<AssetServer>,
+) {
+    let Some((path, loading)) = environment.loading.clone() else {
+        return;
+    };
+    let Some(image) = images.get(&loading) else {
+        if asset_server.get_load_state(&loading) == LoadState::Failed {
+            error!("couldn't load environment {}", path);
+            environment.loading = None;
+        }
+        return;
+    };
+
+    let environment = environment.as_mut();
+    environment.loading = None;
+    match environment_cdf(image) {
+        Some(cdf) => {
+            environment.path = path;
+            environment.image = loading;
+            environment.cdf = images.add(cdf);
+            info!("loaded environment {}", environment.path);
+        }
+        None => error!(
+            "environment {} is {:?}, only rgba32float images can light the scene",
+            path, image.texture_descriptor.format
+        ),
+    }
+}
+
+// luminance weighted by sin(theta), since the rows near the poles cover less of the sphere.
+// rows 0 to height - 1 hold each row's cdf over its texels, row height holds the cdf over the
+// rows and its last texel the sum of every weight, which turns a texel's weight into its pdf.
+// equirect maps are twice as wide as they are tall, so that row fits in the image's width and an
+// 8k map stays inside the texture size limit
+fn environment_cdf(image: &Image) -> Option<Image> {
+    if image.texture_descriptor.format != TextureFormat::Rgba32Float {
+        return None;
+    }
+    let size = image.size();
+    let (width, height) = (size.x as usize, size.y as usize);
+    let texels: &[[f32; 4]] = bytemuck::try_cast_slice(&image.data).ok()?;
+    if texels.len() < width * height {
+        return None;
+    }
+
+    let stride = width.max(height + 1);
+    let mut cdf = vec![0f32; stride * (height + 1)];
+    let mut row_sums = vec![0f32; height];
+    for y in 0..height {
+        let sin_theta = (std::f32::consts::PI * (y as f32 + 0.5) / height as f32).sin();
+        let row = &mut cdf[y * stride..y * stride + width];
+        let mut sum = 0.;
+        for (x, value) in row.iter_mut().enumerate() {
+            let [r, g, b, _] = texels[y * width + x];
+            sum += luminance(r, g, b).max(0.) * sin_theta;
+            *value = sum;
+        }
+        normalize(row, sum);
+        row_sums[y] = sum;
+    }
+
+    let marginal = &mut cdf[height * stride..height * stride + height];
+    let mut total = 0.;
+    for (value, row_sum) in marginal.iter_mut().zip(&row_sums) {
+        total += row_sum;
+        *value = total;
+    }
+    normalize(marginal, total);
+    cdf[(height + 1) * stride - 1] = total;
+
+    Some(Image::new(
+        Extent3d {
+            width: stride as u32,
+            height: height as u32 + 1,
+            depth_or_array_layers: 1,
+        },
+        TextureDimension::D2,
+        bytemuck::cast_slice(&cdf).to_vec(),
+        TextureFormat::R32Float,
+    ))
+}
+
+// an all black row is never picked, but the search still needs it to end at 1
+fn normalize(cdf: &mut [f32], sum: f32) {
+    if sum <= 0. {
+        cdf.iter_mut().for_each(|value| *value = 1.);
+        return;
+    }
+    cdf.iter_mut().for_each(|value| *value /= sum);
+}
+
+// matches luminance in simple.wgsl
+fn luminance(r: f32, g: f32, b: f32) -> f32 {
+    0.2126 * r + 0.7152 * g + 0.0722 * b
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn image(width: u32, height: u32, texels: &[[f32; 4]]) -> Image {
+        Image::new(
+            Extent3d {
+                width,
+                height,
+                depth_or_array_layers: 1,
+            },
+            TextureDimension::D2,
+            bytemuck::cast_slice(texels).to_vec(),
+            TextureFormat::Rgba32Float,
+        )
+    }
+
+    fn rows(cdf: &Image) -> Vec<Vec<f32>> {
+        let values: &[f32] = bytemuck::cast_slice(&cdf.data);
+        values
+            .chunks(cdf.texture_descriptor.size.width as usize)
+            .map(|row| row.to_vec())
+            .collect()
+    }
+
+    fn assert_cdf(values: &[f32]) {
+        assert!(
+            values.windows(2).all(|pair| pair[0] <= pair[1]),
+            "{:?}",
+            values
+        );
+        assert!((values[values.len() - 1] - 1.).abs() < 1e-6, "{:?}", values);
+    }
+
+    #[test]
+    fn cdf_layout_matches_the_shader() {
+        // a bright texel in the lower row, a dim one in the upper and a black one
+        let white = [1., 1., 1., 1.];
+        let grey = [0.25, 0.25, 0.25, 1.];
+        let black = [0., 0., 0., 1.];
+        let (width, height) = (4, 2);
+        let texels = [grey, black, black, black, black, white, white, black];
+        let cdf = environment_cdf(&image(width, height, &texels)).unwrap();
+
+        let stride = width as usize;
+        assert_eq!(cdf.texture_descriptor.size.width, width);
+        assert_eq!(cdf.texture_descriptor.size.height, height + 1);
+        let rows = rows(&cdf);
+
+        for row in &rows[..height as usize] {
+            assert_cdf(&row[..width as usize]);
+        }
+        assert_eq!(&rows[0][..4], &[1., 1., 1., 1.]);
+        assert_eq!(&rows[1][..4], &[0., 0.5, 1., 1.]);
+
+        let marginal = &rows[height as usize];
+        assert_cdf(&marginal[..height as usize]);
+
+        let sin_theta = (std::f32::consts::PI / 4.).sin();
+        let total = (0.25 + 2.) * sin_theta;
+        assert!((marginal[0] - 0.25 * sin_theta / total).abs() < 1e-6);
+        assert!((marginal[stride - 1] - total).abs() < 1e-5);
+    }
+
+    #[test]
+    fn cdf_keeps_the_image_width_when_the_marginal_fits() {
+        let cdf = environment_cdf(&image(8, 4, &[[1.; 4]; 32])).unwrap();
+        assert_eq!(cdf.texture_descriptor.size.width, 8);
+        let cdf = environment_cdf(&image(2, 4, &[[1.; 4]; 8])).unwrap();
+        assert_eq!(cdf.texture_descriptor.size.width, 5);
+    }
+
+    #[test]
+    fn black_images_still_end_at_one() {
+        let cdf = environment_cdf(&image(3, 3, &[[0.; 4]; 9])).unwrap();
+        let rows = rows(&cdf);
+        for row in &rows {
+            assert_cdf(&row[..3]);
+        }
+        assert_eq!(rows[3][3], 0.);
+    }
+
+    #[test]
+    fn only_float_images_have_a_cdf() {
+        let mut image = image(1, 1, &[[1.; 4]]);
+        image.texture_descriptor.format = TextureFormat::Rgba8Unorm;
+        assert!(environment_cdf(&image).is_none());
+    }
+}
@@ -0,0 +1,258 @@
+use crate::{
+    render::{
+        AccumulationBuffer, OneShot, Params, Progress, ACCUMULATION_BUFFER_SIZE, PATH_TRACED,
+    },
+    AppState, SIZE,
+};
+
+use bevy::{
+    prelude::*,
+    render::{
+        render_resource::*,
+        renderer::{RenderDevice, RenderQueue},
+        Render, RenderApp, RenderSet,
+    },
+    tasks::IoTaskPool,
+};
+use bytemuck::cast_slice;
+use image::{codecs::hdr::HdrEncoder, Rgb, RgbaImage};
+use std::{
+    fs::File,
+    io::BufWriter,
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+// send this to save the current render as <prefix>_<samples>spp_depth<depth>_<timestamp>.png/.hdr
+#[derive(Event, Debug, Clone)]
+pub struct SaveImage {
+    pub prefix: String,
+}
+
+impl Default for SaveImage {
+    fn default() -> Self {
+        SaveImage {
+            prefix: "render".to_string(),
+        }
+    }
+}
+
+// shared between the main and render worlds, the render world drains it once the frame is submitted
+#[derive(Resource, Clone, Default)]
+struct ExportRequests {
+    prefixes: Arc<Mutex<Vec<String>>>,
+}
+
+// a copy of the accumulation buffer waiting for the gpu to map it, mapped holds the result once
+// the gpu is done
+struct PendingExport {
+    buffer: Buffer,
+    mapped: Arc<Mutex<Option<Result<(), String>>>>,
+    prefixes: Vec<String>,
+    frames: i32,
+    samples: i32,
+    depth: i32,
+    gamma_corrected: bool,
+}
+
+#[derive(Resource, Default)]
+struct ExportBuffer {
+    pending: Option<PendingExport>,
+}
+
+pub struct ExportPlugin;
+impl Plugin for ExportPlugin {
+    fn build(&self, app: &mut App) {
+        let requests = ExportRequests::default();
+
+        app.add_event::<SaveImage>()
+            .insert_resource(requests.clone())
+            .add_systems(Update, queue_exports);
+
+        app.sub_app_mut(RenderApp)
+            .insert_resource(requests)
+            .init_resource::<ExportBuffer>()
+            .add_systems(
+                Render,
+                (finish_export, read_back_image)
+                    .chain()
+                    .in_set(RenderSet::Cleanup),
+            );
+    }
+}
+
+// a one-shot render is only whole once every tile is done
+pub(crate) fn can_save(one_shot: &OneShot, state: &AppState) -> bool {
+    !one_shot.enabled || *state == AppState::Done
+}
+
+fn queue_exports(
+    mut events: EventReader<SaveImage>,
+    requests: Res<ExportRequests>,
+    one_shot: Res<OneShot>,
+    state: Res<State<AppState>>,
+) {
+    let mut prefixes = requests.prefixes.lock().unwrap();
+    for event in events.iter() {
+        if can_save(&one_shot, state.get()) {
+            prefixes.push(event.prefix.clone());
+        } else {
+            warn!(
+                "not saving {}, the one-shot render has tiles left",
+                event.prefix
+            );
+        }
+    }
+}
+
+// copies the accumulation buffer or texture into a mappable buffer after the frame's compute pass
+// has run
+fn read_back_image(
+    requests: Res<ExportRequests>,
+    params: Res<Params>,
+    progress: Res<Progress>,
+    accumulation_buffer: Res<AccumulationBuffer>,
+    mut export_buffer: ResMut<ExportBuffer>,
+    render_device: Res<RenderDevice>,
+    render_queue: Res<RenderQueue>,
+) {
+    if export_buffer.pending.is_some() {
+        return;
+    }
+
+    if accumulation_buffer.buffer.is_none() && accumulation_buffer.textures.is_none() {
+        return;
+    }
+
+    let prefixes: Vec<String> = requests.prefixes.lock().unwrap().drain(..).collect();
+    if prefixes.is_empty() {
+        return;
+    }
+
+    let buffer = render_device.create_buffer(&BufferDescriptor {
+        label: Some("export buffer"),
+        size: ACCUMULATION_BUFFER_SIZE,
+        usage: BufferUsages::MAP_READ | BufferUsages::COPY_DST,
+        mapped_at_creation: false,
+    });
+
+    let mut encoder = render_device.create_command_encoder(&CommandEncoderDescriptor {
+        label: Some("export encoder"),
+    });
+    if let Some(accumulation) = &accumulation_buffer.buffer {
+        encoder.copy_buffer_to_buffer(accumulation, 0, &buffer, 0, ACCUMULATION_BUFFER_SIZE);
+    } else if let Some(textures) = &accumulation_buffer.textures {
+        // a row of rgba32float pixels is a multiple of 256 bytes, so the rows come out packed the
+        // same way as the buffer
+        encoder.copy_texture_to_buffer(
+            textures.written.as_image_copy(),
+            ImageCopyBuffer {
+                buffer: &buffer,
+                layout: ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: Some(SIZE.0 * 16),
+                    rows_per_image: None,
+                },
+            },
+            Extent3d {
+                width: SIZE.0,
+                height: SIZE.1,
+                depth_or_array_layers: 1,
+            },
+        );
+    }
+    render_queue.submit([encoder.finish()]);
+
+    // the device is polled every frame when the render queue is submitted
+    let mapped = Arc::new(Mutex::new(None));
+    let callback_mapped = mapped.clone();
+    render_device.map_buffer(&buffer.slice(..), MapMode::Read, move |result| {
+        *callback_mapped.lock().unwrap() = Some(result.map_err(|err| err.to_string()));
+    });
+
+    export_buffer.pending = Some(PendingExport {
+        buffer,
+        mapped,
+        prefixes,
+        // the frame the shader was just given, so it matches what is in the buffer
+        frames: progress.frame + 1,
+        samples: params.samples,
+        depth: params.depth,
+        gamma_corrected: params.render_mode == PATH_TRACED,
+    });
+}
+
+fn finish_export(mut export_buffer: ResMut<ExportBuffer>) {
+    let result = match &export_buffer.pending {
+        Some(pending) => pending.mapped.lock().unwrap().take(),
+        None => None,
+    };
+    let Some(result) = result else {
+        return;
+    };
+    // dropped either way, so a failed export doesn't block the next one
+    let pending = export_buffer.pending.take().unwrap();
+    if let Err(err) = result {
+        error!("failed to map export buffer: {}", err);
+        return;
+    }
+
+    let sum: Vec<f32> = cast_slice(&pending.buffer.slice(..).get_mapped_range()).to_vec();
+    pending.buffer.unmap();
+
+    let linear: Vec<f32> = sum.iter().map(|v| v / pending.frames as f32).collect();
+    let samples = pending.samples * pending.frames;
+    let depth = pending.depth;
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    let prefixes = pending.prefixes;
+    let gamma_corrected = pending.gamma_corrected;
+
+    IoTaskPool::get()
+        .spawn(async move {
+            for prefix in prefixes {
+                let name = format!("{}_{}spp_depth{}_{}", prefix, samples, depth, timestamp);
+                save_png(&linear, gamma_corrected, &format!("{}.png", name));
+                save_hdr(&linear, &format!("{}.hdr", name));
+            }
+        })
+        .detach();
+}
+
+// matches what the shader writes to the Rgba8Unorm display texture
+fn save_png(linear: &[f32], gamma_corrected: bool, path: &str) {
+    let bytes: Vec<u8> = linear
+        .chunks_exact(4)
+        .flat_map(|pixel| {
+            [pixel[0], pixel[1], pixel[2], 1.].map(|v| {
+                let v = if gamma_corrected { v.max(0.).sqrt() } else { v };
+                (v.clamp(0., 1.) * 255.).round() as u8
+            })
+        })
+        .collect();
+
+    let image = RgbaImage::from_raw(SIZE.0, SIZE.1, bytes).unwrap();
+    match image.save(path) {
+        Ok(_) => info!("saved {}", path),
+        Err(err) => error!("failed to save {}: {}", path, err),
+    }
+}
+
+fn save_hdr(linear: &[f32], path: &str) {
+    let pixels: Vec<Rgb<f32>> = linear
+        .chunks_exact(4)
+        .map(|pixel| Rgb([pixel[0], pixel[1], pixel[2]]))
+        .collect();
+
+    let result = File::create(path)
+        .map_err(image::ImageError::from)
+        .and_then(|file| {
+            HdrEncoder::new(BufWriter::new(file)).encode(&pixels, SIZE.0 as usize, SIZE.1 as usize)
+        });
+    match result {
+        Ok(_) => info!("saved {}", path),
+        Err(err) => error!("failed to save {}: {}", path, err),
+    }
+}
@@ -0,0 +1,378 @@
+use bevy::{
+    gltf::Gltf,
+    pbr::{AlphaMode, DirectionalLight, PointLight, SpotLight, StandardMaterial},
+    prelude::*,
+    render::{
+        camera::Projection,
+        mesh::{Indices, PrimitiveTopology, VertexAttributeValues},
+    },
+    scene::Scene,
+};
+
+use crate::{
+    collidables::Material,
+    light::Light,
+    mesh::{SurfaceMaterial, TriangleMesh, Vertex},
+};
+
+// bevy works in photometric units, the tracer's lights are in watts
+const LUMENS_PER_WATT: f32 = 683.;
+
+// everything the asset server needs to have loaded to convert a glTF file
+pub struct GltfAssets<'a> {
+    pub scenes: &'a Assets<Scene>,
+    pub meshes: &'a Assets<Mesh>,
+    pub materials: &'a Assets<StandardMaterial>,
+}
+
+// the parts of a glTF scene that aren't triangles
+#[derive(Debug, Default)]
+pub struct GltfExtras {
+    pub camera: Option<GltfCamera>,
+    pub lights: Vec<Light>,
+}
+
+#[derive(Debug)]
+pub struct GltfCamera {
+    pub look_from: Vec3,
+    pub look_at: Vec3,
+    pub vup: Vec3,
+    pub vfov: f32, // degrees
+}
+
+fn gltf_scene<'a>(gltf: &Gltf, assets: &GltfAssets<'a>) -> Option<&'a Scene> {
+    let handle = gltf.default_scene.as_ref().or(gltf.scenes.first())?;
+    assets.scenes.get(handle)
+}
+
+// the loader spawns a hierarchy, so walk up through the parents to place each entity
+fn global_transform(world: &World, entity: Entity) -> Mat4 {
+    let mut matrix = Mat4::IDENTITY;
+    let mut current = Some(entity);
+    while let Some(entity) = current {
+        if let Some(transform) = world.get::<Transform>(entity) {
+            matrix = transform.compute_matrix() * matrix;
+        }
+        current = world.get::<Parent>(entity).map(|parent| parent.get());
+    }
+    matrix
+}
+
+// metallic-roughness mapped onto the closest material the tracer has
+fn surface_material(material: &StandardMaterial) -> SurfaceMaterial {
+    let [r, g, b, alpha] = material.base_color.as_linear_rgba_f32();
+    let [er, eg, eb, _] = material.emissive.as_linear_rgba_f32();
+    let emission = er.max(eg).max(eb);
+
+    if emission > 0. {
+        SurfaceMaterial::new([er, eg, eb].map(|c| c / emission), Material::Emissive)
+            .with_intensity(emission)
+    } else if matches!(material.alpha_mode, AlphaMode::Blend) && alpha < 1. {
+        SurfaceMaterial::new([r, g, b], Material::Dielectric)
+    } else if material.metallic > 0.5 {
+        SurfaceMaterial::new([r, g, b], Material::Metal).with_fuzz(material.perceptual_roughness)
+    } else {
+        SurfaceMaterial::new([r, g, b], Material::Lambertian)
+    }
+}
+
+fn triangle_mesh(
+    name: String,
+    mesh: &Mesh,
+    transform: Mat4,
+    material: SurfaceMaterial,
+) -> Option<TriangleMesh> {
+    if mesh.primitive_topology() != PrimitiveTopology::TriangleList {
+        warn!("skipping {}, only triangle lists are supported", name);
+        return None;
+    }
+
+    let positions = mesh.attribute(Mesh::ATTRIBUTE_POSITION)?.as_float3()?;
+    let normals = mesh
+        .attribute(Mesh::ATTRIBUTE_NORMAL)
+        .and_then(VertexAttributeValues::as_float3);
+    let uvs = match mesh.attribute(Mesh::ATTRIBUTE_UV_0) {
+        Some(VertexAttributeValues::Float32x2(uvs)) => Some(uvs),
+        _ => None,
+    };
+
+    // normals need the inverse transpose so non uniform scales don't skew them
+    let normal_matrix = Mat3::from_mat4(transform).inverse().transpose();
+    let vertices = (0..positions.len())
+        .map(|i| {
+            let position = transform.transform_point3(Vec3::from(positions[i]));
+            let normal = normals.map_or(Vec3::ZERO, |normals| {
+                (normal_matrix * Vec3::from(normals[i])).normalize_or_zero()
+            });
+            let uv = uvs.map_or([0., 0.], |uvs| uvs[i]);
+            Vertex {
+                position: position.into(),
+                u: uv[0],
+                normal: normal.into(),
+                v: uv[1],
+            }
+        })
+        .collect();
+
+    // unindexed meshes use every three vertices as a triangle
+    let flat: Vec<u32> = match mesh.indices() {
+        Some(indices) => indices.iter().map(|i| i as u32).collect(),
+        None => (0..positions.len() as u32).collect(),
+    };
+    let mut indices: Vec<[u32; 3]> = flat
+        .chunks_exact(3)
+        .map(|triangle| [triangle[0], triangle[1], triangle[2]])
+        .collect();
+
+    // a mirroring transform flips the winding
+    if transform.determinant() < 0. {
+        for triangle in &mut indices {
+            triangle.swap(1, 2);
+        }
+    }
+
+    Some(TriangleMesh {
+        name,
+        vertices,
+        indices,
+        material,
+    })
+}
+
+pub fn gltf_meshes(gltf: &Gltf, assets: &GltfAssets) -> Vec<TriangleMesh> {
+    let Some(scene) = gltf_scene(gltf, assets) else {
+        return vec![];
+    };
+    let world = &scene.world;
+
+    world
+        .iter_entities()
+        .filter_map(|entity| {
+            let mesh = assets.meshes.get(entity.get::<Handle<Mesh>>()?)?;
+            let material = entity
+                .get::<Handle<StandardMaterial>>()
+                .and_then(|handle| assets.materials.get(handle))
+                .map_or(
+                    SurfaceMaterial::new([0.8, 0.8, 0.8], Material::Lambertian),
+                    surface_material,
+                );
+            let name = entity
+                .get::<Name>()
+                .map_or("gltf mesh", Name::as_str)
+                .to_string();
+
+            triangle_mesh(name, mesh, global_transform(world, entity.id()), material)
+        })
+        .collect()
+}
+
+pub fn gltf_extras(gltf: &Gltf, assets: &GltfAssets) -> GltfExtras {
+    let mut extras = GltfExtras::default();
+    let Some(scene) = gltf_scene(gltf, assets) else {
+        return extras;
+    };
+    let world = &scene.world;
+
+    for entity in world.iter_entities() {
+        let transform = global_transform(world, entity.id());
+        let (_, rotation, translation) = transform.to_scale_rotation_translation();
+
+        // the first perspective camera, looking down its local -z
+        if let Some(Projection::Perspective(projection)) = entity.get::<Projection>() {
+            if extras.camera.is_none() {
+                extras.camera = Some(GltfCamera {
+                    look_from: translation,
+                    look_at: translation + rotation * Vec3::NEG_Z,
+                    vup: rotation * Vec3::Y,
+                    vfov: projection.fov.to_degrees(),
+                });
+            }
+        }
+
+        // bevy has turned the point and spot intensities from candela into lumens spread over the
+        // whole sphere, so divide that back out to get the intensity per steradian
+        let rgb = |color: Color| {
+            let [r, g, b, _] = color.as_linear_rgba_f32();
+            [r, g, b]
+        };
+        let direction = (rotation * Vec3::NEG_Z).to_array();
+        let per_steradian = 4. * std::f32::consts::PI * LUMENS_PER_WATT;
+        if let Some(point) = entity.get::<PointLight>() {
+            extras.lights.push(Light::point(
+                translation.into(),
+                rgb(point.color),
+                point.intensity / per_steradian,
+            ));
+        }
+        if let Some(spot) = entity.get::<SpotLight>() {
+            extras.lights.push(Light::spot(
+                translation.into(),
+                direction,
+                rgb(spot.color),
+                spot.intensity / per_steradian,
+                spot.inner_angle,
+                spot.outer_angle,
+            ));
+        }
+        // already in lux, the irradiance it gives
+        if let Some(directional) = entity.get::<DirectionalLight>() {
+            extras.lights.push(Light::directional(
+                direction,
+                rgb(directional.color),
+                directional.illuminance / LUMENS_PER_WATT,
+            ));
+        }
+    }
+
+    extras
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn triangle(topology: PrimitiveTopology) -> Mesh {
+        let mut mesh = Mesh::new(topology);
+        mesh.insert_attribute(
+            Mesh::ATTRIBUTE_POSITION,
+            vec![[0., 0., 0.], [1., 0., 0.], [0., 1., 0.]],
+        );
+        mesh.insert_attribute(Mesh::ATTRIBUTE_NORMAL, vec![[0., 0., 1.]; 3]);
+        mesh.insert_attribute(Mesh::ATTRIBUTE_UV_0, vec![[0., 0.], [1., 0.], [0., 1.]]);
+        mesh
+    }
+
+    // the normal the shader would get from the winding
+    fn face_normal(mesh: &TriangleMesh, triangle: [u32; 3]) -> Vec3 {
+        let [a, b, c] = triangle.map(|i| Vec3::from(mesh.vertices[i as usize].position));
+        (b - a).cross(c - a).normalize()
+    }
+
+    #[test]
+    fn rough_materials_stay_diffuse() {
+        let material = surface_material(&StandardMaterial {
+            base_color: Color::rgb_linear(0.2, 0.4, 0.6),
+            ..default()
+        });
+        assert_eq!(
+            material,
+            SurfaceMaterial::new([0.2, 0.4, 0.6], Material::Lambertian)
+        );
+    }
+
+    #[test]
+    fn metallic_materials_keep_their_roughness_as_fuzz() {
+        let material = surface_material(&StandardMaterial {
+            base_color: Color::rgb_linear(0.9, 0.8, 0.7),
+            metallic: 1.,
+            perceptual_roughness: 0.25,
+            ..default()
+        });
+        assert_eq!(
+            material,
+            SurfaceMaterial::new([0.9, 0.8, 0.7], Material::Metal).with_fuzz(0.25)
+        );
+
+        // mostly dielectric in glTF terms is still diffuse here
+        let material = surface_material(&StandardMaterial {
+            metallic: 0.4,
+            ..default()
+        });
+        assert_eq!(material.material, Material::Lambertian as i32);
+    }
+
+    #[test]
+    fn only_blended_transparency_makes_glass() {
+        let glass = StandardMaterial {
+            base_color: Color::rgba_linear(1., 1., 1., 0.5),
+            alpha_mode: AlphaMode::Blend,
+            ..default()
+        };
+        assert_eq!(
+            surface_material(&glass).material,
+            Material::Dielectric as i32
+        );
+
+        let opaque = StandardMaterial {
+            alpha_mode: AlphaMode::Opaque,
+            ..glass
+        };
+        assert_eq!(
+            surface_material(&opaque).material,
+            Material::Lambertian as i32
+        );
+    }
+
+    #[test]
+    fn emission_wins_and_its_brightest_channel_is_the_intensity() {
+        let material = surface_material(&StandardMaterial {
+            emissive: Color::rgb_linear(4., 2., 0.),
+            metallic: 1.,
+            ..default()
+        });
+        assert_eq!(
+            material,
+            SurfaceMaterial::new([1., 0.5, 0.], Material::Emissive).with_intensity(4.)
+        );
+    }
+
+    #[test]
+    fn triangles_are_placed_by_the_transform() {
+        let material = SurfaceMaterial::new([1.; 3], Material::Lambertian);
+        let transform = Mat4::from_scale_rotation_translation(
+            Vec3::new(2., 1., 1.),
+            Quat::IDENTITY,
+            Vec3::new(0., 0., -3.),
+        );
+        let mesh = triangle_mesh(
+            "triangle".to_string(),
+            &triangle(PrimitiveTopology::TriangleList),
+            transform,
+            material,
+        )
+        .unwrap();
+
+        assert_eq!(mesh.indices, vec![[0, 1, 2]]);
+        let positions: Vec<_> = mesh.vertices.iter().map(|v| v.position).collect();
+        assert_eq!(positions, vec![[0., 0., -3.], [2., 0., -3.], [0., 1., -3.]]);
+        assert_eq!((mesh.vertices[1].u, mesh.vertices[1].v), (1., 0.));
+        assert!(mesh
+            .vertices
+            .iter()
+            .all(|v| Vec3::from(v.normal).abs_diff_eq(Vec3::Z, 1e-6)));
+    }
+
+    #[test]
+    fn mirroring_flips_the_winding_back_to_match_the_normals() {
+        let material = SurfaceMaterial::new([1.; 3], Material::Lambertian);
+        let transform = Mat4::from_scale(Vec3::new(-1., 1., 1.));
+        let mesh = triangle_mesh(
+            "mirrored".to_string(),
+            &triangle(PrimitiveTopology::TriangleList),
+            transform,
+            material,
+        )
+        .unwrap();
+
+        assert_eq!(mesh.indices, vec![[0, 2, 1]]);
+        let normal = Vec3::from(mesh.vertices[0].normal);
+        assert!(face_normal(&mesh, mesh.indices[0]).abs_diff_eq(normal, 1e-6));
+    }
+
+    #[test]
+    fn indices_are_read_three_at_a_time() {
+        let material = SurfaceMaterial::new([1.; 3], Material::Lambertian);
+        let mut indexed = triangle(PrimitiveTopology::TriangleList);
+        indexed.set_indices(Some(Indices::U16(vec![0, 1, 2, 2, 1, 0, 0])));
+        let mesh =
+            triangle_mesh("indexed".to_string(), &indexed, Mat4::IDENTITY, material).unwrap();
+        assert_eq!(mesh.indices, vec![[0, 1, 2], [2, 1, 0]]);
+    }
+
+    #[test]
+    fn only_triangle_lists_are_imported() {
+        let material = SurfaceMaterial::new([1.; 3], Material::Lambertian);
+        let lines = triangle(PrimitiveTopology::LineList);
+        assert!(triangle_mesh("lines".to_string(), &lines, Mat4::IDENTITY, material).is_none());
+    }
+}
@@ -0,0 +1,313 @@
+use bevy::{
+    prelude::*,
+    render::{extract_resource::ExtractResource, render_resource::ShaderType},
+};
+use bytemuck::{Pod, Zeroable};
+
+use crate::{
+    bvh::{Aabb, Bvh},
+    collidables::Primitives,
+    mesh::Triangles,
+    storage::SceneArray,
+};
+
+// what an instance places, by index into Primitives or Meshes::files
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Geometry {
+    Primitive(usize),
+    Mesh(usize),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Instance {
+    pub geometry: Geometry,
+    pub transform: Transform,
+}
+
+// every primitive and model entity placed by its transform, filled in by gather_scene. model
+// entities with the same file share its bvh
+#[derive(Resource, Default, Debug, Clone, PartialEq)]
+pub struct Instances {
+    pub instances: Vec<Instance>,
+}
+
+// matches the geometry constants in simple.wgsl
+const PRIMITIVE: u32 = 0;
+const MESH: u32 = 1;
+
+#[derive(ShaderType, Pod, Zeroable, Clone, Copy, Default, Debug, PartialEq)]
+#[repr(C)]
+pub struct GpuInstance {
+    // rows of the affine matrix taking world space rays into object space, the shader uses it as a
+    // mat3x4 and multiplies from the left
+    pub world_to_object: [[f32; 4]; 3],
+    pub geometry: u32,
+    pub index: u32, // the primitive, or the model until upload swaps in its root node
+    pub first: u32, // the model's first triangle
+    pub emitter: u32, // set by light::collect_emitters
+}
+
+impl GpuInstance {
+    pub fn new(geometry: Geometry, world_to_object: Mat4, triangles: &Triangles) -> Self {
+        let (geometry, index, first) = match geometry {
+            Geometry::Primitive(i) => (PRIMITIVE, i as u32, 0),
+            Geometry::Mesh(i) => (MESH, i as u32, triangles.models[i].first),
+        };
+
+        GpuInstance {
+            world_to_object: [0, 1, 2].map(|row| world_to_object.row(row).to_array()),
+            geometry,
+            index,
+            first,
+            emitter: 0,
+        }
+    }
+
+    pub fn is_mesh(&self) -> bool {
+        self.geometry == MESH
+    }
+
+    pub fn world_to_object(&self) -> Mat4 {
+        let [x, y, z] = self.world_to_object.map(Vec4::from);
+        Mat4::from_cols(x, y, z, Vec4::W).transpose()
+    }
+}
+
+// unbounded instances, the planes, come first and are tested on their own, the rest follow in
+// tree order
+#[derive(Resource, ExtractResource, Clone, Default, Debug)]
+pub struct InstanceBvh {
+    pub bvh: Bvh,
+    pub instances: Vec<GpuInstance>,
+    pub unbounded: u32,
+}
+
+impl InstanceBvh {
+    pub fn build(instances: &Instances, primitives: &Primitives, triangles: &Triangles) -> Self {
+        let primitive_count = primitives.primitives.len();
+        let mut unbounded = vec![];
+        let mut bounded = vec![];
+        let mut bounds = vec![];
+        for instance in &instances.instances {
+            let object_to_world = instance.transform.compute_matrix();
+            // a zero scale squashes it out of existence
+            if object_to_world.determinant().abs() < 1e-12 {
+                continue;
+            }
+            let object_bounds = match instance.geometry {
+                Geometry::Primitive(i) if i < primitive_count => primitives.primitives[i].bounds(),
+                Geometry::Mesh(i) if i < triangles.models.len() => match triangles.models[i].bounds
+                {
+                    Some(bounds) => Some(bounds),
+                    None => continue, // still loading
+                },
+                _ => continue,
+            };
+
+            let instance =
+                GpuInstance::new(instance.geometry, object_to_world.inverse(), triangles);
+            match object_bounds {
+                Some(object_bounds) => {
+                    bounded.push(instance);
+                    bounds.push(transformed_bounds(object_bounds, object_to_world));
+                }
+                None => unbounded.push(instance),
+            }
+        }
+
+        let bvh = Bvh::build(&bounds);
+        let unbounded_count = unbounded.len() as u32;
+        let mut ordered = unbounded;
+        ordered.extend(bvh.order.iter().map(|&i| bounded[i as usize]));
+        InstanceBvh {
+            bvh,
+            instances: ordered,
+            unbounded: unbounded_count,
+        }
+    }
+
+    // the nearest hit on any instance. the direction isn't normalized in object space, so the
+    // ray parameter is the same in both spaces
+    pub fn hit(
+        &self,
+        primitives: &Primitives,
+        triangles: &Triangles,
+        origin: Vec3,
+        direction: Vec3,
+    ) -> Option<f32> {
+        self.instances
+            .iter()
+            .filter_map(|instance| {
+                let world_to_object = instance.world_to_object();
+                let origin = world_to_object.transform_point3(origin);
+                let direction = world_to_object.transform_vector3(direction);
+                if instance.is_mesh() {
+                    triangles.hit(instance.index as usize, origin, direction)
+                } else {
+                    let primitive = primitives.primitives.get(instance.index as usize)?;
+                    primitive.hit(origin, direction)
+                }
+            })
+            .min_by(|a, b| a.total_cmp(b))
+    }
+}
+
+fn transformed_bounds(bounds: Aabb, object_to_world: Mat4) -> Aabb {
+    let mut world = Aabb::EMPTY;
+    for corner in 0..8 {
+        let pick = |bit: usize, axis: usize| {
+            if corner & bit == 0 {
+                bounds.min[axis]
+            } else {
+                bounds.max[axis]
+            }
+        };
+        let point = Vec3::new(pick(1, 0), pick(2, 1), pick(4, 2));
+        world.grow(object_to_world.transform_point3(point));
+    }
+    world
+}
+
+pub fn update_instance_bvh(
+    instances: Res<Instances>,
+    primitives: Res<Primitives>,
+    triangles: Res<Triangles>,
+    mut instance_bvh: ResMut<InstanceBvh>,
+) {
+    if !instances.is_changed() && !primitives.is_changed() && !triangles.is_changed() {
+        return;
+    }
+    *instance_bvh = InstanceBvh::build(&instances, &primitives, &triangles);
+}
+
+// the instances on the gpu, with mesh indices swapped for the root node of their model
+#[derive(Resource)]
+pub struct InstanceBuffer {
+    pub buffer: SceneArray<GpuInstance>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::collidables::{Material, Primitive, Shape};
+
+    fn transform() -> Transform {
+        Transform::from_xyz(3., -1., 2.)
+            .with_rotation(Quat::from_euler(EulerRot::YXZ, 0.7, -0.4, 1.1))
+            .with_scale(Vec3::new(2., 0.5, 1.5))
+    }
+
+    fn contains(bounds: &Aabb, point: Vec3) -> bool {
+        let slack = Vec3::splat(1e-4);
+        (bounds.min - slack).cmple(point).all() && (bounds.max + slack).cmpge(point).all()
+    }
+
+    // points all over the surface of a sphere or the rims of a cylinder, in object space
+    fn surface(primitive: &Primitive) -> Vec<Vec3> {
+        let origin = Vec3::from(primitive.origin);
+        let (axis_a, axis_b) = Vec3::from(primitive.u)
+            .normalize_or_zero()
+            .any_orthonormal_pair();
+        let mut points = vec![];
+        for i in 0..32 {
+            let phi = i as f32 / 32. * std::f32::consts::TAU;
+            for j in 0..=16 {
+                let theta = j as f32 / 16. * std::f32::consts::PI;
+                let direction = Vec3::new(
+                    theta.sin() * phi.cos(),
+                    theta.cos(),
+                    theta.sin() * phi.sin(),
+                );
+                match primitive.kind() {
+                    Shape::Sphere => points.push(origin + direction * primitive.radius),
+                    Shape::Cylinder => {
+                        let rim = (axis_a * phi.cos() + axis_b * phi.sin()) * primitive.radius;
+                        points.push(origin + rim);
+                        points.push(origin + Vec3::from(primitive.u) + rim);
+                    }
+                    _ => unreachable!(),
+                }
+            }
+        }
+        points
+    }
+
+    #[test]
+    fn rotated_and_scaled_bounds_contain_the_primitive() {
+        let primitives = [
+            Primitive::sphere([0.5, 0., -1.], 1.5, [1.; 3], Material::Lambertian),
+            Primitive::cylinder([0., 0., 0.], [0., 2., 1.], 0.5, [1.; 3], Material::Metal),
+        ];
+        for primitive in primitives {
+            let object_to_world = transform().compute_matrix();
+            let bounds = transformed_bounds(primitive.bounds().unwrap(), object_to_world);
+            for point in surface(&primitive) {
+                let point = object_to_world.transform_point3(point);
+                assert!(contains(&bounds, point), "{:?} outside {:?}", point, bounds);
+            }
+        }
+    }
+
+    #[test]
+    fn world_to_object_undoes_the_transform() {
+        let object_to_world = transform().compute_matrix();
+        let instance = GpuInstance::new(
+            Geometry::Primitive(0),
+            object_to_world.inverse(),
+            &Triangles::default(),
+        );
+        let round_trip = instance.world_to_object() * object_to_world;
+        assert!(
+            round_trip.abs_diff_eq(Mat4::IDENTITY, 1e-5),
+            "{:?}",
+            round_trip
+        );
+    }
+
+    #[test]
+    fn planes_come_first_and_primitives_are_shared() {
+        let primitives = Primitives {
+            primitives: vec![
+                Primitive::sphere([0.; 3], 1., [1.; 3], Material::Lambertian),
+                Primitive::plane([0.; 3], [0., 1., 0.], [1.; 3], Material::Lambertian),
+            ],
+        };
+        let place = |geometry, x| Instance {
+            geometry,
+            transform: Transform::from_xyz(x, 0., 0.),
+        };
+        let instances = Instances {
+            instances: vec![
+                place(Geometry::Primitive(0), -5.),
+                place(Geometry::Primitive(1), 0.),
+                place(Geometry::Primitive(0), 5.),
+                // squashed flat, so it is left out
+                Instance {
+                    geometry: Geometry::Primitive(0),
+                    transform: Transform::from_scale(Vec3::new(1., 0., 1.)),
+                },
+                place(Geometry::Primitive(2), 0.),
+            ],
+        };
+
+        let instance_bvh = InstanceBvh::build(&instances, &primitives, &Triangles::default());
+        assert_eq!(instance_bvh.unbounded, 1);
+        assert_eq!(instance_bvh.instances.len(), 3);
+        assert_eq!(instance_bvh.instances[0].index, 1);
+        assert!(instance_bvh.instances[1..].iter().all(|i| i.index == 0));
+        assert_eq!(instance_bvh.bvh.order.len(), 2);
+
+        // one sphere, hit through either of its instances
+        let triangles = Triangles::default();
+        let from_left =
+            instance_bvh.hit(&primitives, &triangles, Vec3::new(-10., 0.5, 0.), Vec3::X);
+        let from_right = instance_bvh.hit(
+            &primitives,
+            &triangles,
+            Vec3::new(10., 0.5, 0.),
+            Vec3::NEG_X,
+        );
+        assert!(from_left.is_some_and(|t| (t - (5. - 0.75f32.sqrt())).abs() < 1e-4));
+        assert!(from_right.is_some_and(|t| (t - (5. - 0.75f32.sqrt())).abs() < 1e-4));
+    }
+}
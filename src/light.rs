@@ -0,0 +1,373 @@
+use bevy::{
+    prelude::*,
+    render::{extract_resource::ExtractResource, render_resource::ShaderType},
+};
+use bytemuck::{Pod, Zeroable};
+
+use crate::{
+    collidables::{Material, Primitive, Shape, Sphere},
+    instance::GpuInstance,
+    objects::RtLight,
+    storage::SceneArray,
+};
+
+// matches the light constants in simple.wgsl
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LightKind {
+    Point = 0,
+    Spot = 1,
+    Directional = 2,
+}
+
+impl LightKind {
+    pub const ALL: [LightKind; 3] = [LightKind::Point, LightKind::Spot, LightKind::Directional];
+
+    pub fn from_index(index: i32) -> Self {
+        match index {
+            1 => LightKind::Spot,
+            2 => LightKind::Directional,
+            _ => LightKind::Point,
+        }
+    }
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            LightKind::Point => "Point",
+            LightKind::Spot => "Spot",
+            LightKind::Directional => "Directional",
+        }
+    }
+}
+
+// a light with no size, rays can't hit it so the shader samples it at every diffuse hit instead.
+// point and spot intensities are per steradian and fall off with the distance squared, a
+// directional light's is the irradiance it gives a surface facing it
+#[derive(ShaderType, Pod, Zeroable, Clone, Copy, Default, Debug, PartialEq)]
+#[repr(C)]
+pub struct Light {
+    pub position: [f32; 3], // unused by directional lights
+    pub kind: i32,
+    pub direction: [f32; 3], // the way the light travels, unused by point lights
+    pub intensity: f32,
+    pub color: [f32; 3],
+    pub inner_angle: f32, // radians from the axis, spots are at full strength inside it
+    pub outer_angle: f32, // and fade to nothing at this one
+    _padding: [f32; 3],
+}
+
+impl Light {
+    pub fn point(position: [f32; 3], color: [f32; 3], intensity: f32) -> Self {
+        Light {
+            position,
+            kind: LightKind::Point as i32,
+            color,
+            intensity,
+            direction: [0., -1., 0.],
+            ..default()
+        }
+    }
+
+    pub fn spot(
+        position: [f32; 3],
+        direction: [f32; 3],
+        color: [f32; 3],
+        intensity: f32,
+        inner_angle: f32,
+        outer_angle: f32,
+    ) -> Self {
+        Light {
+            position,
+            kind: LightKind::Spot as i32,
+            direction,
+            intensity,
+            color,
+            inner_angle,
+            outer_angle,
+            ..default()
+        }
+    }
+
+    pub fn directional(direction: [f32; 3], color: [f32; 3], intensity: f32) -> Self {
+        Light {
+            kind: LightKind::Directional as i32,
+            direction,
+            intensity,
+            color,
+            ..default()
+        }
+    }
+
+    pub fn from_parts(light: &RtLight, transform: &Transform) -> Self {
+        Light {
+            position: transform.translation.to_array(),
+            kind: light.kind as i32,
+            direction: (transform.rotation * Vec3::NEG_Z).to_array(),
+            intensity: light.intensity,
+            color: light.color,
+            inner_angle: light.inner_angle,
+            outer_angle: light.outer_angle,
+            _padding: [0.; 3],
+        }
+    }
+
+    pub fn kind(&self) -> LightKind {
+        LightKind::from_index(self.kind)
+    }
+}
+
+// the rotation that turns -z, the way bevy points lights and cameras, along direction. there's no
+// roll, so it is just a pitch and a yaw and works straight up and down too
+pub fn aim(direction: Vec3) -> Quat {
+    let direction = direction.normalize_or_zero();
+    let pitch = direction.y.clamp(-1., 1.).asin();
+    let yaw = (-direction.x).atan2(-direction.z);
+    Quat::from_euler(EulerRot::YXZ, yaw, pitch, 0.)
+}
+
+// every light entity, filled in by gather_scene
+#[derive(Resource, ExtractResource, Clone, Default, Debug, PartialEq)]
+pub struct Lights {
+    pub lights: Vec<Light>,
+}
+
+#[derive(Resource)]
+pub struct LightBuffer {
+    pub buffer: SceneArray<Light>,
+}
+
+// an emissive sphere or quad in world space, which the shader can pick points on to sample it
+// directly as well as finding it by chance
+#[derive(ShaderType, Pod, Zeroable, Clone, Copy, Default, Debug, PartialEq)]
+#[repr(C)]
+pub struct Emitter {
+    pub origin: [f32; 3], // the center of a sphere or the corner of a quad
+    pub shape: i32,       // Shape::Sphere or Shape::Quad
+    pub u: [f32; 3],
+    pub radius: f32,
+    pub v: [f32; 3],
+    pub area: f32,
+    pub emission: [f32; 3], // color times intensity
+    _padding: f32,
+}
+
+impl Emitter {
+    fn sphere(center: Vec3, radius: f32, color: [f32; 4], intensity: f32) -> Self {
+        Emitter {
+            origin: center.to_array(),
+            shape: Shape::Sphere as i32,
+            radius,
+            area: 4. * std::f32::consts::PI * radius * radius,
+            emission: [color[0], color[1], color[2]].map(|c| c * intensity),
+            ..default()
+        }
+    }
+
+    fn quad(corner: Vec3, u: Vec3, v: Vec3, color: [f32; 4], intensity: f32) -> Self {
+        Emitter {
+            origin: corner.to_array(),
+            shape: Shape::Quad as i32,
+            u: u.to_array(),
+            v: v.to_array(),
+            area: u.cross(v).length(),
+            emission: [color[0], color[1], color[2]].map(|c| c * intensity),
+            ..default()
+        }
+    }
+
+    // only quads and spheres that stay round, other shapes are still lit when rays find them
+    fn from_instance(instance: &GpuInstance, primitives: &[Primitive]) -> Option<Self> {
+        if instance.is_mesh() {
+            return None;
+        }
+        let primitive = primitives.get(instance.index as usize)?;
+        if primitive.material != Material::Emissive as i32 {
+            return None;
+        }
+
+        let object_to_world = instance.world_to_object().inverse();
+        let origin = object_to_world.transform_point3(Vec3::from(primitive.origin));
+        match primitive.kind() {
+            Shape::Quad => Some(Emitter::quad(
+                origin,
+                object_to_world.transform_vector3(Vec3::from(primitive.u)),
+                object_to_world.transform_vector3(Vec3::from(primitive.v)),
+                primitive.color,
+                primitive.intensity,
+            )),
+            Shape::Sphere => {
+                let axes = [Vec3::X, Vec3::Y, Vec3::Z]
+                    .map(|axis| object_to_world.transform_vector3(axis).length());
+                let scale = axes[0];
+                let uniform = axes.iter().all(|axis| (axis - scale).abs() <= 1e-4 * scale);
+                uniform.then(|| {
+                    Emitter::sphere(
+                        origin,
+                        primitive.radius.abs() * scale,
+                        primitive.color,
+                        primitive.intensity,
+                    )
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+// the emissive spheres and instances, which are told where they are in the list so a ray that
+// hits one can work out how likely sampling the emitters was to find it. their emitter field is
+// one past that index, 0 means they aren't in it
+pub fn collect_emitters(
+    spheres: &mut [Sphere],
+    instances: &mut [GpuInstance],
+    primitives: &[Primitive],
+) -> Vec<Emitter> {
+    let mut emitters = vec![];
+    for sphere in spheres {
+        if sphere.material == Material::Emissive as i32 {
+            emitters.push(Emitter::sphere(
+                Vec3::from(sphere.center),
+                sphere.radius.abs(),
+                sphere.color,
+                sphere.intensity,
+            ));
+            sphere.emitter = emitters.len() as u32;
+        }
+    }
+    for instance in instances {
+        if let Some(emitter) = Emitter::from_instance(instance, primitives) {
+            emitters.push(emitter);
+            instance.emitter = emitters.len() as u32;
+        }
+    }
+    emitters
+}
+
+#[derive(Resource)]
+pub struct EmitterBuffer {
+    pub buffer: SceneArray<Emitter>,
+    pub count: u32,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        instance::Geometry,
+        mesh::{Model, Triangles},
+    };
+
+    fn instance(geometry: Geometry, transform: Transform, triangles: &Triangles) -> GpuInstance {
+        GpuInstance::new(geometry, transform.compute_matrix().inverse(), triangles)
+    }
+
+    #[test]
+    fn only_emissive_spheres_are_collected() {
+        let mut spheres = [
+            Sphere::new([0.; 3], 1., [1.; 3], Material::Lambertian),
+            Sphere::new([1., 2., 3.], -2., [1., 0.5, 0.], Material::Emissive).with_intensity(4.),
+        ];
+        let emitters = collect_emitters(&mut spheres, &mut [], &[]);
+
+        assert_eq!(emitters.len(), 1);
+        assert_eq!(emitters[0].origin, [1., 2., 3.]);
+        assert_eq!(emitters[0].radius, 2.);
+        assert_eq!(emitters[0].emission, [4., 2., 0.]);
+        assert_eq!(spheres[0].emitter, 0);
+        assert_eq!(spheres[1].emitter, 1);
+    }
+
+    #[test]
+    fn instanced_emitters_are_in_world_space() {
+        let primitives = [
+            Primitive::quad(
+                [0.; 3],
+                [1., 0., 0.],
+                [0., 0., 1.],
+                [1.; 3],
+                Material::Emissive,
+            ),
+            Primitive::sphere([0.; 3], 1., [1.; 3], Material::Emissive),
+        ];
+        let triangles = Triangles::default();
+        let mut spheres = [Sphere::new([0.; 3], 1., [1.; 3], Material::Emissive)];
+        let mut instances = [
+            instance(
+                Geometry::Primitive(0),
+                Transform::from_xyz(0., 5., 0.).with_scale(Vec3::new(2., 1., 3.)),
+                &triangles,
+            ),
+            instance(
+                Geometry::Primitive(1),
+                Transform::from_xyz(1., 0., 0.).with_scale(Vec3::splat(2.)),
+                &triangles,
+            ),
+        ];
+        let emitters = collect_emitters(&mut spheres, &mut instances, &primitives);
+
+        // the instances come after the spheres
+        assert_eq!(emitters.len(), 3);
+        assert_eq!(instances[0].emitter, 2);
+        assert_eq!(instances[1].emitter, 3);
+
+        let quad = emitters[1];
+        assert_eq!(quad.shape, Shape::Quad as i32);
+        assert!(Vec3::from(quad.origin).abs_diff_eq(Vec3::new(0., 5., 0.), 1e-5));
+        assert!(Vec3::from(quad.u).abs_diff_eq(Vec3::new(2., 0., 0.), 1e-5));
+        assert!(Vec3::from(quad.v).abs_diff_eq(Vec3::new(0., 0., 3.), 1e-5));
+        assert!((quad.area - 6.).abs() < 1e-5);
+
+        let sphere = emitters[2];
+        assert_eq!(sphere.shape, Shape::Sphere as i32);
+        assert!(Vec3::from(sphere.origin).abs_diff_eq(Vec3::X, 1e-5));
+        assert!((sphere.radius - 2.).abs() < 1e-5);
+    }
+
+    #[test]
+    fn stretched_spheres_and_other_shapes_are_skipped() {
+        let primitives = [
+            Primitive::sphere([0.; 3], 1., [1.; 3], Material::Emissive),
+            Primitive::disk([0.; 3], [0., 1., 0.], 1., [1.; 3], Material::Emissive),
+            Primitive::quad(
+                [0.; 3],
+                [1., 0., 0.],
+                [0., 0., 1.],
+                [1.; 3],
+                Material::Lambertian,
+            ),
+        ];
+        let triangles = Triangles {
+            models: vec![Model {
+                bvh: default(),
+                first: 0,
+                bounds: None,
+            }],
+            ..default()
+        };
+        let mut instances = [
+            instance(
+                Geometry::Primitive(0),
+                Transform::from_scale(Vec3::new(1., 2., 1.)),
+                &triangles,
+            ),
+            instance(Geometry::Primitive(1), Transform::IDENTITY, &triangles),
+            instance(Geometry::Primitive(2), Transform::IDENTITY, &triangles),
+            instance(Geometry::Mesh(0), Transform::IDENTITY, &triangles),
+        ];
+        let emitters = collect_emitters(&mut [], &mut instances, &primitives);
+
+        assert!(emitters.is_empty());
+        assert!(instances.iter().all(|instance| instance.emitter == 0));
+    }
+
+    #[test]
+    fn rotated_spheres_stay_round() {
+        let primitives = [Primitive::sphere([0.; 3], 0.5, [1.; 3], Material::Emissive)];
+        let transform =
+            Transform::from_rotation(Quat::from_rotation_z(0.6)).with_scale(Vec3::splat(3.));
+        let mut instances = [instance(Geometry::Primitive(0), transform, &default())];
+        let emitters = collect_emitters(&mut [], &mut instances, &primitives);
+
+        assert_eq!(emitters.len(), 1);
+        assert!((emitters[0].radius - 1.5).abs() < 1e-4);
+    }
+}
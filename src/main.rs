@@ -1,11 +1,26 @@
-use bevy::{prelude::*, render::render_resource::*};
+use bevy::{asset::ChangeWatcher, prelude::*, render::render_resource::*};
 use egui_menu::Menu;
+use export::ExportPlugin;
+use mesh::MeshPlugin;
 use render::{ComputeShaderPlugin, RenderImage};
+use scene::ScenePlugin;
+use std::time::Duration;
 
+pub mod bvh;
 pub mod camera;
 pub mod collidables;
 pub mod egui_menu;
+pub mod environment;
+pub mod export;
+pub mod gltf_import;
+pub mod instance;
+pub mod light;
+pub mod mesh;
+pub mod obj;
+pub mod objects;
 pub mod render;
+pub mod scene;
+pub mod storage;
 
 #[derive(States, Debug, Default, Clone, Eq, PartialEq, Hash)]
 enum AppState {
@@ -22,7 +37,18 @@ const INIT_WORKGROUP_SIZE: u32 = 8;
 fn main() {
     let mut app = App::new();
     app.add_state::<AppState>()
-        .add_plugins((DefaultPlugins, ComputeShaderPlugin, Menu))
+        .add_plugins((
+            // scene files are re-applied when they change on disk
+            DefaultPlugins.set(AssetPlugin {
+                watch_for_changes: ChangeWatcher::with_delay(Duration::from_millis(200)),
+                ..default()
+            }),
+            ComputeShaderPlugin,
+            ExportPlugin,
+            MeshPlugin,
+            ScenePlugin,
+            Menu,
+        ))
         .add_systems(Startup, setup);
     app.run();
 }
@@ -0,0 +1,430 @@
+use bevy::{
+    asset::{Asset, HandleId},
+    gltf::Gltf,
+    pbr::StandardMaterial,
+    prelude::*,
+    reflect::{TypePath, TypeUuid},
+    render::{extract_resource::ExtractResource, render_resource::ShaderType},
+};
+use bytemuck::{Pod, Zeroable};
+
+use crate::{
+    bvh::{Aabb, Bvh},
+    camera::Camera,
+    collidables::Material,
+    gltf_import::{gltf_extras, gltf_meshes, GltfAssets},
+    obj::ObjLoader,
+    objects::light_bundle,
+};
+
+#[derive(ShaderType, Pod, Zeroable, Clone, Copy, Default, Debug, PartialEq)]
+#[repr(C)]
+pub struct Vertex {
+    pub position: [f32; 3],
+    pub u: f32,
+    pub normal: [f32; 3], // zero when the mesh has no normals, the shader uses the face normal
+    pub v: f32,
+}
+
+// indices into the vertex buffer and the material buffer
+#[derive(ShaderType, Pod, Zeroable, Clone, Copy, Default, Debug, PartialEq)]
+#[repr(C)]
+pub struct Triangle {
+    pub indices: [u32; 3],
+    pub material: u32,
+}
+
+// the same fields a Sphere carries, shared by every triangle of a mesh
+#[derive(ShaderType, Pod, Zeroable, Clone, Copy, Default, Debug, PartialEq)]
+#[repr(C)]
+pub struct SurfaceMaterial {
+    pub color: [f32; 4],
+    pub material: i32,
+    pub fuzz: f32,
+    pub ior: f32,
+    pub intensity: f32,
+}
+
+impl SurfaceMaterial {
+    pub fn new(color: [f32; 3], material: Material) -> Self {
+        SurfaceMaterial {
+            color: [color[0], color[1], color[2], 1.0],
+            material: material as i32,
+            fuzz: 0.,
+            ior: 1.5,
+            intensity: 1.,
+        }
+    }
+
+    pub fn with_fuzz(mut self, fuzz: f32) -> Self {
+        self.fuzz = fuzz;
+        self
+    }
+
+    pub fn with_ior(mut self, ior: f32) -> Self {
+        self.ior = ior;
+        self
+    }
+
+    pub fn with_intensity(mut self, intensity: f32) -> Self {
+        self.intensity = intensity;
+        self
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct TriangleMesh {
+    pub name: String,
+    pub vertices: Vec<Vertex>,
+    pub indices: Vec<[u32; 3]>,
+    pub material: SurfaceMaterial,
+}
+
+// everything in one OBJ file, split into a mesh per object and material
+#[derive(TypeUuid, TypePath, Debug, Clone)]
+#[uuid = "0c6a3f4e-2d7b-4b8e-a1f9-3e5d7c9b2a64"]
+pub struct ObjAsset {
+    pub meshes: Vec<TriangleMesh>,
+}
+
+#[derive(Debug, Clone)]
+pub enum MeshSource {
+    Obj(Handle<ObjAsset>),
+    Gltf(Handle<Gltf>),
+}
+
+impl MeshSource {
+    pub fn id(&self) -> HandleId {
+        match self {
+            MeshSource::Obj(handle) => handle.id(),
+            MeshSource::Gltf(handle) => handle.id(),
+        }
+    }
+}
+
+// a model file loaded for the RtMesh entities with its path, paths are relative to the assets
+// folder
+#[derive(Debug, Clone)]
+pub struct MeshFile {
+    pub path: String,
+    pub source: MeshSource,
+    // take the camera and lights from the file once it has loaded
+    import_extras: bool,
+}
+
+impl MeshFile {
+    pub fn load(path: &str, asset_server: &AssetServer) -> Self {
+        let is_gltf = path.ends_with(".gltf") || path.ends_with(".glb");
+        let source = if is_gltf {
+            MeshSource::Gltf(asset_server.load(path))
+        } else {
+            MeshSource::Obj(asset_server.load(path))
+        };
+
+        MeshFile {
+            path: path.to_string(),
+            source,
+            import_extras: false,
+        }
+    }
+}
+
+// one file per distinct RtMesh path, kept in step with the entities by gather_scene
+#[derive(Resource, Default, Debug)]
+pub struct Meshes {
+    pub files: Vec<MeshFile>,
+}
+
+impl Meshes {
+    // loads a file ahead of the entity that uses it, so a glTF file can also bring its camera and
+    // lights into the scene. scene files already have those, so gather_scene loads their models
+    // without them. a file that is already loaded is reused, gather_scene keeps one per path
+    pub fn import(&mut self, path: &str, asset_server: &AssetServer) {
+        let index = match self.files.iter().position(|file| file.path == path) {
+            Some(index) => index,
+            None => {
+                self.files.push(MeshFile::load(path, asset_server));
+                self.files.len() - 1
+            }
+        };
+        let file = &mut self.files[index];
+        file.import_extras = matches!(file.source, MeshSource::Gltf(_));
+    }
+}
+
+// one model file, its triangles are a contiguous range in bvh order starting at first
+#[derive(Clone, Default, Debug)]
+pub struct Model {
+    pub bvh: Bvh,
+    pub first: u32,
+    pub bounds: Option<Aabb>, // none until the file has loaded
+}
+
+// every loaded mesh flattened into the buffers the shader reads, with a bvh per model file so
+// instances can share it
+#[derive(Resource, ExtractResource, Clone, Default, Debug)]
+pub struct Triangles {
+    pub vertices: Vec<Vertex>,
+    pub triangles: Vec<Triangle>,
+    pub materials: Vec<SurfaceMaterial>,
+    pub models: Vec<Model>, // in the same order as Meshes::files
+}
+
+impl Triangles {
+    fn build(files: &[Vec<TriangleMesh>]) -> Self {
+        let mut vertices = vec![];
+        let mut triangles = vec![];
+        let mut materials = vec![];
+        let mut models = vec![];
+
+        for meshes in files {
+            let mut model_triangles = vec![];
+            for mesh in meshes {
+                let offset = vertices.len() as u32;
+                let material = materials.len() as u32;
+                vertices.extend_from_slice(&mesh.vertices);
+                materials.push(mesh.material);
+                model_triangles.extend(mesh.indices.iter().map(|indices| Triangle {
+                    indices: indices.map(|i| i + offset),
+                    material,
+                }));
+            }
+
+            let bounds: Vec<Aabb> = model_triangles
+                .iter()
+                .map(|triangle| {
+                    triangle.indices.iter().fold(Aabb::EMPTY, |mut acc, &i| {
+                        acc.grow(Vec3::from(vertices[i as usize].position));
+                        acc
+                    })
+                })
+                .collect();
+            let bvh = Bvh::build(&bounds);
+            let first = triangles.len() as u32;
+            triangles.extend(bvh.order.iter().map(|&i| model_triangles[i as usize]));
+
+            models.push(Model {
+                bvh,
+                first,
+                bounds: bounds.into_iter().reduce(|a, b| a.union(&b)),
+            });
+        }
+
+        Triangles {
+            vertices,
+            triangles,
+            materials,
+            models,
+        }
+    }
+
+    pub fn count(&self) -> usize {
+        self.triangles.len()
+    }
+
+    // the nearest hit on a model in its own space, for picking on the cpu
+    pub fn hit(&self, model: usize, origin: Vec3, direction: Vec3) -> Option<f32> {
+        let model = self.models.get(model)?;
+        model.bvh.hit(origin, direction, |i| {
+            self.hit_triangle(model.first as usize + i, origin, direction)
+        })
+    }
+
+    // same as hit_triangle in simple.wgsl
+    fn hit_triangle(&self, index: usize, origin: Vec3, direction: Vec3) -> Option<f32> {
+        let [v0, v1, v2] = self.triangles[index]
+            .indices
+            .map(|i| Vec3::from(self.vertices[i as usize].position));
+        let edge1 = v1 - v0;
+        let edge2 = v2 - v0;
+        let p = direction.cross(edge2);
+        let determinant = edge1.dot(p);
+        if determinant.abs() < 1e-8 {
+            return None;
+        }
+
+        let inverse_determinant = 1. / determinant;
+        let s = origin - v0;
+        let b1 = s.dot(p) * inverse_determinant;
+        if !(0. ..=1.).contains(&b1) {
+            return None;
+        }
+        let q = s.cross(edge1);
+        let b2 = direction.dot(q) * inverse_determinant;
+        if b2 < 0. || b1 + b2 > 1. {
+            return None;
+        }
+
+        let t = edge2.dot(q) * inverse_determinant;
+        (t > 0.001).then_some(t)
+    }
+}
+
+pub struct MeshPlugin;
+impl Plugin for MeshPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_asset::<ObjAsset>()
+            .init_asset_loader::<ObjLoader>()
+            .init_resource::<Meshes>()
+            .init_resource::<Triangles>()
+            .add_systems(Update, import_gltf_extras)
+            .add_systems(PostUpdate, update_triangles);
+    }
+}
+
+// meshes don't move, so the bvh is only rebuilt when a file is added, removed or reloaded
+pub fn update_triangles(
+    meshes: Res<Meshes>,
+    objs: Res<Assets<ObjAsset>>,
+    gltfs: Res<Assets<Gltf>>,
+    scenes: Res<Assets<Scene>>,
+    bevy_meshes: Res<Assets<Mesh>>,
+    materials: Res<Assets<StandardMaterial>>,
+    mut obj_events: EventReader<AssetEvent<ObjAsset>>,
+    mut gltf_events: EventReader<AssetEvent<Gltf>>,
+    mut triangles: ResMut<Triangles>,
+) {
+    // only the files the scene uses matter, other models loading or changing leave it alone
+    let ours = |id: HandleId| meshes.files.iter().any(|file| file.source.id() == id);
+    // count reads every event, so none are left over for the next frame
+    let objs_reloaded = obj_events
+        .iter()
+        .filter(|event| ours(event_handle(event).id()))
+        .count();
+    let gltfs_reloaded = gltf_events
+        .iter()
+        .filter(|event| ours(event_handle(event).id()))
+        .count();
+    if !meshes.is_changed() && objs_reloaded + gltfs_reloaded == 0 {
+        return;
+    }
+
+    let gltf_assets = GltfAssets {
+        scenes: &scenes,
+        meshes: &bevy_meshes,
+        materials: &materials,
+    };
+    // files that are still loading stay empty, so models line up with the files
+    let loaded: Vec<Vec<TriangleMesh>> = meshes
+        .files
+        .iter()
+        .map(|file| match &file.source {
+            MeshSource::Obj(handle) => objs.get(handle).map_or(vec![], |obj| obj.meshes.clone()),
+            MeshSource::Gltf(handle) => gltfs
+                .get(handle)
+                .map_or(vec![], |gltf| gltf_meshes(gltf, &gltf_assets)),
+        })
+        .collect();
+    *triangles = Triangles::build(&loaded);
+}
+
+fn event_handle<T: Asset>(event: &AssetEvent<T>) -> &Handle<T> {
+    match event {
+        AssetEvent::Created { handle }
+        | AssetEvent::Modified { handle }
+        | AssetEvent::Removed { handle } => handle,
+    }
+}
+
+fn import_gltf_extras(
+    mut meshes: ResMut<Meshes>,
+    gltfs: Res<Assets<Gltf>>,
+    scenes: Res<Assets<Scene>>,
+    bevy_meshes: Res<Assets<Mesh>>,
+    materials: Res<Assets<StandardMaterial>>,
+    mut camera: ResMut<Camera>,
+    mut commands: Commands,
+) {
+    let gltf_assets = GltfAssets {
+        scenes: &scenes,
+        meshes: &bevy_meshes,
+        materials: &materials,
+    };
+
+    // bypass so waiting for the file doesn't rebuild the triangles every frame
+    for file in meshes.bypass_change_detection().files.iter_mut() {
+        let MeshSource::Gltf(handle) = &file.source else {
+            continue;
+        };
+        if !file.import_extras {
+            continue;
+        }
+        let Some(gltf) = gltfs.get(handle) else {
+            continue;
+        };
+        file.import_extras = false;
+
+        let extras = gltf_extras(gltf, &gltf_assets);
+        if let Some(gltf_camera) = extras.camera {
+            let mut new_camera = Camera::look_at(
+                gltf_camera.look_from.into(),
+                gltf_camera.look_at.into(),
+                gltf_camera.vup.into(),
+                gltf_camera.vfov,
+            );
+            new_camera.defocus_angle = camera.defocus_angle;
+            new_camera.focus_dist = camera.focus_dist;
+            new_camera.update_viewport();
+            *camera = new_camera;
+        }
+        for light in &extras.lights {
+            commands.spawn(light_bundle(light));
+        }
+        info!("imported camera and lights from {}", file.path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn importing_a_loaded_file_reuses_it() {
+        let mut app = App::new();
+        app.add_plugins((MinimalPlugins, AssetPlugin::default()));
+        let asset_server = app.world.resource::<AssetServer>();
+
+        let mut meshes = Meshes::default();
+        meshes
+            .files
+            .push(MeshFile::load("models/scene.gltf", asset_server));
+        meshes
+            .files
+            .push(MeshFile::load("models/cube.obj", asset_server));
+        meshes.import("models/scene.gltf", asset_server);
+        meshes.import("models/cube.obj", asset_server);
+
+        assert_eq!(meshes.files.len(), 2);
+        assert!(meshes.files[0].import_extras);
+        // obj files have no camera or lights
+        assert!(!meshes.files[1].import_extras);
+    }
+
+    #[test]
+    fn hit_goes_through_each_model() {
+        // a unit square at z = 0 split into two triangles, and the same square at z = -1
+        let square = |z: f32| TriangleMesh {
+            name: "square".to_string(),
+            vertices: [[0., 0.], [1., 0.], [1., 1.], [0., 1.]]
+                .map(|[x, y]| Vertex {
+                    position: [x, y, z],
+                    ..default()
+                })
+                .to_vec(),
+            indices: vec![[0, 1, 2], [0, 2, 3]],
+            material: SurfaceMaterial::new([1.; 3], Material::Lambertian),
+        };
+        let triangles = Triangles::build(&[vec![square(0.)], vec![square(-1.), square(0.)]]);
+
+        let origin = Vec3::new(0.25, 0.75, 2.);
+        assert_eq!(triangles.hit(0, origin, Vec3::NEG_Z), Some(2.));
+        assert_eq!(triangles.hit(1, origin, Vec3::NEG_Z), Some(2.));
+        assert_eq!(
+            triangles.hit(1, origin - Vec3::Z * 2.5, Vec3::NEG_Z),
+            Some(0.5)
+        );
+        assert_eq!(triangles.hit(0, origin, Vec3::Z), None);
+        assert_eq!(triangles.hit(0, Vec3::new(1.5, 0.5, 2.), Vec3::NEG_Z), None);
+        assert_eq!(triangles.hit(2, origin, Vec3::NEG_Z), None);
+    }
+}
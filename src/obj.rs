@@ -0,0 +1,392 @@
+use bevy::{
+    asset::{AssetLoader, AssetPath, LoadContext, LoadedAsset},
+    prelude::*,
+    utils::{BoxedFuture, HashMap},
+};
+
+use crate::{
+    collidables::Material,
+    mesh::{ObjAsset, SurfaceMaterial, TriangleMesh, Vertex},
+};
+
+// Wavefront OBJ with its MTL libraries, only the parts a ray tracer cares about
+#[derive(Default)]
+pub struct ObjLoader;
+
+impl AssetLoader for ObjLoader {
+    fn load<'a>(
+        &'a self,
+        bytes: &'a [u8],
+        load_context: &'a mut LoadContext,
+    ) -> BoxedFuture<'a, Result<(), bevy::asset::Error>> {
+        Box::pin(async move {
+            let text = std::str::from_utf8(bytes)?;
+            let folder = load_context.path().parent().unwrap_or(load_context.path());
+
+            // material libraries are relative to the obj file
+            let mut materials = HashMap::new();
+            let mut dependencies = vec![];
+            for library in material_libraries(text) {
+                let path = folder.join(library);
+                match load_context.read_asset_bytes(&path).await {
+                    Ok(mtl) => {
+                        materials.extend(parse_mtl(&String::from_utf8_lossy(&mtl)));
+                        dependencies.push(AssetPath::from(path));
+                    }
+                    Err(err) => warn!("failed to read {}: {}", path.display(), err),
+                }
+            }
+
+            let mut asset = LoadedAsset::new(ObjAsset {
+                meshes: parse_obj(text, &materials),
+            });
+            for dependency in dependencies {
+                asset = asset.with_dependency(dependency);
+            }
+            load_context.set_default_asset(asset);
+            Ok(())
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["obj"]
+    }
+}
+
+fn material_libraries(text: &str) -> Vec<&str> {
+    text.lines()
+        .filter_map(|line| line.trim().strip_prefix("mtllib "))
+        .flat_map(|names| names.split_whitespace())
+        .collect()
+}
+
+fn default_material() -> SurfaceMaterial {
+    SurfaceMaterial::new([0.8, 0.8, 0.8], Material::Lambertian)
+}
+
+fn floats<const N: usize>(values: &[&str]) -> [f32; N] {
+    let mut out = [0.; N];
+    for (out, value) in out.iter_mut().zip(values) {
+        *out = value.parse().unwrap_or(0.);
+    }
+    out
+}
+
+// the last value, so options like `d -halo 0.5` are skipped. none if it is missing or not a
+// number, which keeps the default instead of reading it as 0
+fn last_float(values: &[&str]) -> Option<f32> {
+    values.last()?.parse().ok()
+}
+
+// one mesh per object and material, vertices are shared between faces when all of
+// position, uv and normal match
+struct MeshBuilder {
+    mesh: TriangleMesh,
+    lookup: HashMap<(usize, Option<usize>, Option<usize>), u32>,
+}
+
+impl MeshBuilder {
+    fn new(name: &str, material: SurfaceMaterial) -> Self {
+        MeshBuilder {
+            mesh: TriangleMesh {
+                name: name.to_string(),
+                vertices: vec![],
+                indices: vec![],
+                material,
+            },
+            lookup: HashMap::new(),
+        }
+    }
+
+    fn finish(self, meshes: &mut Vec<TriangleMesh>) {
+        if !self.mesh.indices.is_empty() {
+            meshes.push(self.mesh);
+        }
+    }
+}
+
+// obj indices start at 1, negative ones count back from the last element
+fn resolve(index: &str, len: usize) -> Option<usize> {
+    let index: i64 = index.parse().ok()?;
+    let resolved = if index < 0 {
+        len as i64 + index
+    } else {
+        index - 1
+    };
+    (0..len as i64)
+        .contains(&resolved)
+        .then_some(resolved as usize)
+}
+
+pub fn parse_obj(text: &str, materials: &HashMap<String, SurfaceMaterial>) -> Vec<TriangleMesh> {
+    let mut positions: Vec<[f32; 3]> = vec![];
+    let mut uvs: Vec<[f32; 2]> = vec![];
+    let mut normals: Vec<[f32; 3]> = vec![];
+
+    let mut meshes = vec![];
+    let mut name = String::from("default");
+    let mut material = default_material();
+    let mut builder = MeshBuilder::new(&name, material);
+
+    for line in text.lines() {
+        let mut tokens = line.split_whitespace();
+        let Some(keyword) = tokens.next() else {
+            continue;
+        };
+        let values: Vec<&str> = tokens.collect();
+
+        match keyword {
+            "v" => positions.push(floats(&values)),
+            "vt" => uvs.push(floats(&values)),
+            "vn" => normals.push(floats(&values)),
+            "o" | "g" => {
+                name = values.join(" ");
+                builder.finish(&mut meshes);
+                builder = MeshBuilder::new(&name, material);
+            }
+            "usemtl" => {
+                let material_name = values.join(" ");
+                material = materials.get(&material_name).copied().unwrap_or_else(|| {
+                    warn!("unknown material {}", material_name);
+                    default_material()
+                });
+                builder.finish(&mut meshes);
+                builder = MeshBuilder::new(&name, material);
+            }
+            "f" => {
+                let mut corners = vec![];
+                for corner in &values {
+                    let mut parts = corner.split('/');
+                    let Some(position) = parts.next().and_then(|i| resolve(i, positions.len()))
+                    else {
+                        continue;
+                    };
+                    let uv = parts.next().and_then(|i| resolve(i, uvs.len()));
+                    let normal = parts.next().and_then(|i| resolve(i, normals.len()));
+
+                    let key = (position, uv, normal);
+                    let index = *builder.lookup.entry(key).or_insert_with(|| {
+                        let uv = uv.map_or([0., 0.], |i| uvs[i]);
+                        builder.mesh.vertices.push(Vertex {
+                            position: positions[position],
+                            u: uv[0],
+                            normal: normal.map_or([0.; 3], |i| normals[i]),
+                            v: uv[1],
+                        });
+                        builder.mesh.vertices.len() as u32 - 1
+                    });
+                    corners.push(index);
+                }
+
+                // polygons are split into a fan around their first corner
+                for i in 1..corners.len().saturating_sub(1) {
+                    builder
+                        .mesh
+                        .indices
+                        .push([corners[0], corners[i], corners[i + 1]]);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    builder.finish(&mut meshes);
+    meshes
+}
+
+// the closest material this renderer has to each MTL definition
+#[derive(Default)]
+struct MtlMaterial {
+    diffuse: [f32; 3],
+    specular: [f32; 3],
+    emissive: [f32; 3],
+    transmission: Option<[f32; 3]>,
+    shininess: f32,
+    ior: Option<f32>,
+    dissolve: f32,
+    illum: i32,
+}
+
+impl MtlMaterial {
+    fn new() -> Self {
+        MtlMaterial {
+            diffuse: [0.8; 3],
+            dissolve: 1.,
+            illum: 2,
+            ..default()
+        }
+    }
+
+    fn to_surface(&self) -> SurfaceMaterial {
+        let emission = self.emissive.into_iter().fold(0., f32::max);
+        let transparent = self.dissolve < 1. || matches!(self.illum, 4 | 6 | 7 | 9);
+        let mirror = self.illum == 3 || self.illum == 5;
+
+        if emission > 0. {
+            SurfaceMaterial::new(self.emissive.map(|c| c / emission), Material::Emissive)
+                .with_intensity(emission)
+        } else if transparent {
+            SurfaceMaterial::new(self.transmission.unwrap_or([1.; 3]), Material::Dielectric)
+                .with_ior(self.ior.unwrap_or(1.5))
+        } else if mirror {
+            let color = if self.specular.iter().any(|c| *c > 0.) {
+                self.specular
+            } else {
+                self.diffuse
+            };
+            // phong exponents are roughly the inverse of fuzz
+            let fuzz = (2. / (self.shininess + 2.)).sqrt();
+            SurfaceMaterial::new(color, Material::Metal).with_fuzz(fuzz)
+        } else {
+            SurfaceMaterial::new(self.diffuse, Material::Lambertian)
+        }
+    }
+}
+
+pub fn parse_mtl(text: &str) -> HashMap<String, SurfaceMaterial> {
+    let mut materials = HashMap::new();
+    let mut current: Option<(String, MtlMaterial)> = None;
+
+    for line in text.lines() {
+        let mut tokens = line.split_whitespace();
+        let Some(keyword) = tokens.next() else {
+            continue;
+        };
+        let values: Vec<&str> = tokens.collect();
+
+        if keyword == "newmtl" {
+            if let Some((name, material)) = current.take() {
+                materials.insert(name, material.to_surface());
+            }
+            current = Some((values.join(" "), MtlMaterial::new()));
+            continue;
+        }
+        let Some((_, material)) = current.as_mut() else {
+            continue;
+        };
+
+        match keyword {
+            "Kd" => material.diffuse = floats(&values),
+            "Ks" => material.specular = floats(&values),
+            "Ke" => material.emissive = floats(&values),
+            "Tf" => material.transmission = Some(floats(&values)),
+            "Ns" => material.shininess = floats::<1>(&values)[0],
+            "Ni" => material.ior = Some(floats::<1>(&values)[0]),
+            "d" => {
+                if let Some(dissolve) = last_float(&values) {
+                    material.dissolve = dissolve;
+                }
+            }
+            "Tr" => {
+                if let Some(transparency) = last_float(&values) {
+                    material.dissolve = 1. - transparency;
+                }
+            }
+            "illum" => material.illum = values.first().and_then(|v| v.parse().ok()).unwrap_or(2),
+            _ => {}
+        }
+    }
+
+    if let Some((name, material)) = current {
+        materials.insert(name, material.to_surface());
+    }
+    materials
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn triangles(mesh: &TriangleMesh) -> Vec<[[f32; 3]; 3]> {
+        mesh.indices
+            .iter()
+            .map(|t| t.map(|i| mesh.vertices[i as usize].position))
+            .collect()
+    }
+
+    fn material(mtl: &str) -> SurfaceMaterial {
+        parse_mtl(&format!("newmtl test\n{}", mtl))["test"]
+    }
+
+    #[test]
+    fn negative_indices_count_back_from_the_last_vertex() {
+        let positive = "v 0 0 0\nv 1 0 0\nv 0 1 0\nf 1 2 3\n";
+        let negative = "v 0 0 0\nv 1 0 0\nv 0 1 0\nf -3 -2 -1\n";
+        let positive = parse_obj(positive, &HashMap::new());
+        let negative = parse_obj(negative, &HashMap::new());
+        assert_eq!(triangles(&positive[0]), triangles(&negative[0]));
+    }
+
+    #[test]
+    fn negative_indices_are_relative_to_where_the_face_is() {
+        let text = "v 0 0 0\nv 1 0 0\nv 0 1 0\nf -3 -2 -1\nv 5 5 5\n";
+        let meshes = parse_obj(text, &HashMap::new());
+        assert_eq!(
+            triangles(&meshes[0]),
+            vec![[[0., 0., 0.], [1., 0., 0.], [0., 1., 0.]]]
+        );
+    }
+
+    #[test]
+    fn out_of_range_corners_are_skipped() {
+        let text = "v 0 0 0\nv 1 0 0\nv 0 1 0\nf 1 2 3 9\nf 1 -7 2\n";
+        let meshes = parse_obj(text, &HashMap::new());
+        assert_eq!(meshes[0].indices.len(), 1);
+    }
+
+    #[test]
+    fn polygons_become_fans_around_the_first_corner() {
+        let text = "v 0 0 0\nv 1 0 0\nv 1 1 0\nv 0 1 0\nv -1 1 0\nf 1/1/1 2 3 4 5\n";
+        let meshes = parse_obj(text, &HashMap::new());
+        assert_eq!(meshes[0].indices, vec![[0, 1, 2], [0, 2, 3], [0, 3, 4]]);
+    }
+
+    #[test]
+    fn corners_share_vertices_only_when_every_index_matches() {
+        let text = "v 0 0 0\nv 1 0 0\nv 0 1 0\nvt 0 0\nvt 1 1\n\
+                    f 1/1 2/1 3/1\nf 1/1 3/1 2/2\n";
+        let meshes = parse_obj(text, &HashMap::new());
+        assert_eq!(meshes[0].vertices.len(), 4);
+    }
+
+    #[test]
+    fn usemtl_splits_meshes() {
+        let materials = parse_mtl("newmtl red\nKd 1 0 0\n");
+        let text = "v 0 0 0\nv 1 0 0\nv 0 1 0\nf 1 2 3\nusemtl red\nf 1 2 3\n";
+        let meshes = parse_obj(text, &materials);
+        assert_eq!(meshes.len(), 2);
+        assert_eq!(meshes[1].material.color, [1., 0., 0., 1.]);
+    }
+
+    #[test]
+    fn opaque_materials_stay_diffuse() {
+        for mtl in ["", "d\n", "d 1\n", "Tr 0\n", "Tr\n", "d -halo 1\n"] {
+            assert_eq!(
+                material(mtl).material,
+                Material::Lambertian as i32,
+                "{:?}",
+                mtl
+            );
+        }
+    }
+
+    #[test]
+    fn dissolve_and_transparency_make_glass() {
+        for mtl in ["d 0.5\n", "Tr 0.5\n", "d -halo 0.5\n", "illum 4\n"] {
+            assert_eq!(
+                material(mtl).material,
+                Material::Dielectric as i32,
+                "{:?}",
+                mtl
+            );
+        }
+    }
+
+    #[test]
+    fn emission_wins_over_everything_else() {
+        let material = material("Ke 0 4 2\nd 0.5\nillum 3\n");
+        assert_eq!(material.material, Material::Emissive as i32);
+        assert_eq!(material.color, [0., 1., 0.5, 1.]);
+        assert_eq!(material.intensity, 4.);
+    }
+}
@@ -0,0 +1,348 @@
+use bevy::{ecs::system::SystemParam, prelude::*, utils::HashMap};
+use bytemuck::bytes_of;
+use std::f32::consts::FRAC_PI_2;
+
+use crate::{
+    collidables::{Primitive, Primitives, Shape, Sphere, Spheres},
+    instance::{Geometry, Instance, Instances},
+    light::{aim, Light, LightKind, Lights},
+    mesh::{MeshFile, Meshes},
+};
+
+// the scene is made of entities with these components, gather_scene flattens them into the
+// resources that are uploaded to the gpu
+
+// a world space sphere centered on the translation, scale and rotation are ignored. a Sphere
+// primitive is the one to use for stretched or rotated spheres
+#[derive(Component, Clone, Copy, Debug, PartialEq)]
+pub struct RtSphere {
+    pub radius: f32, // a negative radius flips the normals, which makes a hollow dielectric
+}
+
+// in object space, the entity's transform places it in the world. see collidables::Primitive for
+// what origin, u, v and radius mean for each shape
+#[derive(Component, Clone, Copy, Debug, PartialEq)]
+pub struct RtPrimitive {
+    pub shape: Shape,
+    pub origin: [f32; 3],
+    pub u: [f32; 3],
+    pub v: [f32; 3],
+    pub radius: f32,
+}
+
+// a model file relative to the assets folder, entities with the same path share one bvh. the
+// materials come from the file
+#[derive(Component, Clone, Debug, PartialEq)]
+pub struct RtMesh {
+    pub path: String,
+}
+
+// for spheres and primitives, the fields match the ones on the gpu
+#[derive(Component, Clone, Copy, Debug, PartialEq)]
+pub struct RtMaterial {
+    pub color: [f32; 4],
+    pub material: i32,
+    pub fuzz: f32,
+    pub ior: f32,
+    pub intensity: f32,
+}
+
+// placed by the translation and pointed along the transform's -z, like bevy's own lights. see
+// light::Light for what the fields mean
+#[derive(Component, Clone, Copy, Debug, PartialEq)]
+pub struct RtLight {
+    pub kind: LightKind,
+    pub color: [f32; 3],
+    pub intensity: f32,
+    pub inner_angle: f32,
+    pub outer_angle: f32,
+}
+
+// moves along one axis with a sine of the render time, while SphereAnimation is enabled
+#[derive(Component, Clone, Copy, Debug, PartialEq)]
+pub struct Oscillation {
+    pub axis: usize,
+    pub phase: f32,
+}
+
+pub fn sphere_bundle(sphere: &Sphere) -> (RtSphere, RtMaterial, Transform) {
+    (
+        RtSphere {
+            radius: sphere.radius,
+        },
+        RtMaterial {
+            color: sphere.color,
+            material: sphere.material,
+            fuzz: sphere.fuzz,
+            ior: sphere.ior,
+            intensity: sphere.intensity,
+        },
+        Transform::from_translation(Vec3::from(sphere.center)),
+    )
+}
+
+pub fn primitive_bundle(
+    primitive: &Primitive,
+    transform: Transform,
+) -> (RtPrimitive, RtMaterial, Transform) {
+    (
+        RtPrimitive {
+            shape: primitive.kind(),
+            origin: primitive.origin,
+            u: primitive.u,
+            v: primitive.v,
+            radius: primitive.radius,
+        },
+        RtMaterial {
+            color: primitive.color,
+            material: primitive.material,
+            fuzz: primitive.fuzz,
+            ior: primitive.ior,
+            intensity: primitive.intensity,
+        },
+        transform,
+    )
+}
+
+pub fn mesh_bundle(path: &str, transform: Transform) -> (RtMesh, Transform) {
+    (
+        RtMesh {
+            path: path.to_string(),
+        },
+        transform,
+    )
+}
+
+pub fn light_bundle(light: &Light) -> (RtLight, Transform) {
+    (
+        RtLight {
+            kind: light.kind(),
+            color: light.color,
+            intensity: light.intensity,
+            inner_angle: light.inner_angle,
+            outer_angle: light.outer_angle,
+        },
+        Transform::from_translation(Vec3::from(light.position))
+            .with_rotation(aim(Vec3::from(light.direction))),
+    )
+}
+
+pub fn spawn_default_scene(mut commands: Commands) {
+    let oscillations = [(0, 0.), (0, FRAC_PI_2), (1, FRAC_PI_2)];
+    for (sphere, (axis, phase)) in Spheres::default_scene().spheres.iter().zip(oscillations) {
+        commands.spawn((sphere_bundle(sphere), Oscillation { axis, phase }));
+    }
+    for primitive in &Primitives::default_scene().primitives {
+        commands.spawn(primitive_bundle(primitive, Transform::IDENTITY));
+    }
+}
+
+// entities that lost a scene component or were despawned since the last gather
+#[derive(SystemParam)]
+pub struct RemovedScene<'w, 's> {
+    spheres: RemovedComponents<'w, 's, RtSphere>,
+    primitives: RemovedComponents<'w, 's, RtPrimitive>,
+    meshes: RemovedComponents<'w, 's, RtMesh>,
+    lights: RemovedComponents<'w, 's, RtLight>,
+    materials: RemovedComponents<'w, 's, RtMaterial>,
+    transforms: RemovedComponents<'w, 's, Transform>,
+}
+
+// reads every removal so it is only seen once
+fn any_removed<T: Component>(removed: &mut RemovedComponents<T>) -> bool {
+    removed.iter().count() > 0
+}
+
+// each part of the scene is only gathered again when one of its entities changed or went away,
+// and set_if_neq keeps the resources unchanged when that made no difference, so the bvhs aren't
+// rebuilt and the samples keep accumulating
+pub fn gather_scene(
+    sphere_query: Query<(Entity, Ref<RtSphere>, Ref<RtMaterial>, Ref<Transform>)>,
+    primitive_query: Query<(Entity, Ref<RtPrimitive>, Ref<RtMaterial>, Ref<Transform>)>,
+    mesh_query: Query<(Entity, Ref<RtMesh>, Ref<Transform>)>,
+    light_query: Query<(Entity, Ref<RtLight>, Ref<Transform>)>,
+    mut removed: RemovedScene,
+    mut spheres: ResMut<Spheres>,
+    mut primitives: ResMut<Primitives>,
+    mut meshes: ResMut<Meshes>,
+    mut instances: ResMut<Instances>,
+    mut lights: ResMut<Lights>,
+    asset_server: Res<AssetServer>,
+) {
+    // a lost material or transform takes the entity out of its query, whichever kind it was
+    let shared_removed = any_removed(&mut removed.materials) | any_removed(&mut removed.transforms);
+    let spheres_changed = any_removed(&mut removed.spheres)
+        | shared_removed
+        | sphere_query.iter().any(|(_, sphere, material, transform)| {
+            sphere.is_changed() || material.is_changed() || transform.is_changed()
+        });
+    let primitives_changed = any_removed(&mut removed.primitives)
+        | shared_removed
+        | primitive_query
+            .iter()
+            .any(|(_, primitive, material, transform)| {
+                primitive.is_changed() || material.is_changed() || transform.is_changed()
+            });
+    let meshes_changed = any_removed(&mut removed.meshes)
+        | shared_removed
+        | mesh_query
+            .iter()
+            .any(|(_, mesh, transform)| mesh.is_changed() || transform.is_changed());
+    let lights_changed = any_removed(&mut removed.lights)
+        | shared_removed
+        | light_query
+            .iter()
+            .any(|(_, light, transform)| light.is_changed() || transform.is_changed());
+
+    // queries don't promise an order, sort so the buffers don't shuffle when archetypes change
+    if spheres_changed {
+        let mut sphere_entities: Vec<_> = sphere_query.iter().collect();
+        sphere_entities.sort_by_key(|(entity, ..)| *entity);
+        spheres.set_if_neq(Spheres {
+            spheres: sphere_entities
+                .iter()
+                .map(|(_, sphere, material, transform)| {
+                    Sphere::from_parts(sphere, material, transform)
+                })
+                .collect(),
+        });
+    }
+
+    // primitives and models share the instance list, so either one gathers both
+    if primitives_changed || meshes_changed {
+        let mut primitive_entities: Vec<_> = primitive_query.iter().collect();
+        primitive_entities.sort_by_key(|(entity, ..)| *entity);
+        let mut mesh_entities: Vec<_> = mesh_query.iter().collect();
+        mesh_entities.sort_by_key(|(entity, ..)| *entity);
+
+        // entities with the same shape and material share one primitive, and each is an instance
+        // of it. the bytes are what reaches the gpu, so they are what has to match
+        let mut shared: Vec<Primitive> = vec![];
+        let mut shared_indices: HashMap<Vec<u8>, usize> = HashMap::new();
+        let mut primitive_indices = vec![];
+        for (_, primitive, material, _) in &primitive_entities {
+            let primitive = Primitive::from_parts(primitive, material);
+            let index = *shared_indices
+                .entry(bytes_of(&primitive).to_vec())
+                .or_insert_with(|| {
+                    shared.push(primitive);
+                    shared.len() - 1
+                });
+            primitive_indices.push(index);
+        }
+        primitives.set_if_neq(Primitives { primitives: shared });
+
+        // one file per path, files nothing uses anymore are dropped
+        let mut paths: Vec<&str> = vec![];
+        let mut path_indices: HashMap<&str, usize> = HashMap::new();
+        for (_, mesh, _) in &mesh_entities {
+            path_indices.entry(mesh.path.as_str()).or_insert_with(|| {
+                paths.push(&mesh.path);
+                paths.len() - 1
+            });
+        }
+        let loaded = meshes.files.iter().map(|file| file.path.as_str());
+        if !loaded.eq(paths.iter().copied()) {
+            let mut files = std::mem::take(&mut meshes.files);
+            for path in &paths {
+                let file = match files.iter().position(|file| file.path == *path) {
+                    Some(i) => files.remove(i),
+                    None => MeshFile::load(path, &asset_server),
+                };
+                meshes.files.push(file);
+            }
+        }
+
+        let primitive_instances =
+            primitive_entities
+                .iter()
+                .zip(&primitive_indices)
+                .map(|(entry, &index)| Instance {
+                    geometry: Geometry::Primitive(index),
+                    transform: *entry.3,
+                });
+        let mesh_instances = mesh_entities.iter().map(|(_, mesh, transform)| Instance {
+            geometry: Geometry::Mesh(path_indices[mesh.path.as_str()]),
+            transform: **transform,
+        });
+        instances.set_if_neq(Instances {
+            instances: primitive_instances.chain(mesh_instances).collect(),
+        });
+    }
+
+    if lights_changed {
+        let mut light_entities: Vec<_> = light_query.iter().collect();
+        light_entities.sort_by_key(|(entity, ..)| *entity);
+        lights.set_if_neq(Lights {
+            lights: light_entities
+                .iter()
+                .map(|(_, light, transform)| Light::from_parts(light, transform))
+                .collect(),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::collidables::Material;
+
+    fn app() -> App {
+        let mut app = App::new();
+        app.add_plugins((MinimalPlugins, AssetPlugin::default()))
+            .init_resource::<Spheres>()
+            .init_resource::<Primitives>()
+            .init_resource::<Meshes>()
+            .init_resource::<Instances>()
+            .init_resource::<Lights>()
+            .add_systems(Update, gather_scene);
+        app
+    }
+
+    #[test]
+    fn identical_primitives_share_one() {
+        let mut app = app();
+        let cube = Primitive::cuboid([-1.; 3], [1.; 3], [0.5; 3], Material::Lambertian);
+        for x in 0..3 {
+            let transform = Transform::from_xyz(x as f32, 0., 0.);
+            app.world.spawn(primitive_bundle(&cube, transform));
+        }
+        app.update();
+
+        assert_eq!(app.world.resource::<Primitives>().primitives, vec![cube]);
+        let instances = &app.world.resource::<Instances>().instances;
+        assert_eq!(instances.len(), 3);
+        assert!(instances
+            .iter()
+            .all(|instance| instance.geometry == Geometry::Primitive(0)));
+    }
+
+    #[test]
+    fn only_changes_are_gathered_again() {
+        let mut app = app();
+        let cube = Primitive::cuboid([-1.; 3], [1.; 3], [0.5; 3], Material::Lambertian);
+        let entity = app
+            .world
+            .spawn(primitive_bundle(&cube, Transform::IDENTITY))
+            .id();
+        app.update();
+
+        // nothing moved, so what is in the resource stays
+        app.world.resource_mut::<Primitives>().primitives.clear();
+        app.update();
+        assert!(app.world.resource::<Primitives>().primitives.is_empty());
+
+        app.world
+            .get_mut::<Transform>(entity)
+            .unwrap()
+            .translation
+            .x = 1.;
+        app.update();
+        assert_eq!(app.world.resource::<Primitives>().primitives, vec![cube]);
+
+        app.world.despawn(entity);
+        app.update();
+        assert!(app.world.resource::<Primitives>().primitives.is_empty());
+        assert!(app.world.resource::<Instances>().instances.is_empty());
+    }
+}
@@ -1,7 +1,19 @@
-use crate::{camera::Camera, collidables::*, AppState, INIT_WORKGROUP_SIZE, SIZE};
+use crate::{
+    bvh::{update_bvh, Bvh, BvhNode},
+    camera::{camera_controls, pick_focus, update_camera, Camera, CameraControls, FocusPicker},
+    collidables::*,
+    environment::{setup_environment, update_environment, Environment},
+    instance::{update_instance_bvh, InstanceBuffer, InstanceBvh, Instances},
+    light::{collect_emitters, EmitterBuffer, LightBuffer, Lights},
+    mesh::{update_triangles, SurfaceMaterial, Triangle, Triangles, Vertex},
+    objects::{gather_scene, spawn_default_scene},
+    storage::{AccumulationStorage, AccumulationTextures, SceneArray, SceneStorage},
+    AppState, INIT_WORKGROUP_SIZE, SIZE,
+};
 
 use bevy::{
     core::Zeroable,
+    ecs::system::SystemParam,
     prelude::*,
     render::{
         extract_resource::{ExtractResource, ExtractResourcePlugin},
@@ -20,8 +32,6 @@ pub struct RenderImage {
     pub image: Handle<Image>,
 }
 
-// todo: storage buffers for web
-
 // const NOISE_BUFFER_SIZE: u64 = 20;
 // #[derive(Resource, Clone, Deref, ExtractResource)]
 // pub struct NoiseBuffer {
@@ -42,21 +52,35 @@ enum ComputeShaderState {
     Update,
 }
 
-#[derive(ShaderType, Pod, Zeroable, Clone, Copy, Resource, Reflect, ExtractResource, Debug)]
+#[derive(
+    ShaderType, Pod, Zeroable, Clone, Copy, Resource, Reflect, ExtractResource, Debug, PartialEq,
+)]
 #[repr(C)]
 pub struct Params {
-    pub count: i32,
+    pub count: i32, // count, size, x, y and frame are filled in from Progress when uploading
     pub size: i32,
     pub x: i32,
     pub y: i32,
-    pub spheres: i32,
-    pub seed: i32,
+    pub spheres: i32, // filled in from Spheres when uploading
+    pub seed: i32,    // picks the random sequences, the same seed and scene give the same image
     pub samples: i32,
     pub depth: i32,
     pub render_mode: i32,
-    pub _padding1: i32,
-    pub _padding2: i32,
-    pub _padding3: i32,
+    pub frame: i32,
+    pub sky: i32, // a Sky, None leaves only the emissive objects and lights to light the scene
+    pub instances: i32, // filled in from InstanceBvh when uploading, the ones in the bvh
+    pub unbounded: i32, // instances of planes, which aren't in the bvh and come first
+    pub instance_root: i32, // index of the instance bvh root, after the sphere and model nodes
+    pub sample_pattern: i32, // a SamplePattern
+    pub lights: i32, // filled in from Lights when uploading
+    pub mis: i32, // 1 samples the emissive spheres and quads directly and weighs both strategies
+    pub emitters: i32, // filled in when uploading, the emissive spheres and quads
+    pub sky_rotation: f32, // radians around y, turns the environment map
+    pub sky_intensity: f32, // scales the environment map and the physical sky
+    pub turbidity: f32, // haze in the physical sky, 2 is very clear and 10 is hazy
+    pub sun_elevation: f32, // radians above the horizon
+    pub sun_azimuth: f32, // radians around y from -z towards +x
+    _padding: f32,
 }
 
 impl Default for Params {
@@ -66,28 +90,179 @@ impl Default for Params {
             size: 512,
             x: 0,
             y: 0,
-            spheres: 8,
+            spheres: 4,
             seed: 0,
             samples: 25,
             depth: 3,
             render_mode: 0,
-            _padding1: 0,
-            _padding2: 0,
-            _padding3: 0,
+            frame: 0,
+            sky: 1,
+            instances: 0,
+            unbounded: 0,
+            instance_root: 0,
+            sample_pattern: SamplePattern::Random as i32,
+            lights: 0,
+            mis: 1,
+            emitters: 0,
+            sky_rotation: 0.,
+            sky_intensity: 1.,
+            turbidity: 3.,
+            sun_elevation: std::f32::consts::FRAC_PI_4,
+            sun_azimuth: 0.,
+            _padding: 0.,
+        }
+    }
+}
+
+impl Params {
+    pub fn with_progress(&self, progress: &Progress) -> Params {
+        Params {
+            count: progress.count,
+            size: progress.size,
+            x: progress.x,
+            y: progress.y,
+            frame: progress.frame,
+            ..*self
         }
     }
 }
 
+// render_mode where the shader path traces radiance instead of averaging hit colors
+pub const PATH_TRACED: i32 = 4;
+
+// where the random numbers for each sample come from, matches the constants in simple.wgsl
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SamplePattern {
+    Random = 0,
+    Stratified = 1,
+    Sobol = 2,
+    BlueNoise = 3,
+}
+
+impl SamplePattern {
+    pub const ALL: [SamplePattern; 4] = [
+        SamplePattern::Random,
+        SamplePattern::Stratified,
+        SamplePattern::Sobol,
+        SamplePattern::BlueNoise,
+    ];
+
+    pub fn from_index(index: i32) -> Self {
+        Self::ALL
+            .into_iter()
+            .find(|pattern| *pattern as i32 == index)
+            .unwrap_or(SamplePattern::Random)
+    }
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            SamplePattern::Random => "Random",
+            SamplePattern::Stratified => "Stratified",
+            SamplePattern::Sobol => "Sobol",
+            SamplePattern::BlueNoise => "Blue noise",
+        }
+    }
+}
+
+// what rays that miss everything see, matches the constants in simple.wgsl
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Sky {
+    None = 0,
+    Gradient = 1,
+    Environment = 2,
+    Physical = 3,
+}
+
+impl Sky {
+    pub const ALL: [Sky; 4] = [Sky::None, Sky::Gradient, Sky::Environment, Sky::Physical];
+
+    pub fn from_index(index: i32) -> Self {
+        Self::ALL
+            .into_iter()
+            .find(|sky| *sky as i32 == index)
+            .unwrap_or(Sky::Gradient)
+    }
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            Sky::None => "None",
+            Sky::Gradient => "Gradient",
+            Sky::Environment => "Environment map",
+            Sky::Physical => "Physical sky",
+        }
+    }
+}
+
+// the tiling noise texture the BlueNoise pattern reads
+#[derive(Resource, Clone, ExtractResource)]
+pub struct BlueNoise {
+    pub image: Handle<Image>,
+}
+
 #[derive(Resource, Debug)]
 struct ParamsBuffer {
     buffer: Option<Buffer>,
 }
 
+#[derive(Resource)]
+struct BvhBuffer {
+    buffer: SceneArray<BvhNode>,
+    instance_root: u32,
+}
+
+#[derive(Resource)]
+struct TriangleBuffers {
+    vertices: SceneArray<Vertex>,
+    triangles: SceneArray<Triangle>,
+    materials: SceneArray<SurfaceMaterial>,
+}
+
 #[derive(Resource, Debug)]
 struct CameraBuffer {
     buffer: Option<Buffer>,
 }
 
+// running sum of samples per pixel, one vec4<f32> per pixel
+pub(crate) const ACCUMULATION_BUFFER_SIZE: u64 = (SIZE.0 * SIZE.1) as u64 * 16;
+
+// one of the two is created, depending on AccumulationStorage
+#[derive(Resource)]
+pub(crate) struct AccumulationBuffer {
+    pub(crate) buffer: Option<Buffer>,
+    pub(crate) textures: Option<AccumulationTextures>,
+}
+
+// renders the image once, tile by tile, instead of continuously accumulating frames
+#[derive(Resource, Debug, Reflect, Clone, Copy, PartialEq)]
+pub struct OneShot {
+    pub enabled: bool,
+    pub tile_size: i32,
+}
+
+impl Default for OneShot {
+    fn default() -> Self {
+        OneShot {
+            enabled: false,
+            tile_size: 64,
+        }
+    }
+}
+
+impl OneShot {
+    pub fn tile_count(&self) -> i32 {
+        (SIZE.0 as i32 / self.tile_size) * (SIZE.1 as i32 / self.tile_size)
+    }
+
+    // tiles go across the image a row at a time
+    pub fn tile_origin(&self, tile: i32) -> (i32, i32) {
+        let tiles_x = SIZE.0 as i32 / self.tile_size;
+        (
+            (tile % tiles_x) * self.tile_size,
+            (tile / tiles_x) * self.tile_size,
+        )
+    }
+}
+
 #[derive(Resource, Debug, Default, Reflect, Clone)]
 pub struct RenderTime {
     pub time: f32,
@@ -100,31 +275,147 @@ pub struct RenderTime {
     pub _last_10: VecDeque<f32>,
 }
 
+// how far the render has got. it moves on every frame, so it is kept out of Params, where a change
+// means the settings were edited and the accumulated samples have to go
+#[derive(Resource, ExtractResource, Reflect, Clone, Copy, Debug, PartialEq)]
+pub struct Progress {
+    pub frame: i32, // number of frames accumulated so far, 0 clears the accumulation buffer
+    pub count: i32, // tiles dispatched so far in one shot mode
+    pub size: i32,  // the side of the square being rendered, the whole image or a tile
+    pub x: i32,
+    pub y: i32,
+    // set by a reset, so the next frame starts over even though nothing in the scene changed
+    restart: bool,
+}
+
+impl Default for Progress {
+    fn default() -> Self {
+        Progress {
+            frame: 0,
+            count: 0,
+            size: SIZE.0 as i32,
+            x: 0,
+            y: 0,
+            restart: true,
+        }
+    }
+}
+
+impl Progress {
+    // the whole image again, accumulating onto the frames before unless something changed
+    pub fn next_frame(&mut self, changed: bool) {
+        if changed || self.restart {
+            *self = Progress {
+                restart: false,
+                ..default()
+            };
+        } else {
+            self.frame += 1;
+        }
+    }
+
+    // the next tile, starting from the first one if something changed. true once the last tile
+    // has been handed out
+    pub fn next_tile(&mut self, one_shot: &OneShot, changed: bool) -> bool {
+        if changed || self.restart {
+            self.restart = false;
+            self.count = 0;
+        }
+
+        let (x, y) = one_shot.tile_origin(self.count);
+        self.size = one_shot.tile_size;
+        self.x = x;
+        self.y = y;
+        self.frame = 0;
+        self.count += 1;
+        self.count >= one_shot.tile_count()
+    }
+}
+
 pub struct ComputeShaderPlugin;
 impl Plugin for ComputeShaderPlugin {
     fn build(&self, app: &mut App) {
         app.add_plugins((
             ExtractResourcePlugin::<RenderImage>::default(),
             ExtractResourcePlugin::<Params>::default(),
+            ExtractResourcePlugin::<Progress>::default(),
             ExtractResourcePlugin::<Camera>::default(),
             ExtractResourcePlugin::<Spheres>::default(),
+            ExtractResourcePlugin::<Bvh>::default(),
+            ExtractResourcePlugin::<Triangles>::default(),
+            ExtractResourcePlugin::<Primitives>::default(),
+            ExtractResourcePlugin::<InstanceBvh>::default(),
+            ExtractResourcePlugin::<BlueNoise>::default(),
+            ExtractResourcePlugin::<Lights>::default(),
+            ExtractResourcePlugin::<Environment>::default(),
         ))
         .register_type::<RenderImage>()
         .register_type::<Params>()
+        .register_type::<Progress>()
         .register_type::<Camera>()
         .register_type::<RenderTime>()
+        .register_type::<SphereAnimation>()
+        .register_type::<OneShot>()
+        .register_type::<FocusPicker>()
         .register_type::<[f32; 3]>()
         .insert_resource(Params::default())
+        .insert_resource(Progress::default())
         .insert_resource(Camera::create_camera())
-        .insert_resource(Spheres::default_scene())
+        .insert_resource(Spheres::default())
+        .insert_resource(Bvh::default())
+        .insert_resource(Primitives::default())
+        .insert_resource(Instances::default())
+        .insert_resource(InstanceBvh::default())
+        .insert_resource(Lights::default())
         .insert_resource(RenderTime::default())
+        .insert_resource(SphereAnimation::default())
+        .insert_resource(OneShot::default())
+        .insert_resource(FocusPicker::default())
+        .insert_resource(CameraControls::default())
+        .add_systems(
+            Startup,
+            (spawn_default_scene, load_blue_noise, setup_environment),
+        )
         .add_systems(
             Update,
-            (update_time, update_spheres).run_if(in_state(AppState::Running)),
+            (
+                pick_focus,
+                camera_controls,
+                linear_blue_noise,
+                update_environment,
+            ),
+        )
+        .add_systems(Update, update_time.run_if(in_state(AppState::Running)))
+        .add_systems(
+            Update,
+            update_spheres.run_if(in_state(AppState::Running).and_then(not(one_shot_enabled))),
+        )
+        .add_systems(
+            PostUpdate,
+            update_camera.before(update_frame).before(update_tile),
+        )
+        .add_systems(
+            PostUpdate,
+            gather_scene
+                .before(update_bvh)
+                .before(update_triangles)
+                .before(update_frame)
+                .before(update_tile),
+        )
+        .add_systems(
+            PostUpdate,
+            (update_bvh, update_instance_bvh.after(update_triangles)),
+        )
+        .add_systems(
+            PostUpdate,
+            (
+                update_frame.run_if(in_state(AppState::Running).and_then(not(one_shot_enabled))),
+                update_tile.run_if(in_state(AppState::Running).and_then(one_shot_enabled)),
+            ),
         )
         .add_systems(
             Last,
-            (post_reset, reset_time).run_if(in_state(AppState::Reset)),
+            (post_reset, reset_time, reset_frame).run_if(in_state(AppState::Reset)),
         );
 
         let render_app = app.sub_app_mut(RenderApp);
@@ -137,9 +428,36 @@ impl Plugin for ComputeShaderPlugin {
                 state: AppState::Waiting,
             })
             .insert_resource(ParamsBuffer { buffer: None })
-            .insert_resource(SphereBuffer { buffer: None })
-            .insert_resource(CameraBuffer { buffer: None });
-        // todo: storage buffers for web
+            .insert_resource(SphereBuffer {
+                buffer: SceneArray::new("spheres buffer"),
+            })
+            .insert_resource(BvhBuffer {
+                buffer: SceneArray::new("bvh buffer"),
+                instance_root: 0,
+            })
+            .insert_resource(TriangleBuffers {
+                vertices: SceneArray::new("vertex buffer"),
+                triangles: SceneArray::new("triangle buffer"),
+                materials: SceneArray::new("material buffer"),
+            })
+            .insert_resource(PrimitiveBuffer {
+                buffer: SceneArray::new("primitive buffer"),
+            })
+            .insert_resource(InstanceBuffer {
+                buffer: SceneArray::new("instance buffer"),
+            })
+            .insert_resource(LightBuffer {
+                buffer: SceneArray::new("light buffer"),
+            })
+            .insert_resource(EmitterBuffer {
+                buffer: SceneArray::new("emitter buffer"),
+                count: 0,
+            })
+            .insert_resource(CameraBuffer { buffer: None })
+            .insert_resource(AccumulationBuffer {
+                buffer: None,
+                textures: None,
+            });
         // .insert_resource(NoiseBuffer { buffer: None });
 
         let mut render_graph = render_app.world.resource_mut::<RenderGraph>();
@@ -152,7 +470,44 @@ impl Plugin for ComputeShaderPlugin {
 
     fn finish(&self, app: &mut App) {
         let render_app = app.sub_app_mut(RenderApp);
-        render_app.init_resource::<ComputeShaderPipeline>();
+
+        let render_device = render_app.world.resource::<RenderDevice>();
+        // the accumulation buffer, the spheres, the bvh, the vertices, triangles and materials, the
+        // primitives, the instances, the lights and the emitters
+        let storage = SceneStorage::for_device(render_device, 10);
+        let accumulation = AccumulationStorage::for_device(render_device);
+        render_app
+            .insert_resource(storage)
+            .insert_resource(accumulation)
+            .init_resource::<ComputeShaderPipeline>();
+    }
+}
+
+fn load_blue_noise(mut commands: Commands, asset_server: Res<AssetServer>) {
+    commands.insert_resource(BlueNoise {
+        image: asset_server.load("textures/blue_noise.png"),
+    });
+}
+
+// the png loads as srgb, but the values are noise, not colors, and have to reach the shader as
+// they are stored
+fn linear_blue_noise(
+    blue_noise: Res<BlueNoise>,
+    mut events: EventReader<AssetEvent<Image>>,
+    mut images: ResMut<Assets<Image>>,
+) {
+    for event in events.iter() {
+        let (AssetEvent::Created { handle } | AssetEvent::Modified { handle }) = event else {
+            continue;
+        };
+        if *handle != blue_noise.image {
+            continue;
+        }
+        if let Some(image) = images.get_mut(handle) {
+            if image.texture_descriptor.format == TextureFormat::Rgba8UnormSrgb {
+                image.texture_descriptor.format = TextureFormat::Rgba8Unorm;
+            }
+        }
     }
 }
 
@@ -184,6 +539,59 @@ fn update_time(time: Res<Time>, mut render_time: ResMut<RenderTime>) {
             / render_time._last_10.len() as f32);
 }
 
+fn one_shot_enabled(one_shot: Res<OneShot>) -> bool {
+    one_shot.enabled
+}
+
+// everything the render depends on, if any of it changes the samples so far are thrown away
+#[derive(SystemParam)]
+struct RenderInputs<'w> {
+    params: Res<'w, Params>,
+    camera: Res<'w, Camera>,
+    spheres: Res<'w, Spheres>,
+    triangles: Res<'w, Triangles>,
+    primitives: Res<'w, Primitives>,
+    instances: Res<'w, Instances>,
+    lights: Res<'w, Lights>,
+    environment: Res<'w, Environment>,
+    one_shot: Res<'w, OneShot>,
+}
+
+impl RenderInputs<'_> {
+    fn changed(&self) -> bool {
+        self.params.is_changed()
+            || self.camera.is_changed()
+            || self.spheres.is_changed()
+            || self.triangles.is_changed()
+            || self.primitives.is_changed()
+            || self.instances.is_changed()
+            || self.lights.is_changed()
+            || self.environment.is_changed()
+            || self.one_shot.is_changed()
+    }
+}
+
+// keep accumulating samples while the scene is still, start over as soon as anything changes.
+// progress is written every frame, so the render world always gets the new frame number
+fn update_frame(mut progress: ResMut<Progress>, inputs: RenderInputs) {
+    progress.next_frame(inputs.changed());
+}
+
+// dispatch one tile per frame, then move to Done once the last tile has been queued
+fn update_tile(
+    mut progress: ResMut<Progress>,
+    inputs: RenderInputs,
+    mut next_state: ResMut<NextState<AppState>>,
+) {
+    if progress.next_tile(&inputs.one_shot, inputs.changed()) {
+        next_state.set(AppState::Done);
+    }
+}
+
+fn reset_frame(mut progress: ResMut<Progress>) {
+    *progress = Progress::default();
+}
+
 fn reset_time(mut render_time: ResMut<RenderTime>) {
     render_time.time = 0.;
     render_time.frames = 0;
@@ -195,26 +603,6 @@ fn reset_time(mut render_time: ResMut<RenderTime>) {
     render_time._last_10.clear();
 }
 
-// todo: separate modes between one shot and continuous
-
-// fn update_params(mut params: ResMut<Params>, mut next_state: ResMut<NextState<AppState>>) {
-// params.x += params.size;
-
-// if params.x >= SIZE.0 as i32 {
-//     params.y += params.size;
-// }
-// params.x = params.x % SIZE.0 as i32;
-
-// params.count += 1;
-// if params.count > (SIZE.0 * SIZE.1) as i32 / (params.size * params.size) {
-//     // next_state.set(AppState::Done);
-//     params.x = -(params.size as i32);
-//     params.y = 0;
-//     params.count = 0;
-//     params.seed += 1;
-// }
-// }
-
 #[derive(Resource)]
 pub struct ComputeShaderPipeline {
     texture_bind_group_layout: BindGroupLayout,
@@ -224,76 +612,105 @@ pub struct ComputeShaderPipeline {
 
 impl FromWorld for ComputeShaderPipeline {
     fn from_world(world: &mut World) -> Self {
+        let storage = *world.resource::<SceneStorage>();
+        let accumulation = *world.resource::<AccumulationStorage>();
+        let mut entries = vec![
+            BindGroupLayoutEntry {
+                binding: 0,
+                visibility: ShaderStages::COMPUTE,
+                ty: BindingType::StorageTexture {
+                    access: StorageTextureAccess::WriteOnly,
+                    format: TextureFormat::Rgba8Unorm,
+                    view_dimension: TextureViewDimension::D2,
+                },
+                count: None,
+            },
+            BindGroupLayoutEntry {
+                binding: 1,
+                visibility: ShaderStages::COMPUTE,
+                ty: BindingType::Buffer {
+                    ty: BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: BufferSize::new(std::mem::size_of::<Params>() as u64),
+                },
+                count: None,
+            },
+            BindGroupLayoutEntry {
+                binding: 2,
+                visibility: ShaderStages::COMPUTE,
+                ty: BindingType::Buffer {
+                    ty: BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: BufferSize::new(Camera::algined_size()),
+                },
+                count: None,
+            },
+            storage.layout_entry(3),
+            storage.layout_entry(5),
+            storage.layout_entry(6),
+            storage.layout_entry(7),
+            storage.layout_entry(8),
+            storage.layout_entry(9),
+            storage.layout_entry(10),
+            BindGroupLayoutEntry {
+                binding: 11,
+                visibility: ShaderStages::COMPUTE,
+                ty: BindingType::Texture {
+                    sample_type: TextureSampleType::Float { filterable: false },
+                    view_dimension: TextureViewDimension::D2,
+                    multisampled: false,
+                },
+                count: None,
+            },
+            storage.layout_entry(12),
+            storage.layout_entry(13),
+            BindGroupLayoutEntry {
+                binding: 14,
+                visibility: ShaderStages::COMPUTE,
+                ty: BindingType::Texture {
+                    sample_type: TextureSampleType::Float { filterable: false },
+                    view_dimension: TextureViewDimension::D2,
+                    multisampled: false,
+                },
+                count: None,
+            },
+            BindGroupLayoutEntry {
+                binding: 15,
+                visibility: ShaderStages::COMPUTE,
+                ty: BindingType::Texture {
+                    sample_type: TextureSampleType::Float { filterable: false },
+                    view_dimension: TextureViewDimension::D2,
+                    multisampled: false,
+                },
+                count: None,
+            },
+            // BindGroupLayoutEntry {
+            //     binding: 17,
+            //     visibility: ShaderStages::COMPUTE,
+            //     ty: BindingType::Buffer {
+            //         ty: BufferBindingType::Storage { read_only: true },
+            //         has_dynamic_offset: false,
+            //         min_binding_size: BufferSize::new(NOISE_BUFFER_SIZE * 4),
+            //     },
+            //     count: None,
+            // },
+        ];
+        entries.extend(accumulation.layout_entries(ACCUMULATION_BUFFER_SIZE));
         let texture_bind_group_layout =
             world
                 .resource::<RenderDevice>()
                 .create_bind_group_layout(&BindGroupLayoutDescriptor {
                     label: None,
-                    entries: &[
-                        BindGroupLayoutEntry {
-                            binding: 0,
-                            visibility: ShaderStages::COMPUTE,
-                            ty: BindingType::StorageTexture {
-                                access: StorageTextureAccess::WriteOnly,
-                                format: TextureFormat::Rgba8Unorm,
-                                view_dimension: TextureViewDimension::D2,
-                            },
-                            count: None,
-                        },
-                        BindGroupLayoutEntry {
-                            binding: 1,
-                            visibility: ShaderStages::COMPUTE,
-                            ty: BindingType::Buffer {
-                                ty: BufferBindingType::Uniform,
-                                has_dynamic_offset: false,
-                                min_binding_size: BufferSize::new(
-                                    std::mem::size_of::<Params>() as u64
-                                ),
-                            },
-                            count: None,
-                        },
-                        BindGroupLayoutEntry {
-                            binding: 2,
-                            visibility: ShaderStages::COMPUTE,
-                            ty: BindingType::Buffer {
-                                ty: BufferBindingType::Uniform,
-                                has_dynamic_offset: false,
-                                min_binding_size: BufferSize::new(Camera::algined_size()),
-                            },
-                            count: None,
-                        },
-                        BindGroupLayoutEntry {
-                            binding: 3,
-                            visibility: ShaderStages::COMPUTE,
-                            ty: BindingType::Buffer {
-                                ty: BufferBindingType::Uniform,
-                                has_dynamic_offset: false,
-                                min_binding_size: BufferSize::new(
-                                    std::mem::size_of::<Spheres>() as u64
-                                ),
-                            },
-                            count: None,
-                        },
-                        // todo: storage textures for web
-                        // BindGroupLayoutEntry {
-                        //     binding: 4,
-                        //     visibility: ShaderStages::COMPUTE,
-                        //     ty: BindingType::Buffer {
-                        //         ty: BufferBindingType::Storage { read_only: true },
-                        //         has_dynamic_offset: false,
-                        //         min_binding_size: BufferSize::new(NOISE_BUFFER_SIZE * 4),
-                        //     },
-                        //     count: None,
-                        // },
-                    ],
+                    entries: &entries,
                 });
         let shader = world.resource::<AssetServer>().load("shaders/simple.wgsl");
+        let shader_defs = [storage.shader_defs(), accumulation.shader_defs()].concat();
         let pipeline_cache = world.resource_mut::<PipelineCache>();
         let init_pipeline = pipeline_cache.queue_compute_pipeline(ComputePipelineDescriptor {
             label: None,
             layout: vec![texture_bind_group_layout.clone()],
             shader: shader.clone(),
-            shader_defs: vec![],
+            shader_defs: shader_defs.clone(),
             entry_point: Cow::from("init"),
             push_constant_ranges: vec![],
         });
@@ -301,7 +718,7 @@ impl FromWorld for ComputeShaderPipeline {
             label: None,
             layout: vec![texture_bind_group_layout.clone()],
             shader,
-            shader_defs: vec![],
+            shader_defs,
             entry_point: Cow::from("update"),
             push_constant_ranges: vec![],
         });
@@ -323,37 +740,135 @@ fn queue_bind_group(
     params_buffer: Res<ParamsBuffer>,
     camera_buffer: Res<CameraBuffer>,
     spheres_buffer: Res<SphereBuffer>,
+    bvh_buffer: Res<BvhBuffer>,
+    triangle_buffers: Res<TriangleBuffers>,
+    primitive_buffer: Res<PrimitiveBuffer>,
+    accumulation_buffer: Res<AccumulationBuffer>,
+    // systems take at most 16 parameters
+    (instance_buffer, light_buffer, emitter_buffer): (
+        Res<InstanceBuffer>,
+        Res<LightBuffer>,
+        Res<EmitterBuffer>,
+    ),
+    (blue_noise, environment): (Res<BlueNoise>, Res<Environment>),
     // noise_buffer: Res<NoiseBuffer>,
 ) {
     let output_view = &gpu_images[&output_image.image];
+    // the node waits for the bind group, so nothing runs until the textures are on the gpu
+    let Some(blue_noise_view) = gpu_images.get(&blue_noise.image) else {
+        return;
+    };
+    let (Some(environment_view), Some(cdf_view)) = (
+        gpu_images.get(&environment.image),
+        gpu_images.get(&environment.cdf),
+    ) else {
+        return;
+    };
+    // the scene arrays only exist once prepare_params has written them
+    let (
+        Some(spheres),
+        Some(bvh),
+        Some(vertices),
+        Some(triangles),
+        Some(materials),
+        Some(primitives),
+        Some(instances),
+        Some(lights),
+        Some(emitters),
+    ) = (
+        spheres_buffer.buffer.binding(),
+        bvh_buffer.buffer.binding(),
+        triangle_buffers.vertices.binding(),
+        triangle_buffers.triangles.binding(),
+        triangle_buffers.materials.binding(),
+        primitive_buffer.buffer.binding(),
+        instance_buffer.buffer.binding(),
+        light_buffer.buffer.binding(),
+        emitter_buffer.buffer.binding(),
+    )
+    else {
+        return;
+    };
+
+    let mut entries = vec![
+        BindGroupEntry {
+            binding: 0,
+            resource: BindingResource::TextureView(&output_view.texture_view),
+        },
+        BindGroupEntry {
+            binding: 1,
+            resource: params_buffer.buffer.as_ref().unwrap().as_entire_binding(),
+        },
+        BindGroupEntry {
+            binding: 2,
+            resource: camera_buffer.buffer.as_ref().unwrap().as_entire_binding(),
+        },
+        BindGroupEntry {
+            binding: 3,
+            resource: spheres,
+        },
+        BindGroupEntry {
+            binding: 5,
+            resource: bvh,
+        },
+        BindGroupEntry {
+            binding: 6,
+            resource: vertices,
+        },
+        BindGroupEntry {
+            binding: 7,
+            resource: triangles,
+        },
+        BindGroupEntry {
+            binding: 8,
+            resource: materials,
+        },
+        BindGroupEntry {
+            binding: 9,
+            resource: primitives,
+        },
+        BindGroupEntry {
+            binding: 10,
+            resource: instances,
+        },
+        BindGroupEntry {
+            binding: 11,
+            resource: BindingResource::TextureView(&blue_noise_view.texture_view),
+        },
+        BindGroupEntry {
+            binding: 12,
+            resource: lights,
+        },
+        BindGroupEntry {
+            binding: 13,
+            resource: emitters,
+        },
+        BindGroupEntry {
+            binding: 14,
+            resource: BindingResource::TextureView(&environment_view.texture_view),
+        },
+        BindGroupEntry {
+            binding: 15,
+            resource: BindingResource::TextureView(&cdf_view.texture_view),
+        },
+        // BindGroupEntry {
+        //     binding: 17,
+        //     resource: noise_buffer.buffer.as_ref().unwrap().as_entire_binding(),
+        // },
+    ];
+    match (&accumulation_buffer.buffer, &accumulation_buffer.textures) {
+        (Some(buffer), _) => entries.push(BindGroupEntry {
+            binding: 4,
+            resource: buffer.as_entire_binding(),
+        }),
+        (None, Some(textures)) => entries.extend(textures.bind_group_entries()),
+        (None, None) => return,
+    }
 
     let bind_group = render_device.create_bind_group(&BindGroupDescriptor {
         label: None,
         layout: &pipeline.texture_bind_group_layout,
-        entries: &[
-            BindGroupEntry {
-                binding: 0,
-                resource: BindingResource::TextureView(&output_view.texture_view),
-            },
-            BindGroupEntry {
-                binding: 1,
-                resource: params_buffer.buffer.as_ref().unwrap().as_entire_binding(),
-            },
-            BindGroupEntry {
-                binding: 2,
-                resource: camera_buffer.buffer.as_ref().unwrap().as_entire_binding(),
-            },
-            BindGroupEntry {
-                binding: 3,
-                resource: spheres_buffer.buffer.as_ref().unwrap().as_entire_binding(),
-            },
-            // todo: storage textures for web
-
-            // BindGroupEntry {
-            //     binding: 4,
-            //     resource: noise_buffer.buffer.as_ref().unwrap().as_entire_binding(),
-            // },
-        ],
+        entries: &entries,
     });
     commands.insert_resource(RenderImageBindGroup(bind_group));
 }
@@ -374,6 +889,7 @@ impl render_graph::Node for ComputeShaderNode {
     fn update(&mut self, world: &mut World) {
         let pipeline = world.resource::<ComputeShaderPipeline>();
         let pipeline_cache = world.resource::<PipelineCache>();
+        let bind_group_ready = world.contains_resource::<RenderImageBindGroup>();
 
         // if the corresponding pipeline has loaded, transition to the next stage
         match self.state {
@@ -381,6 +897,9 @@ impl render_graph::Node for ComputeShaderNode {
                 if let CachedPipelineState::Ok(_) =
                     pipeline_cache.get_compute_pipeline_state(pipeline.init_pipeline)
                 {
+                    if !bind_group_ready {
+                        return;
+                    }
                     self.state = ComputeShaderState::Init;
                 }
             }
@@ -401,17 +920,19 @@ impl render_graph::Node for ComputeShaderNode {
         render_context: &mut RenderContext,
         world: &World,
     ) -> Result<(), render_graph::NodeRunError> {
-        let texture_bind_group = &world.resource::<RenderImageBindGroup>().0;
+        let Some(texture_bind_group) = world.get_resource::<RenderImageBindGroup>() else {
+            return Ok(());
+        };
         let pipeline_cache = world.resource::<PipelineCache>();
         let pipeline = world.resource::<ComputeShaderPipeline>();
         let state = &world.resource::<RenderState>().state;
-        let window_size = &world.resource::<Params>().size;
+        let window_size = &world.resource::<Progress>().size;
         let workgroup_size = (window_size / 8) as u32;
         let mut pass = render_context
             .command_encoder()
             .begin_compute_pass(&ComputePassDescriptor::default());
 
-        pass.set_bind_group(0, texture_bind_group, &[]);
+        pass.set_bind_group(0, &texture_bind_group.0, &[]);
 
         // select the pipeline based on the current state
         match self.state {
@@ -447,19 +968,39 @@ impl render_graph::Node for ComputeShaderNode {
                 }
             }
         }
+        drop(pass);
+
+        // next frame reads what this one wrote
+        if let Some(textures) = &world.resource::<AccumulationBuffer>().textures {
+            textures.copy_back(render_context.command_encoder());
+        }
 
         Ok(())
     }
 }
 
 fn prepare_params(
-    params: Res<Params>,
+    (params, progress): (Res<Params>, Res<Progress>),
     camera: Res<Camera>,
     spheres: Res<Spheres>,
+    bvh: Res<Bvh>,
+    triangles: Res<Triangles>,
     mut params_buffer: ResMut<ParamsBuffer>,
     mut camera_buffer: ResMut<CameraBuffer>,
     mut spheres_buffer: ResMut<SphereBuffer>,
+    mut bvh_buffer: ResMut<BvhBuffer>,
+    mut triangle_buffers: ResMut<TriangleBuffers>,
+    mut accumulation_buffer: ResMut<AccumulationBuffer>,
     // mut noise_buffer: ResMut<NoiseBuffer>,
+    // systems take at most 16 parameters
+    (primitives, instance_bvh, lights): (Res<Primitives>, Res<InstanceBvh>, Res<Lights>),
+    (mut primitive_buffer, mut instance_buffer, mut light_buffer, mut emitter_buffer): (
+        ResMut<PrimitiveBuffer>,
+        ResMut<InstanceBuffer>,
+        ResMut<LightBuffer>,
+        ResMut<EmitterBuffer>,
+    ),
+    (storage, accumulation): (Res<SceneStorage>, Res<AccumulationStorage>),
     render_queue: Res<RenderQueue>,
     render_device: Res<RenderDevice>,
 ) {
@@ -481,17 +1022,24 @@ fn prepare_params(
         }));
     }
 
-    if spheres_buffer.buffer.is_none() {
-        spheres_buffer.buffer = Some(render_device.create_buffer(&BufferDescriptor {
-            label: Some("spheres buffer"),
-            size: std::mem::size_of::<Spheres>() as u64,
-            usage: BufferUsages::UNIFORM | BufferUsages::COPY_DST,
-            mapped_at_creation: false,
-        }));
+    // only written by the shader, so it is never uploaded from here, but it is read back for export
+    if accumulation_buffer.buffer.is_none() && accumulation_buffer.textures.is_none() {
+        match *accumulation {
+            AccumulationStorage::Buffer => {
+                accumulation_buffer.buffer = Some(render_device.create_buffer(&BufferDescriptor {
+                    label: Some("accumulation buffer"),
+                    size: ACCUMULATION_BUFFER_SIZE,
+                    usage: BufferUsages::STORAGE | BufferUsages::COPY_SRC,
+                    mapped_at_creation: false,
+                }));
+            }
+            AccumulationStorage::Texture => {
+                accumulation_buffer.textures =
+                    Some(AccumulationTextures::new(&render_device, SIZE.0, SIZE.1));
+            }
+        }
     }
 
-    // todo: storage buffers for web
-
     // if noise_buffer.buffer.is_none() {
     //     noise_buffer.buffer = Some(render_device.create_buffer(&BufferDescriptor {
     //         label: Some("noise buffer"),
@@ -511,10 +1059,109 @@ fn prepare_params(
     //     cast_slice(random_number.as_slice()),
     // );
 
+    // big meshes make these expensive, so they are only uploaded when the scene changes
+    if spheres.is_changed()
+        || bvh.is_changed()
+        || triangles.is_changed()
+        || instance_bvh.is_changed()
+        || primitives.is_changed()
+    {
+        // all the trees share one node buffer, spheres first, then one per model, then instances
+        let mut nodes = vec![];
+        bvh.append_to(&mut nodes);
+        let model_roots: Vec<u32> = triangles
+            .models
+            .iter()
+            .map(|model| model.bvh.append_to(&mut nodes))
+            .collect();
+        bvh_buffer.instance_root = instance_bvh.bvh.append_to(&mut nodes);
+
+        // mesh instances start from their model's root node
+        let mut instances: Vec<_> = instance_bvh
+            .instances
+            .iter()
+            .map(|instance| {
+                let mut instance = *instance;
+                if instance.is_mesh() {
+                    instance.index = model_roots[instance.index as usize];
+                }
+                instance
+            })
+            .collect();
+
+        // bvh leaves index into the spheres in tree order
+        let mut ordered: Vec<Sphere> = bvh
+            .order
+            .iter()
+            .filter_map(|&i| spheres.spheres.get(i as usize).copied())
+            .collect();
+
+        let emitters = collect_emitters(&mut ordered, &mut instances, &primitives.primitives);
+        emitter_buffer.count = emitters.len() as u32;
+        emitter_buffer
+            .buffer
+            .write(&emitters, *storage, &render_device, &render_queue);
+        instance_buffer
+            .buffer
+            .write(&instances, *storage, &render_device, &render_queue);
+        spheres_buffer
+            .buffer
+            .write(&ordered, *storage, &render_device, &render_queue);
+        bvh_buffer
+            .buffer
+            .write(&nodes, *storage, &render_device, &render_queue);
+    }
+
+    // instances refer to primitives by index, so these stay in the order they were defined
+    if primitives.is_changed() {
+        primitive_buffer.buffer.write(
+            &primitives.primitives,
+            *storage,
+            &render_device,
+            &render_queue,
+        );
+    }
+
+    if lights.is_changed() {
+        light_buffer
+            .buffer
+            .write(&lights.lights, *storage, &render_device, &render_queue);
+    }
+
+    if triangles.is_changed() {
+        triangle_buffers.vertices.write(
+            &triangles.vertices,
+            *storage,
+            &render_device,
+            &render_queue,
+        );
+        triangle_buffers.triangles.write(
+            &triangles.triangles,
+            *storage,
+            &render_device,
+            &render_queue,
+        );
+        triangle_buffers.materials.write(
+            &triangles.materials,
+            *storage,
+            &render_device,
+            &render_queue,
+        );
+    }
+
+    let params = Params {
+        spheres: spheres.spheres.len() as i32,
+        instances: (instance_bvh.instances.len() as u32 - instance_bvh.unbounded) as i32,
+        unbounded: instance_bvh.unbounded as i32,
+        instance_root: bvh_buffer.instance_root as i32,
+        lights: lights.lights.len() as i32,
+        emitters: emitter_buffer.count as i32,
+        ..params.with_progress(&progress)
+    };
     render_queue.write_buffer(
         &params_buffer.buffer.as_ref().unwrap(),
         0,
-        bytes_of(params.as_ref()),
+        bytes_of(&params),
     );
 
     render_queue.write_buffer(
@@ -522,14 +1169,108 @@ fn prepare_params(
         0,
         bytes_of(camera.as_ref()),
     );
-
-    render_queue.write_buffer(
-        &spheres_buffer.buffer.as_ref().unwrap(),
-        0,
-        bytes_of(spheres.as_ref()),
-    );
 }
 
 fn post_reset(mut next_state: ResMut<NextState<AppState>>) {
     next_state.set(AppState::Waiting);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frames_accumulate_until_something_changes() {
+        let mut progress = Progress::default();
+        progress.next_frame(false);
+        assert_eq!(progress.frame, 0, "the first frame clears the buffer");
+        progress.next_frame(false);
+        progress.next_frame(false);
+        assert_eq!(progress.frame, 2);
+
+        progress.next_frame(true);
+        assert_eq!(progress.frame, 0);
+        progress.next_frame(false);
+        assert_eq!(progress.frame, 1);
+
+        // a reset starts over even though nothing changed
+        progress = Progress::default();
+        progress.next_frame(false);
+        assert_eq!(progress.frame, 0);
+        assert_eq!(
+            (progress.size, progress.x, progress.y),
+            (SIZE.0 as i32, 0, 0)
+        );
+    }
+
+    #[test]
+    fn tiles_cover_the_image_once() {
+        for tile_size in [8, 16, 32, 64, 128, 256, 512] {
+            let one_shot = OneShot {
+                enabled: true,
+                tile_size,
+            };
+            let mut covered = vec![0; (SIZE.0 * SIZE.1) as usize];
+            let mut progress = Progress::default();
+            let mut done = false;
+            let mut tiles = 0;
+            while !done {
+                done = progress.next_tile(&one_shot, false);
+                tiles += 1;
+                assert_eq!(progress.frame, 0);
+                assert_eq!(progress.size, tile_size);
+                // the shader runs size / 8 workgroups of 8 by 8 from x, y
+                assert_eq!(progress.size % INIT_WORKGROUP_SIZE as i32, 0);
+                for y in progress.y..progress.y + progress.size {
+                    for x in progress.x..progress.x + progress.size {
+                        covered[(y * SIZE.0 as i32 + x) as usize] += 1;
+                    }
+                }
+            }
+            assert_eq!(tiles, one_shot.tile_count());
+            assert!(
+                covered.iter().all(|&count| count == 1),
+                "tile size {}",
+                tile_size
+            );
+        }
+    }
+
+    #[test]
+    fn tiles_start_over_when_something_changes() {
+        let one_shot = OneShot::default();
+        let mut progress = Progress::default();
+        progress.next_tile(&one_shot, false);
+        progress.next_tile(&one_shot, false);
+        assert_eq!((progress.x, progress.y), (one_shot.tile_size, 0));
+
+        progress.next_tile(&one_shot, true);
+        assert_eq!((progress.count, progress.x, progress.y), (1, 0, 0));
+    }
+
+    // the shader seeds every pixel's sequence from params.seed and params.frame, so the seed set in
+    // the menu has to survive progress filling in the frame
+    #[test]
+    fn the_seed_reaches_the_upload() {
+        let params = Params {
+            seed: 42,
+            ..Params::default()
+        };
+        let mut progress = Progress::default();
+        progress.next_frame(false);
+        progress.next_frame(false);
+        let uploaded = params.with_progress(&progress);
+        assert_eq!((uploaded.seed, uploaded.frame), (42, 1));
+
+        // a new seed is the only difference in what is uploaded
+        let reseeded = Params { seed: 43, ..params }.with_progress(&progress);
+        assert_ne!(bytes_of(&reseeded), bytes_of(&uploaded));
+        assert_eq!(
+            Params {
+                seed: 42,
+                ..reseeded
+            },
+            uploaded
+        );
+    }
+}
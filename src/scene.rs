@@ -0,0 +1,1043 @@
+use crate::{
+    camera::Camera,
+    collidables::{Material, Primitive, Shape, Sphere, SphereAnimation},
+    environment::Environment,
+    light::{Light, LightKind},
+    objects::{
+        light_bundle, mesh_bundle, primitive_bundle, sphere_bundle, RtLight, RtMaterial, RtMesh,
+        RtPrimitive, RtSphere,
+    },
+    render::{Params, Sky},
+};
+
+use bevy::{
+    asset::{AssetLoader, LoadContext, LoadedAsset},
+    ecs::system::SystemParam,
+    prelude::*,
+    reflect::{TypePath, TypeUuid},
+    utils::BoxedFuture,
+};
+use serde::{Deserialize, Serialize};
+
+// everything needed to reproduce a render, stored as RON in assets/scenes
+#[derive(Serialize, Deserialize, TypeUuid, TypePath, Debug, Clone, PartialEq)]
+#[uuid = "5a3a4c2e-8b0f-4f6e-9d43-6a1c7e0b9f21"]
+pub struct SceneFile {
+    pub camera: SceneCamera,
+    pub spheres: Vec<SceneSphere>,
+    #[serde(default)]
+    pub primitives: Vec<ScenePrimitive>,
+    #[serde(default)]
+    pub meshes: Vec<SceneMesh>,
+    #[serde(default)]
+    pub instances: Vec<SceneInstance>,
+    #[serde(default)]
+    pub lights: Vec<SceneLight>,
+    pub render: SceneRender,
+    pub sky: SceneSky,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SceneCamera {
+    pub look_from: [f32; 3],
+    pub look_at: [f32; 3],
+    pub vup: [f32; 3],
+    pub vfov: f32,
+    pub defocus_angle: f32,
+    pub focus_dist: f32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SceneSphere {
+    pub center: [f32; 3],
+    pub radius: f32,
+    pub color: [f32; 3],
+    pub material: SceneMaterial,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ScenePrimitive {
+    pub shape: SceneShape,
+    pub color: [f32; 3],
+    pub material: SceneMaterial,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum SceneShape {
+    Plane {
+        point: [f32; 3],
+        normal: [f32; 3],
+    },
+    Quad {
+        corner: [f32; 3],
+        u: [f32; 3],
+        v: [f32; 3],
+    },
+    Box {
+        min: [f32; 3],
+        max: [f32; 3],
+    },
+    Disk {
+        center: [f32; 3],
+        normal: [f32; 3],
+        radius: f32,
+    },
+    Cylinder {
+        base: [f32; 3],
+        axis: [f32; 3],
+        radius: f32,
+    },
+    Sphere {
+        center: [f32; 3],
+        radius: f32,
+    },
+}
+
+// a model file relative to the assets folder, its materials come from the file
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SceneMesh {
+    pub path: String,
+}
+
+// places a primitive or model, by its index in the lists above. anything without instances is
+// placed once where it was defined. rotation is in degrees, applied around y, then x, then z
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SceneInstance {
+    pub geometry: SceneGeometry,
+    #[serde(default)]
+    pub translation: [f32; 3],
+    #[serde(default)]
+    pub rotation: [f32; 3],
+    #[serde(default = "unit_scale")]
+    pub scale: [f32; 3],
+}
+
+fn unit_scale() -> [f32; 3] {
+    [1.; 3]
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum SceneGeometry {
+    Primitive(usize),
+    Mesh(usize),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum SceneMaterial {
+    Lambertian,
+    Metal { fuzz: f32 },
+    Dielectric { ior: f32 },
+    Emissive { intensity: f32 },
+}
+
+// direction is the way the light travels, spot angles are in degrees from its axis
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum SceneLight {
+    Point {
+        position: [f32; 3],
+        color: [f32; 3],
+        intensity: f32,
+    },
+    Spot {
+        position: [f32; 3],
+        direction: [f32; 3],
+        color: [f32; 3],
+        intensity: f32,
+        inner_angle: f32,
+        outer_angle: f32,
+    },
+    Directional {
+        direction: [f32; 3],
+        color: [f32; 3],
+        intensity: f32,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SceneRender {
+    pub samples: i32,
+    pub depth: i32,
+    pub render_mode: i32,
+    pub seed: i32,
+    #[serde(default)]
+    pub sample_pattern: i32,
+    #[serde(default = "enabled")]
+    pub mis: bool,
+}
+
+fn enabled() -> bool {
+    true
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum SceneSky {
+    None,
+    Gradient,
+    // an equirectangular .hdr relative to assets, turned by rotation degrees around y
+    Environment {
+        path: String,
+        rotation: f32,
+        intensity: f32,
+    },
+    // the preetham daylight sky and its sun, elevation and azimuth in degrees
+    Physical {
+        turbidity: f32,
+        sun_elevation: f32,
+        sun_azimuth: f32,
+        intensity: f32,
+    },
+}
+
+impl SceneMaterial {
+    fn from_parts(material: i32, fuzz: f32, ior: f32, intensity: f32) -> Self {
+        match Material::from_index(material) {
+            Material::Lambertian => SceneMaterial::Lambertian,
+            Material::Metal => SceneMaterial::Metal { fuzz },
+            Material::Dielectric => SceneMaterial::Dielectric { ior },
+            Material::Emissive => SceneMaterial::Emissive { intensity },
+        }
+    }
+
+    fn material(&self) -> Material {
+        match self {
+            SceneMaterial::Lambertian => Material::Lambertian,
+            SceneMaterial::Metal { .. } => Material::Metal,
+            SceneMaterial::Dielectric { .. } => Material::Dielectric,
+            SceneMaterial::Emissive { .. } => Material::Emissive,
+        }
+    }
+}
+
+impl SceneSphere {
+    fn from_sphere(sphere: &Sphere) -> Self {
+        SceneSphere {
+            center: sphere.center,
+            radius: sphere.radius,
+            color: [sphere.color[0], sphere.color[1], sphere.color[2]],
+            material: SceneMaterial::from_parts(
+                sphere.material,
+                sphere.fuzz,
+                sphere.ior,
+                sphere.intensity,
+            ),
+        }
+    }
+
+    fn to_sphere(&self) -> Sphere {
+        match self.material {
+            SceneMaterial::Lambertian => {
+                Sphere::new(self.center, self.radius, self.color, Material::Lambertian)
+            }
+            SceneMaterial::Metal { fuzz } => {
+                Sphere::new(self.center, self.radius, self.color, Material::Metal).with_fuzz(fuzz)
+            }
+            SceneMaterial::Dielectric { ior } => {
+                Sphere::new(self.center, self.radius, self.color, Material::Dielectric)
+                    .with_ior(ior)
+            }
+            SceneMaterial::Emissive { intensity } => {
+                Sphere::new(self.center, self.radius, self.color, Material::Emissive)
+                    .with_intensity(intensity)
+            }
+        }
+    }
+}
+
+impl ScenePrimitive {
+    fn from_primitive(primitive: &Primitive) -> Self {
+        let shape = match primitive.kind() {
+            Shape::Plane => SceneShape::Plane {
+                point: primitive.origin,
+                normal: primitive.u,
+            },
+            Shape::Quad => SceneShape::Quad {
+                corner: primitive.origin,
+                u: primitive.u,
+                v: primitive.v,
+            },
+            Shape::Box => SceneShape::Box {
+                min: primitive.origin,
+                max: primitive.u,
+            },
+            Shape::Disk => SceneShape::Disk {
+                center: primitive.origin,
+                normal: primitive.u,
+                radius: primitive.radius,
+            },
+            Shape::Cylinder => SceneShape::Cylinder {
+                base: primitive.origin,
+                axis: primitive.u,
+                radius: primitive.radius,
+            },
+            Shape::Sphere => SceneShape::Sphere {
+                center: primitive.origin,
+                radius: primitive.radius,
+            },
+        };
+
+        ScenePrimitive {
+            shape,
+            color: [primitive.color[0], primitive.color[1], primitive.color[2]],
+            material: SceneMaterial::from_parts(
+                primitive.material,
+                primitive.fuzz,
+                primitive.ior,
+                primitive.intensity,
+            ),
+        }
+    }
+
+    fn to_primitive(&self) -> Primitive {
+        let (color, material) = (self.color, self.material.material());
+        let primitive = match self.shape {
+            SceneShape::Plane { point, normal } => Primitive::plane(point, normal, color, material),
+            SceneShape::Quad { corner, u, v } => Primitive::quad(corner, u, v, color, material),
+            SceneShape::Box { min, max } => Primitive::cuboid(min, max, color, material),
+            SceneShape::Disk {
+                center,
+                normal,
+                radius,
+            } => Primitive::disk(center, normal, radius, color, material),
+            SceneShape::Cylinder { base, axis, radius } => {
+                Primitive::cylinder(base, axis, radius, color, material)
+            }
+            SceneShape::Sphere { center, radius } => {
+                Primitive::sphere(center, radius, color, material)
+            }
+        };
+
+        match self.material {
+            SceneMaterial::Lambertian => primitive,
+            SceneMaterial::Metal { fuzz } => primitive.with_fuzz(fuzz),
+            SceneMaterial::Dielectric { ior } => primitive.with_ior(ior),
+            SceneMaterial::Emissive { intensity } => primitive.with_intensity(intensity),
+        }
+    }
+}
+
+impl SceneInstance {
+    fn from_transform(geometry: SceneGeometry, transform: &Transform) -> Self {
+        let (y, x, z) = transform.rotation.to_euler(EulerRot::YXZ);
+
+        SceneInstance {
+            geometry,
+            translation: transform.translation.to_array(),
+            rotation: [x, y, z].map(|angle| tidy(angle.to_degrees())),
+            scale: transform.scale.to_array(),
+        }
+    }
+
+    fn transform(&self) -> Transform {
+        let [x, y, z] = self.rotation.map(f32::to_radians);
+        Transform {
+            translation: Vec3::from(self.translation),
+            rotation: Quat::from_euler(EulerRot::YXZ, y, x, z),
+            scale: Vec3::from(self.scale),
+        }
+    }
+}
+
+impl SceneLight {
+    fn from_light(light: &Light) -> Self {
+        let (position, direction, color, intensity) = (
+            light.position,
+            light.direction.map(tidy),
+            light.color,
+            light.intensity,
+        );
+        match light.kind() {
+            LightKind::Point => SceneLight::Point {
+                position,
+                color,
+                intensity,
+            },
+            LightKind::Spot => SceneLight::Spot {
+                position,
+                direction,
+                color,
+                intensity,
+                inner_angle: tidy(light.inner_angle.to_degrees()),
+                outer_angle: tidy(light.outer_angle.to_degrees()),
+            },
+            LightKind::Directional => SceneLight::Directional {
+                direction,
+                color,
+                intensity,
+            },
+        }
+    }
+
+    fn to_light(&self) -> Light {
+        match *self {
+            SceneLight::Point {
+                position,
+                color,
+                intensity,
+            } => Light::point(position, color, intensity),
+            SceneLight::Spot {
+                position,
+                direction,
+                color,
+                intensity,
+                inner_angle,
+                outer_angle,
+            } => Light::spot(
+                position,
+                direction,
+                color,
+                intensity,
+                inner_angle.to_radians(),
+                outer_angle.to_radians(),
+            ),
+            SceneLight::Directional {
+                direction,
+                color,
+                intensity,
+            } => Light::directional(direction, color, intensity),
+        }
+    }
+}
+
+impl SceneFile {
+    // written from the entities rather than what gather_scene made of them, so a file that was
+    // opened and saved again keeps its lists the way they were written
+    pub fn capture(resources: &SceneResources) -> Self {
+        let SceneResources {
+            camera,
+            spheres,
+            primitives,
+            meshes,
+            lights,
+            environment,
+            params,
+        } = resources;
+
+        let mut sphere_entities: Vec<_> = spheres.iter().collect();
+        sphere_entities.sort_by_key(|(entity, .., slot)| (file_order(*slot), *entity));
+        let mut primitive_entities: Vec<_> = primitives.iter().collect();
+        primitive_entities.sort_by_key(|(entity, .., slot)| (file_order(*slot), *entity));
+        let mut mesh_entities: Vec<_> = meshes.iter().collect();
+        mesh_entities.sort_by_key(|(entity, .., slot)| (file_order(*slot), *entity));
+        let mut light_entities: Vec<_> = lights.iter().collect();
+        light_entities.sort_by_key(|(entity, .., slot)| (file_order(*slot), *entity));
+
+        let mut scene_primitives = vec![];
+        let mut scene_meshes = vec![];
+        let mut placed = vec![];
+        for (entity, primitive, material, transform, slot) in primitive_entities {
+            let primitive =
+                ScenePrimitive::from_primitive(&Primitive::from_parts(primitive, material));
+            let index = share(&mut scene_primitives, slot, primitive);
+            placed.push((entity, SceneGeometry::Primitive(index), transform, slot));
+        }
+        for (entity, mesh, transform, slot) in mesh_entities {
+            let mesh = SceneMesh {
+                path: mesh.path.clone(),
+            };
+            let index = share(&mut scene_meshes, slot, mesh);
+            placed.push((entity, SceneGeometry::Mesh(index), transform, slot));
+        }
+
+        // geometry that is only placed once where it was defined needs no instance, unless the
+        // file gave it one
+        let mut instances: Vec<_> = placed
+            .iter()
+            .filter(|(_, geometry, transform, slot)| {
+                let copies = placed.iter().filter(|other| other.1 == *geometry).count();
+                copies > 1
+                    || **transform != Transform::IDENTITY
+                    || slot.is_some_and(|slot| slot.instance.is_some())
+            })
+            .collect();
+        instances.sort_by_key(|(entity, .., slot)| {
+            (
+                slot.and_then(|slot| slot.instance).unwrap_or(usize::MAX),
+                *entity,
+            )
+        });
+
+        SceneFile {
+            camera: SceneCamera {
+                look_from: camera.camera_center,
+                look_at: camera.look_at,
+                vup: camera.vup,
+                vfov: camera.vfov,
+                defocus_angle: camera.defocus_angle,
+                focus_dist: camera.focus_dist,
+            },
+            spheres: sphere_entities
+                .iter()
+                .map(|(_, sphere, material, transform, _)| {
+                    SceneSphere::from_sphere(&Sphere::from_parts(sphere, material, transform))
+                })
+                .collect(),
+            primitives: scene_primitives
+                .into_iter()
+                .map(|(_, entry)| entry)
+                .collect(),
+            meshes: scene_meshes.into_iter().map(|(_, entry)| entry).collect(),
+            instances: instances
+                .iter()
+                .map(|(_, geometry, transform, _)| {
+                    SceneInstance::from_transform(*geometry, transform)
+                })
+                .collect(),
+            lights: light_entities
+                .iter()
+                .map(|(_, light, transform, _)| {
+                    SceneLight::from_light(&Light::from_parts(light, transform))
+                })
+                .collect(),
+            render: SceneRender {
+                samples: params.samples,
+                depth: params.depth,
+                render_mode: params.render_mode,
+                seed: params.seed,
+                sample_pattern: params.sample_pattern,
+                mis: params.mis != 0,
+            },
+            sky: match Sky::from_index(params.sky) {
+                Sky::None => SceneSky::None,
+                Sky::Gradient => SceneSky::Gradient,
+                Sky::Environment => SceneSky::Environment {
+                    path: environment.path.clone(),
+                    rotation: tidy(params.sky_rotation.to_degrees()),
+                    intensity: params.sky_intensity,
+                },
+                Sky::Physical => SceneSky::Physical {
+                    turbidity: params.turbidity,
+                    sun_elevation: tidy(params.sun_elevation.to_degrees()),
+                    sun_azimuth: tidy(params.sun_azimuth.to_degrees()),
+                    intensity: params.sky_intensity,
+                },
+            },
+        }
+    }
+
+    // spawns the objects, the ones already in the scene have to be despawned first. models that
+    // are already loaded aren't loaded again, gather_scene matches the files by path
+    pub fn apply(
+        &self,
+        commands: &mut Commands,
+        camera: &mut Camera,
+        params: &mut Params,
+        environment: &mut Environment,
+        asset_server: &AssetServer,
+    ) {
+        let mut new_camera = Camera::look_at(
+            self.camera.look_from,
+            self.camera.look_at,
+            self.camera.vup,
+            self.camera.vfov,
+        );
+        new_camera.defocus_angle = self.camera.defocus_angle;
+        new_camera.focus_dist = self.camera.focus_dist;
+        new_camera.update_viewport();
+        *camera = new_camera;
+
+        for (index, sphere) in self.spheres.iter().enumerate() {
+            let slot = SceneSlot {
+                index,
+                instance: None,
+            };
+            commands.spawn((sphere_bundle(&sphere.to_sphere()), slot));
+        }
+        for (index, primitive) in self.primitives.iter().enumerate() {
+            let primitive = primitive.to_primitive();
+            for (instance, transform) in self.placements(SceneGeometry::Primitive(index)) {
+                let slot = SceneSlot { index, instance };
+                commands.spawn((primitive_bundle(&primitive, transform), slot));
+            }
+        }
+        for (index, mesh) in self.meshes.iter().enumerate() {
+            for (instance, transform) in self.placements(SceneGeometry::Mesh(index)) {
+                let slot = SceneSlot { index, instance };
+                commands.spawn((mesh_bundle(&mesh.path, transform), slot));
+            }
+        }
+        for (index, light) in self.lights.iter().enumerate() {
+            let slot = SceneSlot {
+                index,
+                instance: None,
+            };
+            commands.spawn((light_bundle(&light.to_light()), slot));
+        }
+
+        params.samples = self.render.samples;
+        params.depth = self.render.depth;
+        params.render_mode = self.render.render_mode;
+        params.seed = self.render.seed;
+        params.sample_pattern = self.render.sample_pattern;
+        params.mis = self.render.mis as i32;
+        params.sky = match &self.sky {
+            SceneSky::None => Sky::None,
+            SceneSky::Gradient => Sky::Gradient,
+            SceneSky::Environment {
+                path,
+                rotation,
+                intensity,
+            } => {
+                if *path != environment.path {
+                    environment.load(path, asset_server);
+                }
+                params.sky_rotation = rotation.to_radians();
+                params.sky_intensity = *intensity;
+                Sky::Environment
+            }
+            SceneSky::Physical {
+                turbidity,
+                sun_elevation,
+                sun_azimuth,
+                intensity,
+            } => {
+                params.turbidity = *turbidity;
+                params.sun_elevation = sun_elevation.to_radians();
+                params.sun_azimuth = sun_azimuth.to_radians();
+                params.sky_intensity = *intensity;
+                Sky::Physical
+            }
+        } as i32;
+    }
+
+    // each instance of the geometry with its position in the instance list, or just the one
+    // placement where it was defined
+    fn placements(&self, geometry: SceneGeometry) -> Vec<(Option<usize>, Transform)> {
+        let placements: Vec<_> = self
+            .instances
+            .iter()
+            .enumerate()
+            .filter(|(_, instance)| instance.geometry == geometry)
+            .map(|(i, instance)| (Some(i), instance.transform()))
+            .collect();
+        if placements.is_empty() {
+            vec![(None, Transform::IDENTITY)]
+        } else {
+            placements
+        }
+    }
+}
+
+// where the opened file put an entity, so saving writes it back to the same place. index is its
+// position in the list for its kind, and instance its position in the instance list when one
+// placed it. entities added since have none and are saved after the rest
+#[derive(Component, Clone, Copy, Debug, PartialEq)]
+pub struct SceneSlot {
+    pub index: usize,
+    pub instance: Option<usize>,
+}
+
+fn file_order(slot: Option<&SceneSlot>) -> (usize, Option<usize>) {
+    slot.map_or((usize::MAX, None), |slot| (slot.index, slot.instance))
+}
+
+// the index of the entry for an entity, entities placed by the same entry in the file share it
+// again as long as they still match it, anything else gets an entry of its own
+fn share<T: PartialEq>(
+    entries: &mut Vec<(Option<usize>, T)>,
+    slot: Option<&SceneSlot>,
+    entry: T,
+) -> usize {
+    let from = slot.map(|slot| slot.index);
+    let shared = entries
+        .iter()
+        .position(|(other_from, other)| from.is_some() && *other_from == from && *other == entry);
+    shared.unwrap_or_else(|| {
+        entries.push((from, entry));
+        entries.len() - 1
+    })
+}
+
+// angles and directions come back out of radians and quaternions with a little float noise,
+// rounding it off saves the values that were loaded instead of ones a hair away from them. adding
+// zero turns -0 into 0
+fn tidy(value: f32) -> f32 {
+    (value * 1e3).round() / 1e3 + 0.
+}
+
+#[derive(Default)]
+pub struct SceneLoader;
+
+impl AssetLoader for SceneLoader {
+    fn load<'a>(
+        &'a self,
+        bytes: &'a [u8],
+        load_context: &'a mut LoadContext,
+    ) -> BoxedFuture<'a, Result<(), bevy::asset::Error>> {
+        Box::pin(async move {
+            let scene: SceneFile = ron::de::from_bytes(bytes)?;
+            load_context.set_default_asset(LoadedAsset::new(scene));
+            Ok(())
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["scene.ron"]
+    }
+}
+
+// paths are relative to the assets folder
+#[derive(Event, Debug, Clone)]
+pub struct OpenScene {
+    pub path: String,
+}
+
+#[derive(Event, Debug, Clone)]
+pub struct SaveScene {
+    pub path: String,
+}
+
+// the scene being shown, it is re-applied whenever the file changes on disk
+#[derive(Resource, Debug)]
+pub struct CurrentScene {
+    pub path: String,
+    handle: Option<Handle<SceneFile>>,
+    // opening a file that is already loaded sends no asset event, so it is applied from here
+    reapply: bool,
+}
+
+impl Default for CurrentScene {
+    fn default() -> Self {
+        CurrentScene {
+            path: "scenes/default.scene.ron".to_string(),
+            handle: None,
+            reapply: false,
+        }
+    }
+}
+
+// everything a scene file is captured from
+#[derive(SystemParam)]
+pub struct SceneResources<'w, 's> {
+    camera: Res<'w, Camera>,
+    spheres: Query<
+        'w,
+        's,
+        (
+            Entity,
+            &'static RtSphere,
+            &'static RtMaterial,
+            &'static Transform,
+            Option<&'static SceneSlot>,
+        ),
+    >,
+    primitives: Query<
+        'w,
+        's,
+        (
+            Entity,
+            &'static RtPrimitive,
+            &'static RtMaterial,
+            &'static Transform,
+            Option<&'static SceneSlot>,
+        ),
+    >,
+    meshes: Query<
+        'w,
+        's,
+        (
+            Entity,
+            &'static RtMesh,
+            &'static Transform,
+            Option<&'static SceneSlot>,
+        ),
+    >,
+    lights: Query<
+        'w,
+        's,
+        (
+            Entity,
+            &'static RtLight,
+            &'static Transform,
+            Option<&'static SceneSlot>,
+        ),
+    >,
+    environment: Res<'w, Environment>,
+    params: Res<'w, Params>,
+}
+
+pub struct ScenePlugin;
+impl Plugin for ScenePlugin {
+    fn build(&self, app: &mut App) {
+        app.add_asset::<SceneFile>()
+            .init_asset_loader::<SceneLoader>()
+            .add_event::<OpenScene>()
+            .add_event::<SaveScene>()
+            .init_resource::<CurrentScene>()
+            .add_systems(Update, (open_scene, apply_scene, save_scene).chain());
+    }
+}
+
+fn open_scene(
+    mut events: EventReader<OpenScene>,
+    mut current: ResMut<CurrentScene>,
+    scenes: Res<Assets<SceneFile>>,
+    asset_server: Res<AssetServer>,
+) {
+    for event in events.iter() {
+        let handle = asset_server.load(event.path.as_str());
+        current.path = event.path.clone();
+        current.reapply = scenes.contains(&handle);
+        current.handle = Some(handle);
+    }
+}
+
+fn apply_scene(
+    mut commands: Commands,
+    mut events: EventReader<AssetEvent<SceneFile>>,
+    scenes: Res<Assets<SceneFile>>,
+    mut current: ResMut<CurrentScene>,
+    objects: Query<
+        Entity,
+        Or<(
+            With<RtSphere>,
+            With<RtPrimitive>,
+            With<RtMesh>,
+            With<RtLight>,
+        )>,
+    >,
+    mut camera: ResMut<Camera>,
+    mut params: ResMut<Params>,
+    mut animation: ResMut<SphereAnimation>,
+    mut environment: ResMut<Environment>,
+    asset_server: Res<AssetServer>,
+) {
+    let mut apply = std::mem::take(&mut current.reapply);
+    for event in events.iter() {
+        let (AssetEvent::Created { handle } | AssetEvent::Modified { handle }) = event else {
+            continue;
+        };
+        apply |= current.handle.as_ref() == Some(handle);
+    }
+    if !apply {
+        return;
+    }
+
+    if let Some(scene) = current
+        .handle
+        .as_ref()
+        .and_then(|handle| scenes.get(handle))
+    {
+        for entity in &objects {
+            commands.entity(entity).despawn();
+        }
+        scene.apply(
+            &mut commands,
+            &mut camera,
+            &mut params,
+            &mut environment,
+            &asset_server,
+        );
+        // the animation would move the spheres away from where the file put them
+        animation.enabled = false;
+        info!("loaded scene {}", current.path);
+    }
+}
+
+fn save_scene(mut events: EventReader<SaveScene>, resources: SceneResources) {
+    for event in events.iter() {
+        let scene = SceneFile::capture(&resources);
+        let path = format!("assets/{}", event.path);
+        let result = ron::ser::to_string_pretty(&scene, ron::ser::PrettyConfig::default())
+            .map_err(|err| err.to_string())
+            .and_then(|text| std::fs::write(&path, text).map_err(|err| err.to_string()));
+        match result {
+            Ok(_) => info!("saved scene {}", path),
+            Err(err) => error!("failed to save scene {}: {}", path, err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::environment::{setup_environment, update_environment};
+    use bevy::{
+        ecs::system::SystemState,
+        render::render_resource::{Extent3d, TextureDimension, TextureFormat},
+    };
+
+    const SCENE: &str = r#"(
+        camera: (
+            look_from: (1.0, 2.0, 3.0),
+            look_at: (0.0, 0.5, -1.0),
+            vup: (0.0, 1.0, 0.0),
+            vfov: 35.0,
+            defocus_angle: 0.6,
+            focus_dist: 4.5,
+        ),
+        spheres: [
+            (center: (0.0, -100.5, -1.0), radius: 100.0, color: (0.5, 0.5, 0.5), material: Lambertian),
+            (center: (1.0, 0.0, -1.0), radius: -0.4, color: (1.0, 1.0, 1.0), material: Dielectric(ior: 1.5)),
+        ],
+        primitives: [
+            (shape: Plane(point: (0.0, -1.0, 0.0), normal: (0.0, 1.0, 0.0)), color: (0.2, 0.3, 0.4), material: Lambertian),
+            (shape: Box(min: (-0.5, -0.5, -0.5), max: (0.5, 0.5, 0.5)), color: (0.8, 0.6, 0.2), material: Metal(fuzz: 0.25)),
+            (shape: Quad(corner: (0.0, 3.0, 0.0), u: (1.0, 0.0, 0.0), v: (0.0, 0.0, 1.0)), color: (1.0, 0.9, 0.8), material: Emissive(intensity: 8.0)),
+            (shape: Disk(center: (0.0, 2.0, -1.0), normal: (0.0, 1.0, 0.0), radius: 0.5), color: (0.9, 0.9, 0.9), material: Dielectric(ior: 1.5)),
+            (shape: Cylinder(base: (1.0, 0.0, -2.0), axis: (0.0, 1.0, 0.0), radius: 0.25), color: (0.3, 0.7, 0.3), material: Lambertian),
+        ],
+        meshes: [(path: "models/cube.obj")],
+        instances: [
+            (geometry: Primitive(1), translation: (-2.0, 0.0, -3.0), rotation: (0.0, 30.0, 0.0), scale: (1.0, 2.0, 1.0)),
+            (geometry: Primitive(1), translation: (2.0, 0.0, -3.0), rotation: (10.0, 0.0, 20.0), scale: (1.0, 1.0, 1.0)),
+            (geometry: Mesh(0), translation: (0.0, 1.0, -2.0), rotation: (0.0, 0.0, 0.0), scale: (0.5, 0.5, 0.5)),
+        ],
+        lights: [
+            Point(position: (0.0, 4.0, 0.0), color: (1.0, 1.0, 1.0), intensity: 20.0),
+            Spot(position: (1.0, 4.0, -1.0), direction: (0.0, -0.6, -0.8), color: (1.0, 0.5, 0.5), intensity: 30.0, inner_angle: 15.0, outer_angle: 25.0),
+        ],
+        render: (samples: 8, depth: 6, render_mode: 4, seed: 3),
+        sky: Gradient,
+    )"#;
+
+    // an app with just the resources a scene is applied to and captured from
+    fn app() -> App {
+        let mut app = App::new();
+        app.add_plugins((MinimalPlugins, AssetPlugin::default()))
+            .add_asset::<Image>()
+            .insert_resource(Camera::default())
+            .insert_resource(Params::default())
+            .add_systems(Startup, setup_environment)
+            .add_systems(Update, update_environment);
+        app.update();
+        app
+    }
+
+    fn apply(app: &mut App, scene: &SceneFile) {
+        let mut state: SystemState<(
+            Commands,
+            ResMut<Camera>,
+            ResMut<Params>,
+            ResMut<Environment>,
+            Res<AssetServer>,
+        )> = SystemState::new(&mut app.world);
+        let (mut commands, mut camera, mut params, mut environment, asset_server) =
+            state.get_mut(&mut app.world);
+        scene.apply(
+            &mut commands,
+            &mut camera,
+            &mut params,
+            &mut environment,
+            &asset_server,
+        );
+        state.apply(&mut app.world);
+
+        // there is no image loader here, a stand in lets the environment finish loading
+        if let SceneSky::Environment { path, .. } = &scene.sky {
+            let handle: Handle<Image> = app
+                .world
+                .resource::<AssetServer>()
+                .get_handle(path.as_str());
+            let image = Image::new_fill(
+                Extent3d {
+                    width: 2,
+                    height: 1,
+                    depth_or_array_layers: 1,
+                },
+                TextureDimension::D2,
+                bytemuck::cast_slice(&[1f32; 4]),
+                TextureFormat::Rgba32Float,
+            );
+            app.world
+                .resource_mut::<Assets<Image>>()
+                .set_untracked(handle.id(), image);
+        }
+        app.update();
+    }
+
+    fn capture(app: &mut App) -> SceneFile {
+        let mut state: SystemState<SceneResources> = SystemState::new(&mut app.world);
+        SceneFile::capture(&state.get(&app.world))
+    }
+
+    // applies the scene to a fresh app and captures it back
+    fn round_trip(scene: &SceneFile) -> SceneFile {
+        let mut app = app();
+        apply(&mut app, scene);
+        capture(&mut app)
+    }
+
+    #[test]
+    fn scenes_capture_back_to_what_was_loaded() {
+        let scene: SceneFile = ron::from_str(SCENE).unwrap();
+        assert_eq!(round_trip(&scene), scene);
+    }
+
+    #[test]
+    fn the_seed_is_applied_and_captured() {
+        let scene: SceneFile = ron::from_str(SCENE).unwrap();
+        assert_eq!(scene.render.seed, 3);
+        // capture reads it back out of Params
+        assert_eq!(round_trip(&scene).render.seed, 3);
+    }
+
+    #[test]
+    fn physical_skies_capture_back() {
+        let text = SCENE.replace(
+            "sky: Gradient",
+            "sky: Physical(turbidity: 4.0, sun_elevation: 30.0, sun_azimuth: -45.0, intensity: 2.0)",
+        );
+        let scene: SceneFile = ron::from_str(&text).unwrap();
+        assert!(matches!(scene.sky, SceneSky::Physical { .. }));
+        assert_eq!(round_trip(&scene), scene);
+    }
+
+    #[test]
+    fn environment_skies_capture_back() {
+        let text = SCENE.replace(
+            "sky: Gradient",
+            r#"sky: Environment(path: "textures/studio.hdr", rotation: 90.0, intensity: 1.5)"#,
+        );
+        let scene: SceneFile = ron::from_str(&text).unwrap();
+        assert!(matches!(scene.sky, SceneSky::Environment { .. }));
+        assert_eq!(round_trip(&scene), scene);
+    }
+
+    #[test]
+    fn added_objects_are_saved_after_the_file_ones() {
+        let scene: SceneFile = ron::from_str(SCENE).unwrap();
+        let mut app = app();
+        apply(&mut app, &scene);
+        let plane = scene.primitives[0].to_primitive();
+        app.world
+            .spawn(primitive_bundle(&plane, Transform::IDENTITY));
+        let moved = Transform::from_xyz(0., 1., 0.);
+        app.world.spawn(primitive_bundle(&plane, moved));
+
+        // copies of the file's plane, but not merged into it or each other
+        let captured = capture(&mut app);
+        let (count, placed) = (scene.primitives.len(), scene.instances.len());
+        assert_eq!(&captured.primitives[..count], &scene.primitives[..]);
+        assert_eq!(
+            &captured.primitives[count..],
+            &vec![scene.primitives[0].clone(); 2][..]
+        );
+        // only the moved one needs an instance
+        assert_eq!(&captured.instances[..placed], &scene.instances[..]);
+        assert_eq!(
+            &captured.instances[placed..],
+            &[SceneInstance::from_transform(
+                SceneGeometry::Primitive(count + 1),
+                &moved
+            )]
+        );
+    }
+
+    #[test]
+    fn scene_assets_capture_back_to_the_same_file() {
+        let mut count = 0;
+        for entry in std::fs::read_dir("assets/scenes").unwrap() {
+            let path = entry.unwrap().path();
+            if !path.to_string_lossy().ends_with(".scene.ron") {
+                continue;
+            }
+            let text = std::fs::read_to_string(&path).unwrap();
+            let scene: SceneFile = ron::from_str(&text)
+                .unwrap_or_else(|err| panic!("{} doesn't parse: {}", path.display(), err));
+
+            assert_eq!(round_trip(&scene), scene, "{}", path.display());
+            count += 1;
+        }
+        assert!(count > 0);
+    }
+}
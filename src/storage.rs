@@ -0,0 +1,317 @@
+use bevy::{
+    prelude::*,
+    render::{
+        render_resource::*,
+        renderer::{RenderDevice, RenderQueue},
+    },
+};
+use bytemuck::{cast_slice, Pod};
+use std::marker::PhantomData;
+
+// how runtime sized scene data reaches the shader, picked once from the device limits
+#[derive(Resource, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SceneStorage {
+    Buffer,
+    // packed into a Rgba32Uint texture and read with textureLoad, for devices that can't bind a
+    // storage buffer for each scene array
+    Texture,
+}
+
+// one row of the fallback texture, matches SCENE_TEXTURE_WIDTH in simple.wgsl
+pub const SCENE_TEXTURE_WIDTH: u32 = 1024;
+const TEXEL_SIZE: usize = 16;
+
+impl SceneStorage {
+    // storage_buffers is how many the pipeline needs when scene data uses buffers
+    pub fn for_device(render_device: &RenderDevice, storage_buffers: u32) -> Self {
+        let limit = render_device.limits().max_storage_buffers_per_shader_stage;
+        if limit >= storage_buffers {
+            SceneStorage::Buffer
+        } else {
+            info!("not enough storage buffers, falling back to textures for scene data");
+            SceneStorage::Texture
+        }
+    }
+
+    pub fn shader_defs(&self) -> Vec<ShaderDefVal> {
+        match self {
+            SceneStorage::Buffer => vec![],
+            SceneStorage::Texture => vec!["SCENE_TEXTURE".into()],
+        }
+    }
+
+    pub fn layout_entry(&self, binding: u32) -> BindGroupLayoutEntry {
+        let ty = match self {
+            SceneStorage::Buffer => BindingType::Buffer {
+                ty: BufferBindingType::Storage { read_only: true },
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+            SceneStorage::Texture => BindingType::Texture {
+                sample_type: TextureSampleType::Uint,
+                view_dimension: TextureViewDimension::D2,
+                multisampled: false,
+            },
+        };
+
+        BindGroupLayoutEntry {
+            binding,
+            visibility: ShaderStages::COMPUTE,
+            ty,
+            count: None,
+        }
+    }
+}
+
+// where the running sum of samples lives, picked alongside SceneStorage
+#[derive(Resource, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccumulationStorage {
+    Buffer,
+    // for devices without storage buffers, like webgl2. a rgba32float storage texture can't be
+    // read and written in the same pass everywhere, so the shader reads last frame's sums from
+    // one texture, writes the new ones to another, and the node copies them back afterwards
+    Texture,
+}
+
+impl AccumulationStorage {
+    pub fn for_device(render_device: &RenderDevice) -> Self {
+        if render_device.limits().max_storage_buffers_per_shader_stage > 0 {
+            AccumulationStorage::Buffer
+        } else {
+            info!("no storage buffers, accumulating samples in textures");
+            AccumulationStorage::Texture
+        }
+    }
+
+    pub fn shader_defs(&self) -> Vec<ShaderDefVal> {
+        match self {
+            AccumulationStorage::Buffer => vec![],
+            AccumulationStorage::Texture => vec!["ACCUMULATION_TEXTURE".into()],
+        }
+    }
+
+    // the buffer or the texture written at binding 4, plus the texture read at binding 16
+    pub fn layout_entries(&self, buffer_size: u64) -> Vec<BindGroupLayoutEntry> {
+        match self {
+            AccumulationStorage::Buffer => vec![BindGroupLayoutEntry {
+                binding: 4,
+                visibility: ShaderStages::COMPUTE,
+                ty: BindingType::Buffer {
+                    ty: BufferBindingType::Storage { read_only: false },
+                    has_dynamic_offset: false,
+                    min_binding_size: BufferSize::new(buffer_size),
+                },
+                count: None,
+            }],
+            AccumulationStorage::Texture => vec![
+                BindGroupLayoutEntry {
+                    binding: 4,
+                    visibility: ShaderStages::COMPUTE,
+                    ty: BindingType::StorageTexture {
+                        access: StorageTextureAccess::WriteOnly,
+                        format: TextureFormat::Rgba32Float,
+                        view_dimension: TextureViewDimension::D2,
+                    },
+                    count: None,
+                },
+                BindGroupLayoutEntry {
+                    binding: 16,
+                    visibility: ShaderStages::COMPUTE,
+                    ty: BindingType::Texture {
+                        sample_type: TextureSampleType::Float { filterable: false },
+                        view_dimension: TextureViewDimension::D2,
+                        multisampled: false,
+                    },
+                    count: None,
+                },
+            ],
+        }
+    }
+}
+
+// the pair of textures behind AccumulationStorage::Texture, written holds this frame's sums and
+// is copied into read once the pass is done
+pub struct AccumulationTextures {
+    pub written: Texture,
+    written_view: TextureView,
+    pub read: Texture,
+    read_view: TextureView,
+    size: Extent3d,
+}
+
+impl AccumulationTextures {
+    pub fn new(render_device: &RenderDevice, width: u32, height: u32) -> Self {
+        let size = Extent3d {
+            width,
+            height,
+            depth_or_array_layers: 1,
+        };
+        let create = |label, usage| {
+            render_device.create_texture(&TextureDescriptor {
+                label: Some(label),
+                size,
+                mip_level_count: 1,
+                sample_count: 1,
+                dimension: TextureDimension::D2,
+                format: TextureFormat::Rgba32Float,
+                usage,
+                view_formats: &[],
+            })
+        };
+        // written is also the source for exports
+        let written = create(
+            "accumulation texture",
+            TextureUsages::STORAGE_BINDING | TextureUsages::COPY_SRC,
+        );
+        let read = create(
+            "previous accumulation texture",
+            TextureUsages::TEXTURE_BINDING | TextureUsages::COPY_DST,
+        );
+        AccumulationTextures {
+            written_view: written.create_view(&TextureViewDescriptor::default()),
+            written,
+            read_view: read.create_view(&TextureViewDescriptor::default()),
+            read,
+            size,
+        }
+    }
+
+    pub fn bind_group_entries(&self) -> [BindGroupEntry<'_>; 2] {
+        [
+            BindGroupEntry {
+                binding: 4,
+                resource: BindingResource::TextureView(&self.written_view),
+            },
+            BindGroupEntry {
+                binding: 16,
+                resource: BindingResource::TextureView(&self.read_view),
+            },
+        ]
+    }
+
+    pub fn copy_back(&self, encoder: &mut CommandEncoder) {
+        encoder.copy_texture_to_texture(
+            self.written.as_image_copy(),
+            self.read.as_image_copy(),
+            self.size,
+        );
+    }
+}
+
+// a runtime sized array of T on the gpu, it grows to fit whatever is written to it.
+// T has to be a whole number of vec4s so it can be packed into texels
+pub struct SceneArray<T> {
+    label: &'static str,
+    capacity: usize,
+    buffer: Option<Buffer>,
+    texture: Option<(Texture, TextureView)>,
+    _marker: PhantomData<T>,
+}
+
+impl<T: Pod> SceneArray<T> {
+    pub fn new(label: &'static str) -> Self {
+        assert_eq!(std::mem::size_of::<T>() % TEXEL_SIZE, 0);
+        SceneArray {
+            label,
+            capacity: 0,
+            buffer: None,
+            texture: None,
+            _marker: PhantomData,
+        }
+    }
+
+    pub fn write(
+        &mut self,
+        data: &[T],
+        storage: SceneStorage,
+        render_device: &RenderDevice,
+        render_queue: &RenderQueue,
+    ) {
+        // reallocate in powers of two, empty arrays still need something to bind
+        if data.len() > self.capacity || (self.buffer.is_none() && self.texture.is_none()) {
+            self.capacity = data.len().max(1).next_power_of_two();
+            self.allocate(storage, render_device);
+        }
+
+        let bytes: &[u8] = cast_slice(data);
+        match storage {
+            SceneStorage::Buffer => {
+                if !bytes.is_empty() {
+                    render_queue.write_buffer(self.buffer.as_ref().unwrap(), 0, bytes);
+                }
+            }
+            SceneStorage::Texture => {
+                let (texture, _) = self.texture.as_ref().unwrap();
+                let row_size = SCENE_TEXTURE_WIDTH as usize * TEXEL_SIZE;
+                let rows = bytes.len().div_ceil(row_size).max(1);
+
+                // texture writes have to cover whole rows
+                let mut padded = bytes.to_vec();
+                padded.resize(rows * row_size, 0);
+
+                render_queue.write_texture(
+                    ImageCopyTexture {
+                        texture,
+                        mip_level: 0,
+                        origin: Origin3d::ZERO,
+                        aspect: TextureAspect::All,
+                    },
+                    &padded,
+                    ImageDataLayout {
+                        offset: 0,
+                        bytes_per_row: Some(row_size as u32),
+                        rows_per_image: None,
+                    },
+                    Extent3d {
+                        width: SCENE_TEXTURE_WIDTH,
+                        height: rows as u32,
+                        depth_or_array_layers: 1,
+                    },
+                );
+            }
+        }
+    }
+
+    fn allocate(&mut self, storage: SceneStorage, render_device: &RenderDevice) {
+        let size = self.capacity * std::mem::size_of::<T>();
+        match storage {
+            SceneStorage::Buffer => {
+                self.buffer = Some(render_device.create_buffer(&BufferDescriptor {
+                    label: Some(self.label),
+                    size: size as u64,
+                    usage: BufferUsages::STORAGE | BufferUsages::COPY_DST,
+                    mapped_at_creation: false,
+                }));
+            }
+            SceneStorage::Texture => {
+                let texels = size / TEXEL_SIZE;
+                let rows = texels.div_ceil(SCENE_TEXTURE_WIDTH as usize).max(1);
+                let texture = render_device.create_texture(&TextureDescriptor {
+                    label: Some(self.label),
+                    size: Extent3d {
+                        width: SCENE_TEXTURE_WIDTH,
+                        height: rows as u32,
+                        depth_or_array_layers: 1,
+                    },
+                    mip_level_count: 1,
+                    sample_count: 1,
+                    dimension: TextureDimension::D2,
+                    format: TextureFormat::Rgba32Uint,
+                    usage: TextureUsages::TEXTURE_BINDING | TextureUsages::COPY_DST,
+                    view_formats: &[],
+                });
+                let view = texture.create_view(&TextureViewDescriptor::default());
+                self.texture = Some((texture, view));
+            }
+        }
+    }
+
+    // none until the first write
+    pub fn binding(&self) -> Option<BindingResource<'_>> {
+        match (&self.buffer, &self.texture) {
+            (Some(buffer), _) => Some(buffer.as_entire_binding()),
+            (None, Some((_, view))) => Some(BindingResource::TextureView(view)),
+            (None, None) => None,
+        }
+    }
+}